@@ -1,25 +1,76 @@
 use log::*;
-use std::cell::RefCell;
+use openssl::sha::sha256;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
 use std::io::Read;
-use std::path::PathBuf;
-use std::slice::Iter;
+use std::path::{Path, PathBuf};
 use std::{error, io};
 use thiserror::Error;
 
+use crate::cancel::CancelToken;
 use crate::checksum::ChecksumLookup;
-use crate::cpio::{CpioFile, CpioReader};
+use crate::component::{InstalledComponents, COMPONENTS_FILENAME};
+use crate::config::Config;
+use crate::container::{ArchiveEntry, ArchiveFormat, ContainerReader, EntryKind};
+use crate::history::{PayloadOutcome, PayloadResult};
 use crate::manifest::{self, Manifest, PayloadInfo, PayloadType};
-use crate::payload::{self, ImagePayload, Payload};
+use crate::payload::{self, DeltaPayload, ImagePayload, MtdPayload, Payload, UbiVolumePayload};
+use crate::script::{self, ScriptDir};
+use crate::slot::{self, Slot, SlotStatus};
+use crate::throttle::Throttle;
 
 pub const CHECKSUMS_FILENAME: &str = "checksums";
+pub const MANIFEST_FILENAME: &str = "manifest.jsonc";
 
-pub struct Archive<'a, R: io::Read> {
-    cpio_reader: CpioReader<R>,
+/// The largest checksums or manifest file which will be read into memory, unless the config
+/// sets max_metadata_size.
+pub const DEFAULT_MAX_METADATA_SIZE: u64 = 1024 * 1024;
+
+/// Deployment progress of a single payload, reported as each block is written.
+pub struct Progress<'p> {
+    pub filename: &'p str,
+    pub bytes_written: u64,
+    pub size: u64,
+}
+
+/// A streamed skipper archive. The checksums and manifest.jsonc files must come before any
+/// payloads, in either order, and payloads are matched to their manifest entries by filename.
+pub struct Archive<R: io::Read> {
+    container: ContainerReader<R>,
     checksums: ChecksumLookup,
     manifest: Manifest,
+    // sha256 of the checksums file, which covers the content of every payload
+    archive_hash: String,
+
+    // refcell is used because a mut ref cannot be used (need to call read_payload_info in a loop)
+    seen: RefCell<HashSet<String>>,
+    results: RefCell<Vec<PayloadResult>>,
+    updated_slot: Cell<Option<Slot>>,
+    components_path: Option<PathBuf>,
+    cancel: CancelToken,
+    throttle: RefCell<Option<Throttle>>,
+}
+
+// counts the bytes read from a payload file, reporting them to the callback, and throttles
+// the rate at which they're read
+struct ProgressReader<'t, R: io::Read, F: FnMut(u64)> {
+    inner: R,
+    bytes_read: u64,
+    report: F,
+    throttle: Option<&'t mut Throttle>,
+    cancel: &'t CancelToken,
+}
 
-    // refcell is used because a mut ref cannot be used (need to call get_next_payload in a loop)
-    payload_iter: RefCell<Option<Iter<'a, PayloadInfo>>>,
+impl<'t, R: io::Read, F: FnMut(u64)> io::Read for ProgressReader<'t, R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        if let Some(throttle) = self.throttle.as_mut() {
+            throttle.wait(count as u64, self.cancel)?;
+        }
+        self.bytes_read += count as u64;
+        (self.report)(self.bytes_read);
+        Ok(count)
+    }
 }
 
 #[derive(Error, Debug)]
@@ -36,8 +87,12 @@ pub enum ArchiveError {
     #[error("archive: file not found, cause: {reason}")]
     FileNotFoundError { reason: String },
 
-    #[error("archive: buffer insufficient for destination file")]
-    FileBufferSizeError,
+    #[error("archive: {filename} is {size} bytes, larger than the limit of {max_size} bytes")]
+    MetadataSizeError {
+        filename: String,
+        size: u64,
+        max_size: u64,
+    },
 
     #[error("checksum: format error, cause {reason}")]
     ChecksumFormatError { reason: String },
@@ -48,6 +103,9 @@ pub enum ArchiveError {
     #[error("checksum: mismatch error in file {filename}")]
     ChecksumMismatchError { filename: String },
 
+    #[error("checksum: delta source mismatch error in {path}")]
+    SourceChecksumMismatchError { path: String },
+
     #[error("manifest parse error, cause: {0}")]
     ManifestParseError(serde_json::Error),
 
@@ -65,94 +123,513 @@ pub enum ArchiveError {
 
     #[error("archive: payload deployment error, cause: {}", reason)]
     PayloadDeployError { reason: String },
+
+    #[error("archive: slot error, cause: {}", reason)]
+    SlotError { reason: String },
+
+    #[error("archive: script {filename} failed, cause: {reason}")]
+    ScriptError { filename: String, reason: String },
+
+    #[error("archive: component error, cause: {}", reason)]
+    ComponentError { reason: String },
+
+    #[error("archive: deploy cancelled")]
+    Cancelled,
 }
 
-impl<'a, R: io::Read> Archive<'a, R> {
-    pub fn new(reader: R) -> Result<Archive<'a, R>, ArchiveError> {
-        let cpio_reader = CpioReader::new(reader);
+impl<R: io::Read> Archive<R> {
+    pub fn new(reader: R) -> Result<Archive<R>, ArchiveError> {
+        let max_metadata_size = Config::try_get()
+            .map(|config| config.security.max_metadata_size)
+            .unwrap_or(DEFAULT_MAX_METADATA_SIZE);
+        Self::with_max_metadata_size(reader, max_metadata_size)
+    }
+
+    /// Reads the archive, refusing checksums or manifest files larger than max_metadata_size
+    /// bytes.
+    pub fn with_max_metadata_size(
+        reader: R,
+        max_metadata_size: u64,
+    ) -> Result<Archive<R>, ArchiveError> {
+        let container = ContainerReader::new(reader)?;
 
-        let checksums = read_checksum_file(&cpio_reader)?;
-        let manifest = read_manifest(&cpio_reader)?;
+        let (checksums, manifest, archive_hash) = read_metadata(&container, max_metadata_size)?;
 
         Ok(Archive {
-            cpio_reader,
+            container,
             checksums,
             manifest,
-            payload_iter: RefCell::new(None),
+            archive_hash,
+            seen: RefCell::new(HashSet::new()),
+            results: RefCell::new(Vec::new()),
+            updated_slot: Cell::new(None),
+            components_path: None,
+            cancel: CancelToken::new(),
+            throttle: RefCell::new(None),
         })
     }
 
-    fn get_next_payload(
-        &'a self,
-        file: &CpioFile<R>,
-    ) -> Result<Option<Box<dyn Payload + 'a>>, ArchiveError> {
-        let mut iter = self.payload_iter.borrow_mut();
-        if iter.is_none() {
-            *iter = Some(self.manifest.payloads.iter());
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    pub fn format(&self) -> ArchiveFormat {
+        self.container.format()
+    }
+
+    /// Identifies the archive, the hex sha256 of its checksums file.
+    pub fn archive_hash(&self) -> &str {
+        &self.archive_hash
+    }
+
+    /// The result of each payload deployed so far, in archive order. A payload which was being
+    /// written when the deploy failed is recorded as failed.
+    pub fn payload_results(&self) -> Vec<PayloadResult> {
+        self.results.borrow().clone()
+    }
+
+    /// The slot which the archive was deployed to, once the deploy has completed. None if the
+    /// deploy didn't use the A/B slots.
+    pub fn updated_slot(&self) -> Option<Slot> {
+        self.updated_slot.get()
+    }
+
+    /// The token is checked between each block written, a cancelled deploy returns
+    /// ArchiveError::Cancelled.
+    pub fn set_cancel_token(&mut self, cancel: CancelToken) {
+        self.cancel = cancel;
+    }
+
+    /// The file which records the installed version of each component, by default
+    /// components.json in the data dir of the config.
+    pub fn set_components_path(&mut self, path: &Path) {
+        self.components_path = Some(path.to_path_buf());
+    }
+
+    /// Limits the rate at which payloads are written, for deploys run in the background.
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        *self.throttle.get_mut() = Some(throttle);
+    }
+
+    fn read_payload_info(&self, file: &dyn ArchiveEntry) -> Result<&PayloadInfo, ArchiveError> {
+        if *file.kind() != EntryKind::File {
+            return Err(ArchiveError::ManifestFormatError {
+                reason: format!("file {} in archive is not a regular file", file.filename()),
+            });
         }
-        let payload_info =
-            iter.as_mut()
-                .unwrap()
-                .next()
-                .ok_or(ArchiveError::ManifestFormatError {
-                    reason: String::from(format!(
-                        "file {} in archive is missing manfest entry",
-                        file.filename
-                    )),
-                })?;
-
-        // need to check that filename in the manifest matches the archive entry
-        if payload_info.filename != file.filename {
+        let payload_info = self.manifest.find_payload(file.filename()).ok_or_else(|| {
+            ArchiveError::ManifestFormatError {
+                reason: format!(
+                    "file {} in archive is missing manifest entry",
+                    file.filename()
+                ),
+            }
+        })?;
+        if !self.seen.borrow_mut().insert(file.filename().to_owned()) {
             return Err(ArchiveError::ManifestFormatError {
-                reason: String::from(format!(
-                    "file {} in archive doesn't match manifest entry filename {}",
-                    file.filename, payload_info.filename
-                )),
+                reason: format!("file {} appears more than once in archive", file.filename()),
             });
         }
+        Ok(payload_info)
+    }
+
+    // called once the archive has been read, every payload which isn't optional must be present
+    fn check_payloads_present(&self) -> Result<(), ArchiveError> {
+        let seen = self.seen.borrow();
+        match self
+            .manifest
+            .payloads
+            .iter()
+            .find(|info| !info.optional && !seen.contains(&info.filename))
+        {
+            Some(info) => Err(ArchiveError::ManifestFormatError {
+                reason: format!("payload {} is missing from archive", info.filename),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn push_result(&self, filename: &str, outcome: PayloadOutcome) {
+        self.results.borrow_mut().push(PayloadResult {
+            filename: filename.to_owned(),
+            outcome,
+            bytes_written: 0,
+        });
+    }
+
+    // updates the result of the payload being deployed, which is recorded as failed until it
+    // has been deployed and verified, and its hooks have run
+    fn update_result<F: FnOnce(&mut PayloadResult)>(&self, update: F) {
+        if let Some(result) = self.results.borrow_mut().last_mut() {
+            update(result);
+        }
+    }
 
+    fn create_payload<'p>(
+        &self,
+        payload_info: &'p PayloadInfo,
+        file: &dyn ArchiveEntry,
+        scripts: &ScriptDir,
+    ) -> Result<Box<dyn Payload + 'p>, ArchiveError> {
         match payload_info.payload_type {
             PayloadType::Image => {
-                let image_size = file.filesize;
+                let image_size = file.filesize();
+                let dest = payload_info
+                    .dest
+                    .as_ref()
+                    .ok_or_else(|| missing_field(payload_info, "dest"))?;
+                let mut payload = ImagePayload::new(image_size, PathBuf::from(dest));
+                payload.set_encoding(payload_info.encoding);
+                payload.set_offset(payload_info.offset);
+                payload.set_max_size(payload_info.max_size);
+                Ok(Box::new(payload))
+            }
+            PayloadType::Delta => {
+                let delta_size = file.filesize();
+                let (source, dest) = resolve_delta_paths(payload_info)?;
+                let source_size = payload_info
+                    .source_size
+                    .ok_or_else(|| missing_field(payload_info, "source_size"))?;
+                let source_checksum = payload_info
+                    .source_checksum
+                    .as_ref()
+                    .ok_or_else(|| missing_field(payload_info, "source_checksum"))?
+                    .parse()?;
                 let payload =
-                    ImagePayload::new(image_size as u64, PathBuf::from(&payload_info.dest));
-                Ok(Some(Box::new(payload)))
+                    DeltaPayload::new(delta_size, source, source_size, source_checksum, dest);
+                Ok(Box::new(payload))
+            }
+            PayloadType::Mtd | PayloadType::UbiVolume => {
+                let image_size = file.filesize();
+                let dest = PathBuf::from(
+                    payload_info
+                        .dest
+                        .as_ref()
+                        .ok_or_else(|| missing_field(payload_info, "dest"))?,
+                );
+                if let PayloadType::Mtd = payload_info.payload_type {
+                    Ok(Box::new(MtdPayload::new(image_size, dest)))
+                } else {
+                    Ok(Box::new(UbiVolumePayload::new(image_size, dest)))
+                }
+            }
+            PayloadType::Script => {
+                // scripts are extracted to the script directory, and run once verified
+                let script_size = file.filesize();
+                let payload =
+                    ImagePayload::new(script_size, scripts.extract_path(file.filename())?);
+                Ok(Box::new(payload))
             }
         }
     }
 
-    pub fn deploy(&'a self) -> Result<(), ArchiveError> {
-        while let Some(mut file) = self.cpio_reader.read_next_file()? {
-            let payload = self.get_next_payload(&file)?;
-            if let Some(payload) = payload {
-                payload::deploy_payload(&mut file, payload)?;
+    fn run_hook(
+        &self,
+        scripts: &ScriptDir,
+        extracted: &HashSet<String>,
+        hook: &str,
+    ) -> Result<(), ArchiveError> {
+        let script_info = self
+            .manifest
+            .find_payload(hook)
+            .filter(|info| matches!(info.payload_type, PayloadType::Script))
+            .ok_or_else(|| ArchiveError::ManifestFormatError {
+                reason: format!("hook {} is not a script payload in the manifest", hook),
+            })?;
+        if !extracted.contains(hook) {
+            return Err(ArchiveError::ManifestFormatError {
+                reason: format!(
+                    "hook script {} must come before its payload in the archive",
+                    hook
+                ),
+            });
+        }
+        script::run_script(
+            &scripts.script_path(hook),
+            hook,
+            script_info.script_timeout(),
+        )
+    }
 
-                let cksum_expected = self.checksums.get_checksum(&file.filename).ok_or(
-                    ArchiveError::ChecksumMissingError {
-                        filename: file.filename.clone(),
-                    },
-                )?;
-                file.finalise(cksum_expected)?;
-            } else {
-                return Err(ArchiveError::UnknownPayload(format!(
-                    "got file but no payload!"
-                )));
+    // reads a file to the end without deploying it, and verifies its checksum
+    fn verify_file(&self, file: &mut dyn ArchiveEntry) -> Result<(), ArchiveError> {
+        let count = io::copy(file, &mut io::sink()).map_err(|err| ArchiveError::IOError {
+            source: err,
+            context: format!("verifying archive file: {}", file.filename()),
+        })?;
+        if count != file.filesize() {
+            return Err(ArchiveError::FormatError {
+                offset: count as usize,
+                reason: format!("archive file {} is truncated", file.filename()),
+            });
+        }
+
+        let cksum_expected = self.checksums.get_checksum(file.filename()).ok_or(
+            ArchiveError::ChecksumMissingError {
+                filename: file.filename().to_owned(),
+            },
+        )?;
+        file.finalise(cksum_expected)
+    }
+
+    /// Reads every file in the archive and verifies its checksum, without deploying anything.
+    pub fn verify(&self) -> Result<(), ArchiveError> {
+        while let Some(mut file) = self.container.read_next_entry()? {
+            self.cancel.check()?;
+            self.read_payload_info(&*file)?;
+            self.verify_file(&mut *file)?;
+        }
+        self.check_payloads_present()
+    }
+
+    pub fn deploy(&self) -> Result<(), ArchiveError> {
+        self.deploy_with_progress(|_| {})
+    }
+
+    /// Deploys the archive, calling progress as each block of a payload is written.
+    ///
+    /// When the config is loaded and a payload is written to the A/B slots, the inactive slot
+    /// is marked invalid until the deploy has completed, so a failed or cancelled deploy is
+    /// never booted. Payloads may not be written to the active slot.
+    ///
+    /// Payloads of components whose installed version matches the manifest are verified but
+    /// not deployed, unless the component is written to the A/B slots, as the inactive slot may
+    /// hold any version. Versions are recorded once the deploy has completed, those of
+    /// components written to the slots are pending until the slot is committed.
+    pub fn deploy_with_progress<F: FnMut(&Progress)>(
+        &self,
+        progress: F,
+    ) -> Result<(), ArchiveError> {
+        let components_path = self.components_path.clone().or_else(|| {
+            Config::try_get()
+                .map(|config| Path::new(&config.paths.data_dir).join(COMPONENTS_FILENAME))
+        });
+        let mut installed = match &components_path {
+            Some(path) => InstalledComponents::load(path)?,
+            None => InstalledComponents::default(),
+        };
+        let updated = installed.plan(&self.manifest)?;
+        let slot_components = match Config::try_get() {
+            Some(config) => slot_components(&self.manifest, config),
+            None => HashSet::new(),
+        };
+        let skipped: HashSet<&str> = self
+            .manifest
+            .components
+            .iter()
+            .map(|component| component.name.as_str())
+            .filter(|name| !updated.contains(name) && !slot_components.contains(name))
+            .collect();
+        if !self.manifest.components.is_empty()
+            && self
+                .manifest
+                .payloads
+                .iter()
+                .all(|info| is_skipped(info, &skipped))
+        {
+            info!("all components are up to date, nothing to deploy");
+            return self.deploy_files(None, &skipped, progress);
+        }
+
+        // the active slot is only needed by archives which write to the slots
+        let slots = match Config::try_get() {
+            Some(config)
+                if self
+                    .manifest
+                    .payloads
+                    .iter()
+                    .any(|info| !is_skipped(info, &skipped) && uses_slots(info, config)) =>
+            {
+                Some((config, slot::active_slot(config)?))
+            }
+            _ => None,
+        };
+        if let Some((config, active)) = slots {
+            slot::set_slot_status(config, active.other(), SlotStatus::Invalid)?;
+        }
+
+        let active_device = slots.map(|(config, active)| active.device(config));
+        match self.deploy_files(active_device, &skipped, progress) {
+            // a cancelled reader may fail part way through any read
+            Err(_) if self.cancel.is_cancelled() => Err(ArchiveError::Cancelled),
+            Err(err) => Err(err),
+            Ok(()) => {
+                if let Some(path) = &components_path {
+                    // the inactive slot has been overwritten, along with any pending versions
+                    if slots.is_some() {
+                        installed.discard_pending();
+                    }
+                    for component in &self.manifest.components {
+                        let name = component.name.as_str();
+                        if slots.is_some() && slot_components.contains(name) {
+                            installed.set_pending(name, &component.version);
+                        } else if updated.contains(&name) {
+                            installed.set_version(name, &component.version);
+                        }
+                    }
+                    installed.save(path)?;
+                }
+                if let Some((config, active)) = slots {
+                    slot::set_slot_status(config, active.other(), SlotStatus::Updated)?;
+                    self.updated_slot.set(Some(active.other()));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn deploy_files<F: FnMut(&Progress)>(
+        &self,
+        active_device: Option<&str>,
+        skipped: &HashSet<&str>,
+        mut progress: F,
+    ) -> Result<(), ArchiveError> {
+        let scripts = ScriptDir::new();
+        let mut extracted = HashSet::new();
+
+        while let Some(mut file) = self.container.read_next_entry()? {
+            self.cancel.check()?;
+            let payload_info = self.read_payload_info(&*file)?;
+            if is_skipped(payload_info, skipped) {
+                debug!("skipping {}, its component is up to date", file.filename());
+                self.verify_file(&mut *file)?;
+                self.push_result(file.filename(), PayloadOutcome::Skipped);
+                continue;
+            }
+            if let (Some(dest), Some(active_device)) = (&payload_info.dest, active_device) {
+                if slot::same_device(dest, active_device) {
+                    return Err(ArchiveError::SlotError {
+                        reason: format!(
+                            "payload {} would overwrite the active slot: {}",
+                            payload_info.filename, dest
+                        ),
+                    });
+                }
+            }
+            self.push_result(file.filename(), PayloadOutcome::Failed);
+            if let Some(hook) = &payload_info.pre_install {
+                self.run_hook(&scripts, &extracted, hook)?;
             }
+
+            let payload = self.create_payload(payload_info, &*file, &scripts)?;
+            let filename = file.filename().to_owned();
+            let size = file.filesize();
+            let mut throttle = self.throttle.borrow_mut();
+            let mut reader = ProgressReader {
+                inner: &mut file,
+                bytes_read: 0,
+                report: |bytes_written| {
+                    progress(&Progress {
+                        filename: &filename,
+                        bytes_written,
+                        size,
+                    })
+                },
+                throttle: throttle.as_mut(),
+                cancel: &self.cancel,
+            };
+            let deployed = payload::deploy_payload(&mut reader, payload, &self.cancel);
+            self.update_result(|result| result.bytes_written = reader.bytes_read);
+            deployed?;
+
+            let cksum_expected = self.checksums.get_checksum(file.filename()).ok_or(
+                ArchiveError::ChecksumMissingError {
+                    filename: file.filename().to_owned(),
+                },
+            )?;
+            file.finalise(cksum_expected)?;
+
+            if let PayloadType::Script = payload_info.payload_type {
+                // scripts which aren't hooks are run as soon as they've been verified
+                scripts.make_executable(file.filename())?;
+                extracted.insert(file.filename().to_owned());
+                if !self.manifest.is_hook(file.filename()) {
+                    let path = scripts.script_path(file.filename());
+                    script::run_script(&path, file.filename(), payload_info.script_timeout())?;
+                }
+            }
+
+            if let Some(hook) = &payload_info.post_install {
+                self.run_hook(&scripts, &extracted, hook)?;
+            }
+            self.update_result(|result| result.outcome = PayloadOutcome::Deployed);
         }
-        Ok(())
+        self.check_payloads_present()
+    }
+}
+
+fn is_skipped(payload_info: &PayloadInfo, skipped: &HashSet<&str>) -> bool {
+    matches!(payload_info.component.as_deref(), Some(name) if skipped.contains(name))
+}
+
+// whether a payload is written to one of the A/B slots, delta payloads are written to the
+// inactive slot unless their dest is given
+fn uses_slots(payload_info: &PayloadInfo, config: &Config) -> bool {
+    match &payload_info.dest {
+        Some(dest) => {
+            slot::same_device(dest, &config.slots.rootfs_a)
+                || slot::same_device(dest, &config.slots.rootfs_b)
+        }
+        None => matches!(payload_info.payload_type, PayloadType::Delta),
+    }
+}
+
+// components with a payload written to the A/B slots, they are deployed whatever the installed
+// version, since the inactive slot may hold any version
+fn slot_components<'m>(manifest: &'m Manifest, config: &Config) -> HashSet<&'m str> {
+    manifest
+        .payloads
+        .iter()
+        .filter(|info| uses_slots(info, config))
+        .filter_map(|info| info.component.as_deref())
+        .collect()
+}
+
+fn missing_field(payload_info: &PayloadInfo, field: &str) -> ArchiveError {
+    ArchiveError::ManifestFormatError {
+        reason: format!(
+            "payload {} is missing required field: {}",
+            payload_info.filename, field
+        ),
     }
 }
 
-struct TextFile<'a> {
+// delta payloads are applied from the active slot to the inactive slot, unless the manifest
+// names the source or dest explicitly
+fn resolve_delta_paths(payload_info: &PayloadInfo) -> Result<(PathBuf, PathBuf), ArchiveError> {
+    if let (Some(source), Some(dest)) = (&payload_info.source, &payload_info.dest) {
+        return Ok((PathBuf::from(source), PathBuf::from(dest)));
+    }
+
+    let config = Config::try_get().ok_or_else(|| ArchiveError::SlotError {
+        reason: format!(
+            "config is not loaded, cannot determine slots for payload: {}",
+            payload_info.filename
+        ),
+    })?;
+    let active = slot::active_slot(config)?;
+
+    let source = match &payload_info.source {
+        Some(source) => source.as_str(),
+        None => active.device(config),
+    };
+    let dest = match &payload_info.dest {
+        Some(dest) => dest.as_str(),
+        None => active.other().device(config),
+    };
+    Ok((PathBuf::from(source), PathBuf::from(dest)))
+}
+
+struct TextFile {
     filename: String,
-    content: &'a str,
+    content: String,
 }
 
-fn read_text_file<'a, R: io::Read>(
-    cpio_reader: &CpioReader<R>,
-    buf: &'a mut [u8],
-) -> Result<TextFile<'a>, ArchiveError> {
-    let mut file = match cpio_reader.read_next_file()? {
+fn read_text_file<R: io::Read>(
+    container: &ContainerReader<R>,
+    max_size: u64,
+) -> Result<TextFile, ArchiveError> {
+    let mut file = match container.read_next_entry()? {
         Some(inner) => inner,
         None => {
             return Err(ArchiveError::FileNotFoundError {
@@ -160,63 +637,72 @@ fn read_text_file<'a, R: io::Read>(
             })
         }
     };
-    if file.filesize as usize > buf.len() {
-        return Err(ArchiveError::FileBufferSizeError);
+    if file.filesize() > max_size {
+        return Err(ArchiveError::MetadataSizeError {
+            filename: file.filename().to_owned(),
+            size: file.filesize(),
+            max_size,
+        });
     }
 
-    // TODO: need to make read_to_end work properly
-    let count = file.read(buf).map_err(|err| ArchiveError::IOError {
-        source: err,
-        context: format!("read err in archive file: {}", file.filename),
-    })?;
-    let data = std::str::from_utf8(&buf[..count])?;
+    let mut buf = Vec::with_capacity(file.filesize() as usize);
+    let count = file
+        .read_to_end(&mut buf)
+        .map_err(|err| ArchiveError::IOError {
+            source: err,
+            context: format!("read err in archive file: {}", file.filename()),
+        })?;
+    if count != file.filesize() as usize {
+        return Err(ArchiveError::FormatError {
+            offset: count,
+            reason: format!("archive file {} is truncated", file.filename()),
+        });
+    }
+    let data = String::from_utf8(buf).map_err(|err| err.utf8_error())?;
     trace!("text file data: {}", data);
 
     Ok(TextFile {
-        filename: String::from(file.filename),
+        filename: file.filename().to_owned(),
         content: data,
     })
 }
 
-fn read_and_parse_text_file<T, F, R: io::Read>(
-    cpio_reader: &CpioReader<R>,
-    filename_expected: &str,
-    parse_function: F,
-) -> Result<T, ArchiveError>
-where
-    F: FnOnce(&str) -> Result<T, ArchiveError>,
-{
-    let mut buf = [0u8; 4096];
-    let text_file = read_text_file(cpio_reader, &mut buf)?;
-
-    if text_file.filename != filename_expected {
-        return Err(ArchiveError::FileNotFoundError {
-            reason: format!(
-                "expected file {}, got {}",
-                filename_expected, text_file.filename
-            ),
-        });
+// the checksums and manifest files may come in either order, but both must come before the
+// payloads so that each payload can be deployed as it's streamed
+fn read_metadata<R: io::Read>(
+    container: &ContainerReader<R>,
+    max_size: u64,
+) -> Result<(ChecksumLookup, Manifest, String), ArchiveError> {
+    let mut checksums = None;
+    let mut manifest = None;
+    let mut archive_hash = String::new();
+    while checksums.is_none() || manifest.is_none() {
+        let text_file = read_text_file(container, max_size)?;
+        match text_file.filename.as_str() {
+            CHECKSUMS_FILENAME if checksums.is_none() => {
+                checksums = Some(ChecksumLookup::parse_checksum_file(&text_file.content)?);
+                archive_hash = sha256(text_file.content.as_bytes())
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+            }
+            MANIFEST_FILENAME if manifest.is_none() => {
+                manifest = Some(
+                    manifest::parse_manifest(&text_file.content)
+                        .map_err(ArchiveError::ManifestParseError)?,
+                );
+            }
+            filename => {
+                return Err(ArchiveError::FileNotFoundError {
+                    reason: format!(
+                        "expected {} and {} before payloads, got {}",
+                        CHECKSUMS_FILENAME, MANIFEST_FILENAME, filename
+                    ),
+                })
+            }
+        }
     }
-
-    let parsed = parse_function(text_file.content)?;
-    Ok(parsed)
-}
-
-fn read_checksum_file<R: io::Read>(
-    cpio_reader: &CpioReader<R>,
-) -> Result<ChecksumLookup, ArchiveError> {
-    read_and_parse_text_file(
-        cpio_reader,
-        CHECKSUMS_FILENAME,
-        ChecksumLookup::parse_checksum_file,
-    )
-}
-
-fn read_manifest<R: io::Read>(cpio_reader: &CpioReader<R>) -> Result<Manifest, ArchiveError> {
-    let parse_func = |content: &str| {
-        manifest::parse_manifest(content).map_err(|err| ArchiveError::ManifestParseError(err))
-    };
-    read_and_parse_text_file(cpio_reader, "manifest.jsonc", parse_func)
+    Ok((checksums.unwrap(), manifest.unwrap(), archive_hash))
 }
 
 #[cfg(test)]
@@ -226,6 +712,7 @@ mod test {
     use crate::test_server::*;
     use crate::test_utils::*;
     use std::fs;
+    use std::io::Cursor;
     use std::time::Duration;
 
     #[test]
@@ -258,4 +745,448 @@ mod test {
         let archive = Archive::new(reader).unwrap();
         assert_eq!(archive.deploy().unwrap(), ());
     }
+
+    #[test]
+    fn basics_from_tar() {
+        // generated from the same files as test.cpio, with
+        //
+        // $ tar --format=pax -cf test.tar checksums manifest.jsonc rootfs.img
+        //
+        init_logging();
+        let input = fs::File::open(test_path("archive/test.tar")).unwrap();
+        let archive = Archive::new(input).unwrap();
+        assert_eq!(archive.format(), ArchiveFormat::Tar);
+        archive.verify().unwrap();
+
+        let input = fs::File::open(test_path("archive/test.tar")).unwrap();
+        Archive::new(input).unwrap().deploy().unwrap();
+    }
+
+    #[test]
+    fn script_hooks() {
+        init_logging();
+        let pre_marker = make_tempfile_path();
+        let post_marker = make_tempfile_path();
+        let dest = make_tempfile_path();
+
+        let pre_script = format!("#!/bin/sh\ntouch {}\n", pre_marker.display());
+        let post_script = format!(
+            "#!/bin/sh\ntest -f {} && touch {}\n",
+            dest.display(),
+            post_marker.display()
+        );
+        let manifest = format!(
+            r#"{{
+                "payloads": [
+                    {{ "type": "script", "filename": "pre.sh" }},
+                    {{ "type": "script", "filename": "post.sh", "timeout": 5 }},
+                    {{
+                        "type": "image",
+                        "filename": "rootfs.img",
+                        "dest": "{}",
+                        "pre_install": "pre.sh",
+                        "post_install": "post.sh"
+                    }}
+                ]
+            }}"#,
+            dest.display()
+        );
+        let image = fs::read(test_path("archive/test.img")).unwrap();
+        let archive = make_archive(
+            &manifest,
+            &[
+                ("pre.sh", pre_script.as_bytes()),
+                ("post.sh", post_script.as_bytes()),
+                ("rootfs.img", &image),
+            ],
+        );
+
+        let archive = Archive::new(Cursor::new(archive)).unwrap();
+        archive.deploy().unwrap();
+        assert!(pre_marker.exists());
+        assert!(post_marker.exists());
+        assert_eq!(fs::read(dest).unwrap(), image);
+    }
+
+    #[test]
+    fn failing_hook_aborts_deploy() {
+        init_logging();
+        let dest = make_tempfile_path();
+        let manifest = format!(
+            r#"{{
+                "payloads": [
+                    {{ "type": "script", "filename": "fail.sh" }},
+                    {{
+                        "type": "image",
+                        "filename": "rootfs.img",
+                        "dest": "{}",
+                        "pre_install": "fail.sh"
+                    }}
+                ]
+            }}"#,
+            dest.display()
+        );
+        let archive = make_archive(
+            &manifest,
+            &[
+                ("fail.sh", b"#!/bin/sh\nexit 1\n"),
+                ("rootfs.img", b"image"),
+            ],
+        );
+
+        let archive = Archive::new(Cursor::new(archive)).unwrap();
+        let err = archive.deploy().unwrap_err();
+        assert!(matches!(err, ArchiveError::ScriptError { .. }));
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn corrupt_script_not_run() {
+        init_logging();
+        let marker = make_tempfile_path();
+        let script = format!("#!/bin/sh\ntouch {}\n", marker.display());
+        let manifest = r#"{ "payloads": [ { "type": "script", "filename": "run.sh" } ] }"#;
+        let mut archive = make_archive(manifest, &[("run.sh", script.as_bytes())]);
+
+        // corrupt the script content, after the checksums file has been generated
+        let pos = archive
+            .windows(script.len())
+            .position(|window| window == script.as_bytes())
+            .unwrap();
+        archive[pos + script.len() - 2] = b'X';
+
+        let archive = Archive::new(Cursor::new(archive)).unwrap();
+        let err = archive.deploy().unwrap_err();
+        assert!(matches!(err, ArchiveError::ChecksumMismatchError { .. }));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn progress_reported() {
+        init_logging();
+        let dest = make_tempfile_path();
+        let manifest = format!(
+            r#"{{
+                "version": "1.2.0",
+                "payloads": [ {{ "type": "image", "filename": "rootfs.img", "dest": "{}" }} ]
+            }}"#,
+            dest.display()
+        );
+        let image = fs::read(test_path("archive/test.img")).unwrap();
+        let archive = make_archive(&manifest, &[("rootfs.img", &image)]);
+
+        let mut reports = Vec::new();
+        let archive = Archive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(archive.manifest().version.as_deref(), Some("1.2.0"));
+        archive
+            .deploy_with_progress(|progress| {
+                assert_eq!(progress.filename, "rootfs.img");
+                assert_eq!(progress.size, image.len() as u64);
+                reports.push(progress.bytes_written);
+            })
+            .unwrap();
+
+        assert!(reports.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(*reports.last().unwrap(), image.len() as u64);
+    }
+
+    #[test]
+    fn verify_only() {
+        init_logging();
+        let dest = make_tempfile_path();
+        let manifest = format!(
+            r#"{{ "payloads": [ {{ "type": "image", "filename": "rootfs.img", "dest": "{}" }} ] }}"#,
+            dest.display()
+        );
+        let image = fs::read(test_path("archive/test.img")).unwrap();
+        let mut archive = make_archive(&manifest, &[("rootfs.img", &image)]);
+
+        Archive::new(Cursor::new(&archive))
+            .unwrap()
+            .verify()
+            .unwrap();
+        assert!(!dest.exists());
+
+        let pos = archive
+            .windows(image.len())
+            .position(|window| window == &image[..])
+            .unwrap();
+        archive[pos + 10] ^= 0xFF;
+        let err = Archive::new(Cursor::new(&archive))
+            .unwrap()
+            .verify()
+            .unwrap_err();
+        assert!(matches!(err, ArchiveError::ChecksumMismatchError { .. }));
+    }
+
+    #[test]
+    fn cancelled_deploy() {
+        init_logging();
+        let dest = make_tempfile_path();
+        let manifest = format!(
+            r#"{{ "payloads": [ {{ "type": "image", "filename": "rootfs.img", "dest": "{}" }} ] }}"#,
+            dest.display()
+        );
+        let image = fs::read(test_path("archive/test-img-larger.img")).unwrap();
+        let archive = make_archive(&manifest, &[("rootfs.img", &image)]);
+
+        let cancel = CancelToken::new();
+        let mut archive = Archive::new(Cursor::new(archive)).unwrap();
+        archive.set_cancel_token(cancel.clone());
+        let mut reports = 0;
+        let err = archive
+            .deploy_with_progress(|_| {
+                reports += 1;
+                cancel.cancel();
+            })
+            .unwrap_err();
+        assert!(matches!(err, ArchiveError::Cancelled));
+        assert_eq!(reports, 1);
+        assert!(fs::metadata(&dest).unwrap().len() < image.len() as u64);
+    }
+
+    #[test]
+    fn entries_in_any_order() {
+        init_logging();
+        let first = make_tempfile_path();
+        let second = make_tempfile_path();
+        let manifest = format!(
+            r#"{{
+                "payloads": [
+                    {{ "type": "image", "filename": "first.img", "dest": "{}" }},
+                    {{ "type": "image", "filename": "second.img", "dest": "{}" }}
+                ]
+            }}"#,
+            first.display(),
+            second.display()
+        );
+        let files: [(&str, &[u8]); 2] = [("second.img", b"second"), ("first.img", b"first")];
+        let checksums = make_checksums(&files);
+        let archive = make_cpio(&[
+            ("manifest.jsonc", manifest.as_bytes()),
+            ("checksums", checksums.as_bytes()),
+            files[0],
+            files[1],
+        ]);
+
+        Archive::new(Cursor::new(archive))
+            .unwrap()
+            .deploy()
+            .unwrap();
+        assert_eq!(fs::read(first).unwrap(), b"first");
+        assert_eq!(fs::read(second).unwrap(), b"second");
+    }
+
+    #[test]
+    fn optional_payloads() {
+        init_logging();
+        let dest = make_tempfile_path();
+        let manifest = format!(
+            r#"{{
+                "payloads": [
+                    {{ "type": "script", "filename": "extra.sh", "optional": true }},
+                    {{ "type": "image", "filename": "rootfs.img", "dest": "{}" }}
+                ]
+            }}"#,
+            dest.display()
+        );
+        let archive = make_archive(&manifest, &[("rootfs.img", b"image")]);
+        Archive::new(Cursor::new(archive))
+            .unwrap()
+            .deploy()
+            .unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"image");
+
+        // required payloads must be present
+        let manifest = manifest.replace(r#""optional": true"#, r#""optional": false"#);
+        let archive = make_archive(&manifest, &[("rootfs.img", b"image")]);
+        let err = Archive::new(Cursor::new(archive))
+            .unwrap()
+            .verify()
+            .unwrap_err();
+        assert!(matches!(err, ArchiveError::ManifestFormatError { .. }));
+    }
+
+    #[test]
+    fn unexpected_entries() {
+        init_logging();
+        let manifest = r#"{ "payloads": [ { "type": "script", "filename": "run.sh" } ] }"#;
+
+        // payloads can't be streamed before the manifest has been read
+        let archive = make_cpio(&[("run.sh", b"#!/bin/sh\n"), ("manifest.jsonc", b"{}")]);
+        let err = Archive::new(Cursor::new(archive)).err().unwrap();
+        assert!(matches!(err, ArchiveError::FileNotFoundError { .. }));
+
+        let archive = make_archive(manifest, &[("other.sh", b"#!/bin/sh\n")]);
+        let err = Archive::new(Cursor::new(archive))
+            .unwrap()
+            .verify()
+            .unwrap_err();
+        assert!(matches!(err, ArchiveError::ManifestFormatError { .. }));
+
+        let archive = make_archive(
+            manifest,
+            &[("run.sh", b"#!/bin/sh\n"), ("run.sh", b"#!/bin/sh\n")],
+        );
+        let err = Archive::new(Cursor::new(archive))
+            .unwrap()
+            .verify()
+            .unwrap_err();
+        assert!(matches!(err, ArchiveError::ManifestFormatError { .. }));
+    }
+
+    #[test]
+    fn large_metadata() {
+        init_logging();
+        let mut payloads = Vec::new();
+        let mut files = Vec::new();
+        for idx in 0..100 {
+            let filename = format!("script-{:03}-with-a-long-descriptive-name.sh", idx);
+            payloads.push(format!(
+                r#"{{ "type": "script", "filename": "{}", "optional": true }}"#,
+                filename
+            ));
+            files.push(filename);
+        }
+        let manifest = format!(r#"{{ "payloads": [ {} ] }}"#, payloads.join(",\n"));
+        assert!(manifest.len() > 4096);
+        let files: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|filename| (filename.as_str(), &b"#!/bin/sh\n"[..]))
+            .collect();
+        let archive = make_archive(&manifest, &files);
+
+        let archive_reader = Archive::new(Cursor::new(&archive)).unwrap();
+        assert_eq!(archive_reader.manifest().payloads.len(), 100);
+        archive_reader.verify().unwrap();
+
+        let err = Archive::with_max_metadata_size(Cursor::new(&archive), 4096)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            ArchiveError::MetadataSizeError { max_size: 4096, .. }
+        ));
+    }
+
+    #[test]
+    fn components() {
+        init_logging();
+        let soc_dest = make_tempfile_path();
+        let fpga_dest = make_tempfile_path();
+        let components_path = make_tempfile_path();
+        let make_component_archive = |soc_version: &str, fpga_version: &str| {
+            let manifest = format!(
+                r#"{{
+                    "components": [
+                        {{ "name": "soc", "version": "{}", "requires": {{ "fpga": ">=1.2" }} }},
+                        {{ "name": "fpga", "version": "{}" }}
+                    ],
+                    "payloads": [
+                        {{ "type": "image", "filename": "soc.bin", "dest": "{}", "component": "soc" }},
+                        {{ "type": "image", "filename": "fpga.bit", "dest": "{}", "component": "fpga" }}
+                    ]
+                }}"#,
+                soc_version,
+                fpga_version,
+                soc_dest.display(),
+                fpga_dest.display()
+            );
+            make_archive(&manifest, &[("soc.bin", b"soc"), ("fpga.bit", b"fpga")])
+        };
+        let deploy = |archive: Vec<u8>| {
+            let mut archive = Archive::new(Cursor::new(archive)).unwrap();
+            archive.set_components_path(&components_path);
+            archive.deploy()
+        };
+
+        deploy(make_component_archive("2.0", "1.2")).unwrap();
+        assert!(soc_dest.exists() && fpga_dest.exists());
+        let installed = InstalledComponents::load(&components_path).unwrap();
+        assert_eq!(installed.version("soc"), Some("2.0"));
+
+        // nothing is written when every component is up to date
+        fs::remove_file(&soc_dest).unwrap();
+        fs::remove_file(&fpga_dest).unwrap();
+        deploy(make_component_archive("2.0", "1.2")).unwrap();
+        assert!(!soc_dest.exists() && !fpga_dest.exists());
+
+        deploy(make_component_archive("2.0", "1.3")).unwrap();
+        assert!(!soc_dest.exists() && fpga_dest.exists());
+        let installed = InstalledComponents::load(&components_path).unwrap();
+        assert_eq!(installed.version("fpga"), Some("1.3"));
+
+        let err = deploy(make_component_archive("2.1", "1.1")).unwrap_err();
+        assert!(matches!(err, ArchiveError::ComponentError { .. }));
+        assert!(!soc_dest.exists());
+    }
+
+    #[test]
+    fn payload_results() {
+        init_logging();
+        let dest = make_tempfile_path();
+        let manifest = format!(
+            r#"{{
+                "payloads": [
+                    {{ "type": "image", "filename": "rootfs.img", "dest": "{}" }},
+                    {{ "type": "image", "filename": "app.img", "dest": "/does/not/exist/app.img" }}
+                ]
+            }}"#,
+            dest.display()
+        );
+        let image = fs::read(test_path("archive/test.img")).unwrap();
+        let archive = make_archive(&manifest, &[("rootfs.img", &image), ("app.img", b"app")]);
+
+        let first = Archive::new(Cursor::new(&archive)).unwrap();
+        let archive = Archive::new(Cursor::new(&archive)).unwrap();
+        assert_eq!(archive.archive_hash().len(), 64);
+        assert_eq!(archive.archive_hash(), first.archive_hash());
+
+        assert!(archive.deploy().is_err());
+        let results = archive.payload_results();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].outcome, PayloadOutcome::Deployed);
+        assert_eq!(results[0].bytes_written, image.len() as u64);
+        assert_eq!(results[1].filename, "app.img");
+        assert_eq!(results[1].outcome, PayloadOutcome::Failed);
+    }
+
+    #[test]
+    fn slot_payloads() {
+        let config = Config::parse(
+            r#"{ "slots": { "rootfs_a": "/dev/mmcblk0p2", "rootfs_b": "/dev/mmcblk0p3" } }"#,
+        )
+        .unwrap();
+        let manifest = manifest::parse_manifest(
+            r#"{
+                "components": [
+                    { "name": "rootfs", "version": "2.0" },
+                    { "name": "fpga", "version": "1.3" }
+                ],
+                "payloads": [
+                    {
+                        "type": "image", "filename": "rootfs.img", "dest": "/dev/mmcblk0p3",
+                        "component": "rootfs"
+                    },
+                    { "type": "delta", "filename": "rootfs.delta" },
+                    { "type": "image", "filename": "boot.img", "dest": "/dev/mmcblk0p1" },
+                    {
+                        "type": "mtd", "filename": "fpga.bit", "dest": "/dev/mtd3",
+                        "component": "fpga"
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let uses: Vec<bool> = manifest
+            .payloads
+            .iter()
+            .map(|info| uses_slots(info, &config))
+            .collect();
+        assert_eq!(uses, vec![true, true, false, false]);
+        assert_eq!(
+            slot_components(&manifest, &config),
+            vec!["rootfs"].into_iter().collect()
+        );
+    }
 }
@@ -1,19 +1,356 @@
-use std::{fs::File, path::PathBuf};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process;
+use std::time::Duration;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use reqwest::blocking::Client;
 use skipper::archive::Archive;
+use skipper::config::{self, Config, DEFAULT_DATA_DIR};
+use skipper::error_report::ErrorReport;
+use skipper::history::{self, HistoryEntry, HISTORY_FILENAME};
+use skipper::http_reader::{self, HttpError, HttpReader};
+use skipper::tls;
+
+const DEFAULT_TIMEOUT_SECS: &str = "30";
+
+const EXIT_CODES_HELP: &str = "EXIT CODES:
+    0   success
+    1   invalid arguments
+    2   config error
+    3   source could not be opened
+    10  network error, retry
+    11  unexpected server response, retry
+    12  tls error, e.g. an untrusted or unpinned server certificate
+    13  credentials rejected by the server
+    20  archive corrupt or invalid, do not retry
+    21  delta source does not match the active slot
+    22  component version requirements are not met
+    30  deploy failed
+    31  install script failed
+    40  cancelled, retry";
+
+/// Where the archive is read from, "-" is stdin e.g. when piped over ssh during bring-up.
+enum Source<'s> {
+    Stdin,
+    Http(&'s str),
+    File(&'s str),
+}
+
+impl<'s> Source<'s> {
+    fn parse(source: &'s str) -> Source<'s> {
+        if source == "-" {
+            Source::Stdin
+        } else if source.starts_with("http://") || source.starts_with("https://") {
+            Source::Http(source)
+        } else {
+            Source::File(source.strip_prefix("file://").unwrap_or(source))
+        }
+    }
+
+    fn open(&self, matches: &ArgMatches, timeout: Duration) -> Result<Box<dyn Read>, ErrorReport> {
+        match self {
+            Source::Stdin => Ok(Box::new(io::stdin())),
+            Source::Http(url) => open_http(url, matches, timeout)
+                .map(|reader| Box::new(reader) as Box<dyn Read>)
+                .map_err(|err| ErrorReport::from(&err)),
+            Source::File(path) => File::open(path)
+                .map(|file| Box::new(file) as Box<dyn Read>)
+                .map_err(|err| ErrorReport::source(&err, path)),
+        }
+    }
+}
+
+fn open_http(url: &str, matches: &ArgMatches, timeout: Duration) -> Result<HttpReader, HttpError> {
+    let mut builder = HttpReader::builder(url, timeout);
+    if let Some(config) = Config::try_get() {
+        if let Some(tls) = &config.security.tls {
+            builder = builder.tls(tls);
+        }
+        if let Some(http) = &config.http {
+            builder = builder.http_config(http);
+        }
+    }
+    if let Some(token) = matches.value_of("token") {
+        builder = builder.bearer_token(token);
+    }
+    for header in matches.values_of("header").into_iter().flatten() {
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| HttpError::FormatError {
+                reason: format!("invalid header, expected name: value, got: {}", header),
+            })?;
+        builder = builder.header(name.trim(), value.trim());
+    }
+    builder.build()
+}
+
+// the config is only loaded when a path is given, overrides apply to the loaded config so are
+// refused without one
+fn load_config(matches: &ArgMatches) -> Result<Option<Config>, ErrorReport> {
+    let overrides: Vec<&str> = matches.values_of("set").into_iter().flatten().collect();
+    let config_path = match matches.value_of("config") {
+        Some(config_path) => config_path,
+        None if !overrides.is_empty() => {
+            return Err(ErrorReport::usage(
+                "--set needs a config file, given with --config",
+            ))
+        }
+        None => match config::env_override_names().first() {
+            Some(name) => {
+                return Err(ErrorReport::usage(&format!(
+                    "{} overrides the config, which needs a config file, given with --config",
+                    name
+                )))
+            }
+            None => return Ok(None),
+        },
+    };
+    Config::load_with_overrides(Some(config_path), &overrides)
+        .map(Some)
+        .map_err(|err| ErrorReport::from(&err))
+}
+
+// reports are sent with the server headers from the config, and any given for downloads
+fn send_report(
+    matches: &ArgMatches,
+    timeout: Duration,
+    entry: &HistoryEntry,
+) -> Result<(), HttpError> {
+    let server = Config::try_get().and_then(|config| config.server.as_ref());
+    let url = match (matches.value_of("report"), server) {
+        (Some(url), _) => url.to_owned(),
+        (None, Some(server)) => match history::report_url(server)? {
+            Some(url) => url,
+            None => return Ok(()),
+        },
+        (None, None) => return Ok(()),
+    };
+
+    let mut builder = Client::builder().timeout(timeout);
+    let mut headers = BTreeMap::new();
+    if let Some(config) = Config::try_get() {
+        if let Some(tls) = &config.security.tls {
+            builder = tls::configure(builder, tls)?;
+        }
+        if let Some(http) = &config.http {
+            builder = http_reader::configure(builder, http)?;
+        }
+    }
+    if let Some(server) = server {
+        headers.extend(server.headers.clone());
+    }
+    if let Some(token) = matches.value_of("token") {
+        headers.insert(String::from("Authorization"), format!("Bearer {}", token));
+    }
+    for header in matches.values_of("header").into_iter().flatten() {
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_owned(), value.trim().to_owned());
+        }
+    }
+    history::post_report(&builder.build()?, &url, &headers, entry)
+}
+
+// the history is kept in the data dir, so is only written when a config is given
+fn record_history(matches: &ArgMatches, timeout: Duration, entry: &HistoryEntry) {
+    if let Some(config) = Config::try_get() {
+        let path = Path::new(&config.paths.data_dir).join(HISTORY_FILENAME);
+        if let Err(err) = history::append(&path, entry) {
+            eprintln!(
+                "warning: failed to write install history {}: {}",
+                path.display(),
+                err
+            );
+        }
+    }
+    if let Err(err) = send_report(matches, timeout, entry) {
+        eprintln!("warning: failed to send install report: {}", err);
+    }
+}
+
+fn deploy_from(
+    source: &str,
+    matches: &ArgMatches,
+    timeout: Duration,
+    entry: &mut HistoryEntry,
+) -> Result<(), ErrorReport> {
+    let reader = Source::parse(source).open(matches, timeout)?;
+
+    let archive = Archive::new(reader).map_err(|err| ErrorReport::from(&err))?;
+    entry.set_archive(&archive);
+    let result = archive.deploy();
+    entry.set_payloads(archive.payload_results());
+    result.map_err(|err| ErrorReport::from(&err))
+}
+
+fn deploy(matches: &ArgMatches) -> Result<(), ErrorReport> {
+    let timeout = matches
+        .value_of("timeout")
+        .unwrap()
+        .parse::<u64>()
+        .map_err(|err| ErrorReport::usage(&format!("invalid timeout: {}", err)))?;
+    let timeout = Duration::from_secs(timeout);
+    if let Some(config) = load_config(matches)? {
+        Config::init(config);
+    }
+
+    let source = matches.value_of("source").unwrap();
+    eprintln!("Starting deployment from: {}", source);
+    let mut entry = HistoryEntry::new(source);
+    let result = deploy_from(source, matches, timeout, &mut entry);
+    entry.finish(result.as_ref().err());
+    record_history(matches, timeout, &entry);
+    result?;
+    eprintln!("Deployment complete");
+    Ok(())
+}
+
+fn print_entry(entry: &HistoryEntry) {
+    println!(
+        "{} {} {} from {}",
+        history::format_time(entry.started),
+        format!("{:?}", entry.state).to_lowercase(),
+        entry.version.as_deref().unwrap_or("unknown"),
+        entry.source
+    );
+    if let Some(archive_hash) = &entry.archive_hash {
+        println!("    archive: {}", archive_hash);
+    }
+    println!(
+        "    {} bytes written in {} secs",
+        entry.bytes_written,
+        entry.finished.saturating_sub(entry.started)
+    );
+    for payload in &entry.payloads {
+        println!(
+            "    {}: {}, {} bytes",
+            payload.filename,
+            format!("{:?}", payload.outcome).to_lowercase(),
+            payload.bytes_written
+        );
+    }
+    if let (Some(kind), Some(error)) = (&entry.error_kind, &entry.error) {
+        println!("    error: {}, {}", kind, error);
+    }
+}
+
+fn show_history(args: &ArgMatches) -> Result<(), ErrorReport> {
+    let data_dir = match load_config(args)? {
+        Some(config) => config.paths.data_dir,
+        None => String::from(DEFAULT_DATA_DIR),
+    };
+    let path = Path::new(&data_dir).join(HISTORY_FILENAME);
+    let entries = history::read(&path)
+        .map_err(|err| ErrorReport::source(&err, &path.display().to_string()))?;
+
+    let count = match args.value_of("count") {
+        Some(count) => count
+            .parse::<usize>()
+            .map_err(|err| ErrorReport::usage(&format!("invalid count: {}", err)))?,
+        None => entries.len(),
+    };
+    for entry in &entries[entries.len().saturating_sub(count)..] {
+        if args.is_present("json") {
+            println!("{}", serde_json::to_string(entry).unwrap());
+        } else {
+            print_entry(entry);
+        }
+    }
+    Ok(())
+}
 
 fn main() {
     let matches = App::new("Skipper deploy")
-        .arg(Arg::with_name("source").required(true))
+        .arg(
+            Arg::with_name("source")
+                .required(true)
+                .help("archive path, file:// or http(s):// url, or - for stdin"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .default_value(DEFAULT_TIMEOUT_SECS)
+                .help("http timeout in seconds"),
+        )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .takes_value(true)
+                .help("bearer token for http downloads"),
+        )
+        .arg(
+            Arg::with_name("header")
+                .long("header")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("extra header for http downloads, as \"name: value\""),
+        )
+        .arg(
+            Arg::with_name("report")
+                .long("report")
+                .takes_value(true)
+                .help("url to POST a report of the deploy to, defaults to the server report_url"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .takes_value(true)
+                .global(true)
+                .help("config file, used to deploy to the inactive slot and record history"),
+        )
+        .arg(
+            Arg::with_name("set")
+                .long("set")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true)
+                .help("override a field of the config file, as section.field=value"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("print errors as a json object on stdout"),
+        )
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("list previous deploys, oldest first")
+                .arg(
+                    Arg::with_name("count")
+                        .short("n")
+                        .long("count")
+                        .takes_value(true)
+                        .help("only list the most recent deploys"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("print each deploy as a json object"),
+                ),
+        )
+        .after_help(EXIT_CODES_HELP)
         .get_matches();
 
-    let source = matches.value_of("source").unwrap();
-    // for now only file deployments are supported
-    println!("Starting deployment from file: {}", source);
-    let source = File::open(PathBuf::from(source)).unwrap();
-
-    let archive = Archive::new(source).unwrap();
-    archive.deploy().unwrap();
-    println!("Deployment complete");
+    let history = matches.subcommand_matches("history");
+    let result = match history {
+        Some(args) => show_history(args),
+        None => deploy(&matches),
+    };
+    if let Err(report) = result {
+        // either --json asks for json output, whether given before or after the subcommand
+        let json =
+            matches.is_present("json") || history.is_some_and(|args| args.is_present("json"));
+        if json {
+            println!("{}", serde_json::to_string(&report).unwrap());
+        } else {
+            eprintln!("error: {}", report.message);
+        }
+        process::exit(report.exit_code);
+    }
 }
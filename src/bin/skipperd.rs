@@ -0,0 +1,153 @@
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use log::*;
+use skipper::config::Config;
+use skipper::control::{self, ControlClient, Request};
+use skipper::daemon::Daemon;
+use skipper::staging::InstallStrategy;
+use skipper::throttle;
+
+fn exit_on_error<T, E: std::fmt::Display>(result: Result<T, E>, message: &str) -> T {
+    match result {
+        Ok(value) => value,
+        Err(err) => {
+            eprintln!("{}: {}", message, err);
+            process::exit(1);
+        }
+    }
+}
+
+fn run_daemon(config: &'static Config, once: bool) {
+    let mut daemon = exit_on_error(
+        Daemon::new(config.server.clone(), Path::new(&config.paths.data_dir)),
+        "failed to start daemon",
+    );
+    daemon.set_install_strategy(config.policy.install_strategy);
+    if let Some(tls) = &config.security.tls {
+        exit_on_error(daemon.set_tls(tls.clone()), "failed to load tls config");
+    }
+    if let Some(http) = &config.http {
+        exit_on_error(daemon.set_http(http.clone()), "failed to load http config");
+    }
+    if let Some(background) = &config.policy.background {
+        daemon.set_background(background.clone());
+    }
+
+    if once {
+        enter_background(config);
+        exit_on_error(daemon.poll(), "update failed");
+        return;
+    }
+    if config.server.is_none() {
+        warn!("no update server is configured, only local installs will run");
+    }
+
+    let daemon = Arc::new(daemon);
+    exit_on_error(
+        control::serve(daemon.clone(), Path::new(&config.paths.control_socket)),
+        "failed to start control socket",
+    );
+    info!("skipperd started");
+    // deploys run on this thread, the control socket threads keep their priority
+    enter_background(config);
+    daemon.run();
+}
+
+fn enter_background(config: &Config) {
+    if let Some(background) = &config.policy.background {
+        if let Err(err) = throttle::enter_background(background) {
+            warn!("failed to lower deploy thread priority: {}", err);
+        }
+    }
+}
+
+fn run_client(config: &Config, command: &str, args: &ArgMatches) {
+    let socket = Path::new(&config.paths.control_socket);
+    let mut client = exit_on_error(ControlClient::connect(socket), "failed to connect");
+
+    let request = match command {
+        "status" => Request::Status,
+        "install" => Request::Install {
+            source: args.value_of("source").unwrap().to_owned(),
+            strategy: match args.value_of("strategy") {
+                Some("stream") => Some(InstallStrategy::Stream),
+                Some("staged") => Some(InstallStrategy::Staged),
+                _ => None,
+            },
+        },
+        "cancel" => Request::Cancel,
+        "commit" => Request::Commit,
+        "rollback" => Request::Rollback,
+        "history" => Request::History,
+        "watch" => {
+            let events = exit_on_error(client.subscribe(), "failed to subscribe");
+            for event in events {
+                let event = exit_on_error(event, "failed to read event");
+                println!("{}", serde_json::to_string(&event).unwrap());
+            }
+            return;
+        }
+        _ => unreachable!(),
+    };
+    let response = exit_on_error(client.request(&request), "request failed");
+    println!("{}", serde_json::to_string_pretty(&response).unwrap());
+}
+
+fn main() {
+    env_logger::init();
+    let matches = App::new("Skipper update daemon")
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .takes_value(true)
+                .help("path to the config file"),
+        )
+        .arg(
+            Arg::with_name("set")
+                .long("set")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("override a config field, as section.field=value"),
+        )
+        .arg(
+            Arg::with_name("once")
+                .long("once")
+                .help("poll the update server once and exit"),
+        )
+        .subcommand(SubCommand::with_name("status").about("show the daemon status"))
+        .subcommand(
+            SubCommand::with_name("install")
+                .about("install an archive from a url or local path")
+                .arg(Arg::with_name("source").required(true))
+                .arg(
+                    Arg::with_name("strategy")
+                        .long("strategy")
+                        .takes_value(true)
+                        .possible_values(&["stream", "staged"])
+                        .help("stream to the inactive slot, or download and verify first"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("cancel").about("cancel the running install"))
+        .subcommand(SubCommand::with_name("commit").about("commit the pending version"))
+        .subcommand(SubCommand::with_name("rollback").about("roll back the pending version"))
+        .subcommand(SubCommand::with_name("history").about("list previous installs"))
+        .subcommand(SubCommand::with_name("watch").about("print daemon events as they occur"))
+        .get_matches();
+
+    let overrides: Vec<&str> = matches.values_of("set").into_iter().flatten().collect();
+    let config = exit_on_error(
+        Config::load_with_overrides(matches.value_of("config"), &overrides),
+        "failed to load config",
+    );
+    let config = Config::init(config);
+
+    match matches.subcommand() {
+        (command, Some(args)) => run_client(config, command, args),
+        _ => run_daemon(config, matches.is_present("once")),
+    }
+}
@@ -0,0 +1,73 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use thiserror::Error;
+
+use crate::archive::ArchiveError;
+
+/// Returned (wrapped in an io::Error) by readers which have been cancelled.
+#[derive(Error, Debug)]
+#[error("cancelled")]
+pub struct CancelledError;
+
+/// A token shared between a deployment and whoever may cancel it. Cloned tokens share the
+/// same state, the default token is never cancelled unless cancel is called on it.
+#[derive(Clone, Default, Debug)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn check(&self) -> Result<(), ArchiveError> {
+        if self.is_cancelled() {
+            return Err(ArchiveError::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// As check, for use in io::Read implementations.
+    pub fn check_io(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            return Err(io::Error::other(CancelledError));
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if the error was returned by a cancelled reader.
+pub fn is_cancelled_error(err: &io::Error) -> bool {
+    err.get_ref()
+        .map(|inner| inner.is::<CancelledError>())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shared_between_clones() {
+        let token = CancelToken::new();
+        let other = token.clone();
+        assert!(token.check().is_ok());
+
+        other.cancel();
+        assert!(matches!(token.check(), Err(ArchiveError::Cancelled)));
+        let err = token.check_io().unwrap_err();
+        assert!(is_cancelled_error(&err));
+        assert!(!is_cancelled_error(&io::Error::other("other")));
+    }
+}
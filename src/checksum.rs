@@ -1,6 +1,8 @@
 use crate::archive::ArchiveError;
 use crc32fast::Hasher;
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 
 #[derive(Debug)]
 pub struct Checksum {
@@ -26,8 +28,12 @@ impl Checksum {
         let hasher = self.hasher.take().unwrap();
         self.final_value = Some(hasher.finalize());
     }
+}
+
+impl FromStr for Checksum {
+    type Err = ArchiveError;
 
-    pub fn from_str(s: &str) -> Result<Checksum, ArchiveError> {
+    fn from_str(s: &str) -> Result<Checksum, ArchiveError> {
         let cksum = u32::from_str_radix(s, 16).map_err(|_| ArchiveError::ChecksumFormatError {
             reason: format!("failed to parse hex checksum from: {}", s),
         })?;
@@ -36,9 +42,11 @@ impl Checksum {
             hasher: None,
         })
     }
+}
 
-    pub fn to_string(&self) -> String {
-        format!("{:08X?}", self.final_value.unwrap())
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08X?}", self.final_value.unwrap())
     }
 }
 
@@ -77,7 +85,7 @@ impl ChecksumLookup {
     pub fn get_checksum(&self, filename: &str) -> Option<Checksum> {
         // return a value containing the final value but no hasher
         self.cksums.get(filename).map(|cksum| Checksum {
-            final_value: cksum.final_value.clone(),
+            final_value: cksum.final_value,
             hasher: None,
         })
     }
@@ -0,0 +1,266 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::archive::ArchiveError;
+use crate::manifest::Manifest;
+
+// installed component versions are kept in the data dir
+pub const COMPONENTS_FILENAME: &str = "components.json";
+
+/// The installed version of each component, by name. Versions of components written to the
+/// inactive slot are pending until the slot is committed.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct InstalledComponents {
+    versions: BTreeMap<String, String>,
+    #[serde(default)]
+    pending: BTreeMap<String, String>,
+}
+
+impl InstalledComponents {
+    /// Loads the installed versions, a missing file means no components are installed.
+    pub fn load(path: &Path) -> Result<InstalledComponents, ArchiveError> {
+        let buf = match fs::read_to_string(path) {
+            Ok(buf) => buf,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(InstalledComponents::default())
+            }
+            Err(err) => {
+                return Err(ArchiveError::IOError {
+                    source: err,
+                    context: format!("reading installed components: {}", path.display()),
+                })
+            }
+        };
+        serde_json::from_str(&buf).map_err(|err| ArchiveError::ComponentError {
+            reason: format!("invalid components file {}: {}", path.display(), err),
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ArchiveError> {
+        // written via a rename, so the file is always complete
+        let map_err = |err: io::Error| ArchiveError::IOError {
+            source: err,
+            context: format!("writing installed components: {}", path.display()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(map_err)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string(self).unwrap()).map_err(map_err)?;
+        fs::rename(&tmp_path, path).map_err(map_err)?;
+        Ok(())
+    }
+
+    pub fn version(&self, name: &str) -> Option<&str> {
+        self.versions.get(name).map(String::as_str)
+    }
+
+    pub fn set_version(&mut self, name: &str, version: &str) {
+        info!("component {} installed at version {}", name, version);
+        self.versions.insert(name.to_owned(), version.to_owned());
+    }
+
+    pub fn pending_version(&self, name: &str) -> Option<&str> {
+        self.pending.get(name).map(String::as_str)
+    }
+
+    pub fn set_pending(&mut self, name: &str, version: &str) {
+        info!("component {} pending at version {}", name, version);
+        self.pending.insert(name.to_owned(), version.to_owned());
+    }
+
+    /// Moves the pending versions to the installed versions, once the slot they were written
+    /// to is committed.
+    pub fn commit_pending(&mut self) {
+        for (name, version) in std::mem::take(&mut self.pending) {
+            self.set_version(&name, &version);
+        }
+    }
+
+    pub fn discard_pending(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Returns the components of the manifest which need installing, those whose installed
+    /// version differs. Every requirement of the manifest's components must be met by the
+    /// versions installed once the whole manifest is installed.
+    pub fn plan<'m>(&self, manifest: &'m Manifest) -> Result<Vec<&'m str>, ArchiveError> {
+        let mut versions = self.versions.clone();
+        for component in &manifest.components {
+            versions.insert(component.name.clone(), component.version.clone());
+        }
+
+        for component in &manifest.components {
+            for (required, constraint) in &component.requires {
+                let version =
+                    versions
+                        .get(required)
+                        .ok_or_else(|| ArchiveError::ComponentError {
+                            reason: format!(
+                                "{} requires {} {}, which is not installed",
+                                component.name, required, constraint
+                            ),
+                        })?;
+                if !satisfies(version, constraint)? {
+                    return Err(ArchiveError::ComponentError {
+                        reason: format!(
+                            "{} requires {} {}, but version {} would be installed",
+                            component.name, required, constraint, version
+                        ),
+                    });
+                }
+            }
+        }
+
+        for payload in &manifest.payloads {
+            if let Some(name) = &payload.component {
+                if manifest.find_component(name).is_none() {
+                    return Err(ArchiveError::ManifestFormatError {
+                        reason: format!(
+                            "payload {} belongs to unknown component {}",
+                            payload.filename, name
+                        ),
+                    });
+                }
+            }
+        }
+
+        Ok(manifest
+            .components
+            .iter()
+            .filter(|component| self.version(&component.name) != Some(&component.version))
+            .map(|component| component.name.as_str())
+            .collect())
+    }
+}
+
+/// Compares dotted versions, numeric parts are compared as numbers and any other parts as
+/// strings. Missing parts count as zero, so 1.2 and 1.2.0 are equal.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut a_parts = a.split('.');
+    let mut b_parts = b.split('.');
+    loop {
+        let (a_part, b_part) = match (a_parts.next(), b_parts.next()) {
+            (None, None) => return Ordering::Equal,
+            (a_part, b_part) => (a_part.unwrap_or("0"), b_part.unwrap_or("0")),
+        };
+        let ordering = match (a_part.parse::<u64>(), b_part.parse::<u64>()) {
+            (Ok(a_num), Ok(b_num)) => a_num.cmp(&b_num),
+            _ => a_part.cmp(b_part),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// Checks a version against a constraint such as ">=1.2, <2", a bare version must match
+/// exactly.
+pub fn satisfies(version: &str, constraint: &str) -> Result<bool, ArchiveError> {
+    for term in constraint.split(',').map(str::trim) {
+        let ops: [(&str, &[Ordering]); 7] = [
+            (">=", &[Ordering::Greater, Ordering::Equal]),
+            ("<=", &[Ordering::Less, Ordering::Equal]),
+            ("!=", &[Ordering::Less, Ordering::Greater]),
+            ("==", &[Ordering::Equal]),
+            (">", &[Ordering::Greater]),
+            ("<", &[Ordering::Less]),
+            ("=", &[Ordering::Equal]),
+        ];
+        let (allowed, required) = ops
+            .iter()
+            .find_map(|(op, allowed)| term.strip_prefix(op).map(|rest| (*allowed, rest)))
+            .unwrap_or((&[Ordering::Equal], term));
+        let required = required.trim();
+        if required.is_empty() {
+            return Err(ArchiveError::ComponentError {
+                reason: format!("invalid version constraint: {}", constraint),
+            });
+        }
+        if !allowed.contains(&compare_versions(version, required)) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manifest::parse_manifest;
+    use crate::test_utils::*;
+
+    #[test]
+    fn versions() {
+        assert_eq!(compare_versions("1.2", "1.2.0"), Ordering::Equal);
+        assert_eq!(compare_versions("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("1.2.0", "1.2.1"), Ordering::Less);
+        assert_eq!(compare_versions("1.2.rc1", "1.2.rc2"), Ordering::Less);
+
+        assert!(satisfies("1.2.0", "1.2").unwrap());
+        assert!(satisfies("1.4.0", ">=1.2, <2").unwrap());
+        assert!(!satisfies("2.0.0", ">=1.2, <2").unwrap());
+        assert!(satisfies("2.0.0", "!= 1.2").unwrap());
+        assert!(!satisfies("1.1", "> 1.1").unwrap());
+        assert!(satisfies("1.2.0", "").is_err());
+    }
+
+    #[test]
+    fn plan() {
+        init_logging();
+        let manifest = parse_manifest(
+            r#"{
+                "components": [
+                    { "name": "soc", "version": "2.0.0", "requires": { "fpga": ">=1.2" } },
+                    { "name": "fpga", "version": "1.3.0" }
+                ],
+                "payloads": []
+            }"#,
+        )
+        .unwrap();
+
+        let mut installed = InstalledComponents::default();
+        assert_eq!(installed.plan(&manifest).unwrap(), vec!["soc", "fpga"]);
+        installed.set_version("fpga", "1.3.0");
+        installed.set_version("coproc", "0.9");
+        assert_eq!(installed.plan(&manifest).unwrap(), vec!["soc"]);
+
+        // pending versions aren't installed until committed
+        installed.set_pending("soc", "2.0.0");
+        assert_eq!(installed.plan(&manifest).unwrap(), vec!["soc"]);
+        installed.commit_pending();
+        assert_eq!(installed.version("soc"), Some("2.0.0"));
+        assert_eq!(installed.pending_version("soc"), None);
+        installed.set_pending("soc", "2.1.0");
+        installed.discard_pending();
+        assert_eq!(installed.version("soc"), Some("2.0.0"));
+        assert!(installed.plan(&manifest).unwrap().is_empty());
+
+        let path = make_tempfile_path();
+        installed.set_pending("fpga", "1.4.0");
+        installed.save(&path).unwrap();
+        assert_eq!(InstalledComponents::load(&path).unwrap(), installed);
+        assert_eq!(
+            InstalledComponents::load(&make_tempfile_path()).unwrap(),
+            InstalledComponents::default()
+        );
+
+        let manifest = parse_manifest(
+            r#"{
+                "components": [
+                    { "name": "soc", "version": "2.0.0", "requires": { "coproc": ">=1.0" } }
+                ],
+                "payloads": []
+            }"#,
+        )
+        .unwrap();
+        let err = installed.plan(&manifest).unwrap_err();
+        assert!(matches!(err, ArchiveError::ComponentError { .. }));
+    }
+}
@@ -1,10 +1,21 @@
 use log::*;
 use once_cell::sync::OnceCell;
+use reqwest::Url;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
-use std::{fs::File, io::{self, Read}, path::Path};
+use serde_json::{Map, Value};
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
 use thiserror::Error;
 
+use crate::archive::DEFAULT_MAX_METADATA_SIZE;
 use crate::json;
+use crate::staging::InstallStrategy;
 
 #[derive(Debug, Error)]
 pub enum ConfigError {
@@ -15,20 +26,369 @@ pub enum ConfigError {
     },
 
     #[error("config: ConfigParseError, cause: {source}")]
-    ConfigParseError { 
+    ConfigParseError {
         #[from]
-        source: serde_json::Error
+        source: serde_json::Error,
     },
+
+    #[error("config: invalid value for {field}, cause: {reason}")]
+    InvalidField { field: String, reason: String },
 }
 
-#[derive(Deserialize)]
-pub struct Config {
+pub const DEFAULT_DATA_DIR: &str = "/data/skipper";
+
+// fields may be overridden by environment variables named SKIPPER_<SECTION>_<FIELD>, with
+// nested fields separated by a double underscore
+const ENV_PREFIX: &str = "SKIPPER_";
+const ENV_NESTED_SEPARATOR: &str = "__";
+
+// slots fields which were at the top level, before the config was split into sections
+const FLAT_SLOT_FIELDS: [&str; 2] = ["rootfs_a", "rootfs_b"];
+
+const SECTIONS: [&str; 7] = [
+    "paths",
+    "slots",
+    "bootloader",
+    "server",
+    "http",
+    "security",
+    "policy",
+];
+
+fn default_data_dir() -> String {
+    String::from(DEFAULT_DATA_DIR)
+}
+
+fn default_control_socket() -> String {
+    String::from("/run/skipper/control.sock")
+}
+
+fn default_cmdline() -> String {
+    String::from("/proc/cmdline")
+}
+
+fn default_slot_arg() -> String {
+    String::from("skipper.slot")
+}
+
+fn default_io_idle() -> bool {
+    true
+}
+
+fn default_max_metadata_size() -> u64 {
+    DEFAULT_MAX_METADATA_SIZE
+}
+
+fn default_poll_interval() -> u64 {
+    3600
+}
+
+fn default_retry_interval() -> u64 {
+    60
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn invalid(field: &str, reason: &str) -> ConfigError {
+    ConfigError::InvalidField {
+        field: field.to_owned(),
+        reason: reason.to_owned(),
+    }
+}
+
+/// Where skipper keeps its state, and the control socket of skipperd.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PathsConfig {
+    #[serde(default = "default_data_dir")]
+    pub data_dir: String,
+    #[serde(default = "default_control_socket")]
+    pub control_socket: String,
+}
+
+/// The block devices of the A and B rootfs slots.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SlotsConfig {
     pub rootfs_a: String,
     pub rootfs_b: String,
 }
 
+/// How slots are shared with the bootloader. The booted slot is read from slot_arg on the
+/// kernel command line, e.g. skipper.slot=a, or else from the root device. Slot statuses are
+/// written to status_file for the bootloader integration, by default slots.json in the data dir.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BootloaderConfig {
+    #[serde(default = "default_cmdline")]
+    pub cmdline: String,
+    #[serde(default = "default_slot_arg")]
+    pub slot_arg: String,
+    pub status_file: Option<String>,
+}
+
+/// The update server polled by skipperd. Intervals are in seconds, headers are sent with update
+/// checks, downloads and reports, e.g. an Authorization header. A report of each install is
+/// POSTed to report_url, which may be relative to the url.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub url: String,
+    pub report_url: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+    #[serde(default)]
+    pub poll_jitter: u64,
+    #[serde(default = "default_retry_interval")]
+    pub retry_interval: u64,
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// Limits applied to deploys run in the background. Rates are in bytes per second, max_pressure
+/// is a PSI "some avg10" percentage, checked for cpu, io and memory.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BackgroundConfig {
+    #[serde(default = "default_io_idle")]
+    pub io_idle: bool,
+    pub nice: Option<i32>,
+    pub write_rate: Option<u64>,
+    pub download_rate: Option<u64>,
+    pub max_load: Option<f64>,
+    pub max_pressure: Option<f64>,
+}
+
+/// TLS settings for connections to the update server. Paths are to PEM files, pinned certs
+/// are sha256 fingerprints of the server certificate, in hex with optional colons.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub ca_bundle: Option<String>,
+    #[serde(default)]
+    pub pinned_certs: Vec<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+/// Settings for http connections. The proxy is used for all urls, except those whose host is
+/// listed in no_proxy, entries also match subdomains. The rate limit is in bytes per second,
+/// chunk_size is the size of each range request, timeouts are in seconds.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    pub proxy: Option<String>,
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+    pub rate_limit: Option<u64>,
+    pub chunk_size: Option<u64>,
+    pub pool_idle_timeout: Option<u64>,
+    pub pool_max_idle: Option<usize>,
+    pub tcp_keepalive: Option<u64>,
+}
+
+/// Trust settings for servers and archives. max_metadata_size is the largest checksums or
+/// manifest file read from an archive, in bytes.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SecurityConfig {
+    pub tls: Option<TlsConfig>,
+    #[serde(default = "default_max_metadata_size")]
+    pub max_metadata_size: u64,
+}
+
+/// How and when updates are installed.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    #[serde(default)]
+    pub install_strategy: InstallStrategy,
+    pub background: Option<BackgroundConfig>,
+}
+
+/// The skipper config, in sections. Only the slots section is required, missing fields of the
+/// other sections take their defaults.
+pub struct Config {
+    pub paths: PathsConfig,
+    pub slots: SlotsConfig,
+    pub bootloader: BootloaderConfig,
+    pub server: Option<ServerConfig>,
+    pub http: Option<HttpConfig>,
+    pub security: SecurityConfig,
+    pub policy: PolicyConfig,
+}
+
 static INSTANCE: OnceCell<Config> = OnceCell::new();
 
+// a missing section is read as an empty one, so each field takes its default
+fn section<T: DeserializeOwned>(
+    sections: &mut Map<String, Value>,
+    name: &str,
+) -> Result<T, ConfigError> {
+    let value = sections
+        .remove(name)
+        .unwrap_or_else(|| Value::Object(Map::new()));
+    T::deserialize(value).map_err(|err| invalid(name, &err.to_string()))
+}
+
+fn optional_section<T: DeserializeOwned>(
+    sections: &mut Map<String, Value>,
+    name: &str,
+) -> Result<Option<T>, ConfigError> {
+    match sections.remove(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => T::deserialize(value)
+            .map(Some)
+            .map_err(|err| invalid(name, &err.to_string())),
+    }
+}
+
+// objects are merged field by field, anything else is replaced
+fn merge(target: &mut Value, value: Value) {
+    match (target, value) {
+        (Value::Object(target), Value::Object(value)) => {
+            for (key, value) in value {
+                merge(target.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (target, value) => *target = value,
+    }
+}
+
+/// Overrides a field of a config before it's deserialized, the key is a dotted path such as
+/// server.poll_interval. The value is parsed as json, or used as a string if it isn't valid
+/// json.
+fn apply_override(config: &mut Value, key: &str, value: &str) -> Result<(), ConfigError> {
+    debug!("config override: {}", key);
+    let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_owned()));
+    let mut target = config;
+    for part in key.split('.') {
+        if part.is_empty() {
+            return Err(invalid(key, "expected a field such as section.field"));
+        }
+        if !target.is_object() {
+            *target = Value::Object(Map::new());
+        }
+        target = target
+            .as_object_mut()
+            .unwrap()
+            .entry(part)
+            .or_insert(Value::Null);
+    }
+    merge(target, value);
+    Ok(())
+}
+
+fn apply_env_overrides(
+    config: &mut Value,
+    overrides: &[(String, String, String)],
+) -> Result<(), ConfigError> {
+    for (name, key, field_value) in overrides {
+        apply_override(config, key, field_value).map_err(|err| env_error(err, overrides))?;
+        debug!("config override from {}", name);
+    }
+    Ok(())
+}
+
+// an invalid field which is overridden from the environment is reported with the names of the
+// variables, as the mistake may not be in the config file
+fn env_error(err: ConfigError, overrides: &[(String, String, String)]) -> ConfigError {
+    let (field, reason) = match &err {
+        ConfigError::InvalidField { field, reason } => (field, reason),
+        _ => return err,
+    };
+    let overlaps = |key: &str| {
+        key == field
+            || key.starts_with(&format!("{}.", field))
+            || field.starts_with(&format!("{}.", key))
+    };
+    let names: Vec<&str> = overrides
+        .iter()
+        .filter(|(_, key, _)| overlaps(key))
+        .map(|(name, _, _)| name.as_str())
+        .collect();
+    if names.is_empty() {
+        return err;
+    }
+    invalid(&format!("{} (set by {})", field, names.join(", ")), reason)
+}
+
+// e.g. SKIPPER_SERVER_POLL_INTERVAL overrides server.poll_interval, and
+// SKIPPER_SECURITY_TLS__CA_BUNDLE overrides security.tls.ca_bundle. Variables which don't name
+// a section are ignored, e.g. SKIPPER_CONFIG.
+fn env_key(name: &str) -> Option<String> {
+    let name = name.strip_prefix(ENV_PREFIX)?.to_lowercase();
+    let (section, field) = name.split_once('_')?;
+    if !SECTIONS.contains(&section) {
+        return None;
+    }
+    let parts: Vec<&str> = field.split(ENV_NESTED_SEPARATOR).collect();
+    if parts.iter().any(|part| part.is_empty()) {
+        return None;
+    }
+    Some(format!("{}.{}", section, parts.join(".")))
+}
+
+// returns the variable name, the key of the field it overrides, and the value
+fn env_overrides<I: Iterator<Item = (String, String)>>(vars: I) -> Vec<(String, String, String)> {
+    vars.filter_map(|(name, value)| Some((name.clone(), env_key(&name)?, value)))
+        .collect()
+}
+
+// variables which aren't unicode can't be config values
+fn env_vars() -> impl Iterator<Item = (String, String)> {
+    env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+}
+
+/// The names of the environment variables which override config fields, for tools which only
+/// load a config when asked to.
+pub fn env_override_names() -> Vec<String> {
+    env_vars()
+        .map(|(name, _)| name)
+        .filter(|name| env_key(name).is_some())
+        .collect()
+}
+
+// configs from before the slots section had the slot devices at the top level, they're moved
+// into the slots section so the old format keeps working
+fn upgrade_flat_slots(config: &mut Value) {
+    let sections = match config {
+        Value::Object(sections) => sections,
+        _ => return,
+    };
+    if sections.contains_key("slots")
+        || !FLAT_SLOT_FIELDS
+            .iter()
+            .any(|field| sections.contains_key(*field))
+    {
+        return;
+    }
+    warn!("the config has rootfs_a and rootfs_b at the top level, move them into a slots section");
+    let mut slots = Map::new();
+    for field in FLAT_SLOT_FIELDS.iter() {
+        if let Some(value) = sections.remove(*field) {
+            slots.insert((*field).to_owned(), value);
+        }
+    }
+    sections.insert(String::from("slots"), Value::Object(slots));
+}
+
+fn validate_url(field: &str, url: &str) -> Result<Url, ConfigError> {
+    let parsed = Url::parse(url).map_err(|err| invalid(field, &err.to_string()))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(invalid(
+            field,
+            &format!("expected an http(s) url, got {}", url),
+        ));
+    }
+    Ok(parsed)
+}
+
 impl Config {
     pub fn get() -> &'static Config {
         INSTANCE
@@ -36,20 +396,188 @@ impl Config {
             .expect("config instance was fetched before it was initialized")
     }
 
-    pub fn load_config<P: AsRef<Path>>(config_path: Option<P>) -> Result<Config,ConfigError> {
+    pub fn try_get() -> Option<&'static Config> {
+        INSTANCE.get()
+    }
+
+    /// Sets the global config instance, this may only be done once.
+    pub fn init(config: Config) -> &'static Config {
+        if INSTANCE.set(config).is_err() {
+            panic!("config instance was initialized twice");
+        }
+        Config::get()
+    }
+
+    pub fn load_config<P: AsRef<Path>>(config_path: Option<P>) -> Result<Config, ConfigError> {
+        Config::load_with_overrides(config_path, &[] as &[&str])
+    }
+
+    /// Loads the config file, then applies overrides from SKIPPER_<SECTION>_<FIELD> environment
+    /// variables, e.g. SKIPPER_SERVER_URL, then overrides given as section.field=value, e.g.
+    /// from --set on the command line. Nested fields are separated by a double underscore in
+    /// variable names, e.g. SKIPPER_SECURITY_TLS__CA_BUNDLE.
+    ///
+    /// The config is validated once every override is applied, an invalid field which is set
+    /// from the environment is reported with the names of the variables.
+    pub fn load_with_overrides<P: AsRef<Path>, S: AsRef<str>>(
+        config_path: Option<P>,
+        overrides: &[S],
+    ) -> Result<Config, ConfigError> {
         let config_path = match &config_path {
-            Some(path) => path.as_ref(),
-            None => Path::new("/data/skipper/config.jsonc"),
+            Some(path) => path.as_ref().to_path_buf(),
+            None => Path::new(DEFAULT_DATA_DIR).join("config.jsonc"),
         };
         debug!("reading config file from {}", config_path.display());
-        let mut file = File::open(config_path)?;
+        let mut file = File::open(&config_path)?;
 
         let mut buf = String::new();
         file.read_to_string(&mut buf)?;
 
-        let config: Config = json::parse_jsonc(buf.as_str())?;
+        let mut value: Value = json::parse_jsonc(buf.as_str())?;
+        upgrade_flat_slots(&mut value);
+        let env = env_overrides(env_vars());
+        apply_env_overrides(&mut value, &env)?;
+        for config_override in overrides {
+            let config_override = config_override.as_ref();
+            let (key, field_value) = config_override
+                .split_once('=')
+                .ok_or_else(|| invalid(config_override, "expected section.field=value"))?;
+            apply_override(&mut value, key, field_value)?;
+        }
+        Config::from_value(value).map_err(|err| env_error(err, &env))
+    }
+
+    /// Parses and validates a config, without any overrides.
+    pub fn parse(buf: &str) -> Result<Config, ConfigError> {
+        let mut value = json::parse_jsonc(buf)?;
+        upgrade_flat_slots(&mut value);
+        Config::from_value(value)
+    }
+
+    fn from_value(value: Value) -> Result<Config, ConfigError> {
+        let mut sections = match value {
+            Value::Object(sections) => sections,
+            _ => return Err(invalid("config", "expected an object of sections")),
+        };
+        let config = Config {
+            paths: section(&mut sections, "paths")?,
+            slots: match sections.get("slots") {
+                Some(_) => section(&mut sections, "slots")?,
+                None => return Err(invalid("slots", "the slots section is required")),
+            },
+            bootloader: section(&mut sections, "bootloader")?,
+            server: optional_section(&mut sections, "server")?,
+            http: optional_section(&mut sections, "http")?,
+            security: section(&mut sections, "security")?,
+            policy: section(&mut sections, "policy")?,
+        };
+        if let Some(name) = sections.keys().next() {
+            if FLAT_SLOT_FIELDS.contains(&name.as_str()) {
+                return Err(invalid(
+                    name,
+                    "the config format has changed, move it into the slots section",
+                ));
+            }
+            return Err(invalid(name, "unknown config section"));
+        }
+        config.validate()?;
         Ok(config)
     }
+
+    // checks the values which serde can't, so mistakes are found when the config is loaded
+    // rather than during an update
+    fn validate(&self) -> Result<(), ConfigError> {
+        let not_empty = [
+            ("paths.data_dir", &self.paths.data_dir),
+            ("paths.control_socket", &self.paths.control_socket),
+            ("slots.rootfs_a", &self.slots.rootfs_a),
+            ("slots.rootfs_b", &self.slots.rootfs_b),
+            ("bootloader.cmdline", &self.bootloader.cmdline),
+        ];
+        for (field, value) in not_empty.iter() {
+            if value.is_empty() {
+                return Err(invalid(field, "must not be empty"));
+            }
+        }
+        if self.slots.rootfs_a == self.slots.rootfs_b {
+            return Err(invalid("slots.rootfs_b", "must differ from slots.rootfs_a"));
+        }
+        let slot_arg = &self.bootloader.slot_arg;
+        if slot_arg.is_empty() || slot_arg.contains(|c: char| c == '=' || c.is_whitespace()) {
+            return Err(invalid(
+                "bootloader.slot_arg",
+                "must be a kernel argument name, without =",
+            ));
+        }
+
+        if let Some(server) = &self.server {
+            let url = validate_url("server.url", &server.url)?;
+            if let Some(report_url) = &server.report_url {
+                url.join(report_url)
+                    .map_err(|err| invalid("server.report_url", &err.to_string()))?;
+            }
+            let intervals = [
+                ("server.poll_interval", server.poll_interval),
+                ("server.retry_interval", server.retry_interval),
+                ("server.timeout", server.timeout),
+            ];
+            for (field, interval) in intervals.iter() {
+                if *interval == 0 {
+                    return Err(invalid(field, "must be greater than 0"));
+                }
+            }
+        }
+
+        if let Some(http) = &self.http {
+            if let Some(proxy) = &http.proxy {
+                validate_url("http.proxy", proxy)?;
+            }
+            if http.chunk_size == Some(0) {
+                return Err(invalid("http.chunk_size", "must be greater than 0"));
+            }
+        }
+
+        if self.security.max_metadata_size == 0 {
+            return Err(invalid(
+                "security.max_metadata_size",
+                "must be greater than 0",
+            ));
+        }
+        if let Some(tls) = &self.security.tls {
+            if tls.client_cert.is_some() != tls.client_key.is_some() {
+                return Err(invalid(
+                    "security.tls.client_key",
+                    "client_cert and client_key must be set together",
+                ));
+            }
+            for pin in &tls.pinned_certs {
+                let hex = pin.replace(':', "");
+                if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(invalid(
+                        "security.tls.pinned_certs",
+                        &format!("expected a sha256 fingerprint in hex, got {}", pin),
+                    ));
+                }
+            }
+        }
+
+        if let Some(background) = &self.policy.background {
+            if let Some(nice) = background.nice {
+                if !(-20..=19).contains(&nice) {
+                    return Err(invalid("policy.background.nice", "must be from -20 to 19"));
+                }
+            }
+            if let Some(max_pressure) = background.max_pressure {
+                if !(0.0..=100.0).contains(&max_pressure) {
+                    return Err(invalid(
+                        "policy.background.max_pressure",
+                        "must be a percentage, from 0 to 100",
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -63,7 +591,239 @@ mod test {
 
         let config_path = test_path("config/config.jsonc");
         let config = Config::load_config(Some(config_path)).unwrap();
-        assert_eq!(config.rootfs_a, "/tmp/rootfs_a");
-        assert_eq!(config.rootfs_b, "/tmp/rootfs_b");
+        assert_eq!(config.slots.rootfs_a, "/tmp/rootfs_a");
+        assert_eq!(config.slots.rootfs_b, "/tmp/rootfs_b");
+        assert_eq!(config.paths.data_dir, DEFAULT_DATA_DIR);
+        assert_eq!(config.bootloader.slot_arg, "skipper.slot");
+        assert!(config.server.is_none());
+        assert_eq!(config.policy.install_strategy, InstallStrategy::Stream);
+        assert_eq!(config.security.max_metadata_size, DEFAULT_MAX_METADATA_SIZE);
+    }
+
+    #[test]
+    fn server() {
+        init_logging();
+
+        let config_path = test_path("config/server.jsonc");
+        let config = Config::load_config(Some(config_path)).unwrap();
+        let server = config.server.unwrap();
+        assert_eq!(server.url, "http://updates.example.com/device.json");
+        assert_eq!(server.poll_interval, 600);
+        assert_eq!(server.poll_jitter, 60);
+        assert_eq!(server.retry_interval, default_retry_interval());
+        assert_eq!(server.headers["Authorization"], "Bearer device-token");
+        assert_eq!(config.paths.data_dir, "/tmp/skipper");
+        assert_eq!(
+            config.bootloader.status_file.as_deref(),
+            Some("/boot/skipper/slots.json")
+        );
+        assert_eq!(config.policy.install_strategy, InstallStrategy::Staged);
+        assert_eq!(config.security.max_metadata_size, 4194304);
+
+        let background = config.policy.background.unwrap();
+        assert!(background.io_idle);
+        assert_eq!(background.nice, Some(10));
+        assert_eq!(background.write_rate, Some(4194304));
+        assert_eq!(background.max_load, Some(3.5));
+        assert_eq!(background.max_pressure, None);
+
+        let tls = config.security.tls.unwrap();
+        assert_eq!(tls.ca_bundle.as_deref(), Some("/etc/skipper/ca.pem"));
+        assert_eq!(tls.pinned_certs.len(), 1);
+        assert_eq!(tls.client_cert.as_deref(), Some("/etc/skipper/device.pem"));
+
+        let http = config.http.unwrap();
+        assert_eq!(http.proxy.as_deref(), Some("http://proxy.example.com:3128"));
+        assert_eq!(http.no_proxy, vec!["localhost", "example.local"]);
+        assert_eq!(http.rate_limit, Some(1048576));
+        assert_eq!(http.chunk_size, Some(65536));
+        assert_eq!(http.pool_idle_timeout, None);
+    }
+
+    #[test]
+    fn overrides() {
+        init_logging();
+
+        let vars = vec![
+            (
+                String::from("SKIPPER_PATHS_DATA_DIR"),
+                String::from("/var/lib/skipper"),
+            ),
+            (
+                String::from("SKIPPER_SERVER_POLL_INTERVAL"),
+                String::from("60"),
+            ),
+            (
+                String::from("SKIPPER_SECURITY_TLS__CA_BUNDLE"),
+                String::from("/etc/ssl/ca.pem"),
+            ),
+            (
+                String::from("SKIPPER_CONFIG"),
+                String::from("/etc/skipper.jsonc"),
+            ),
+            (String::from("SKIPPER_SERVER_"), String::from("1")),
+            (String::from("SKIPPER_HTTP_PROXY____URL"), String::from("1")),
+            (String::from("HOME"), String::from("/root")),
+        ];
+        let overrides = env_overrides(vars.into_iter());
+        assert_eq!(
+            overrides,
+            vec![
+                (
+                    String::from("SKIPPER_PATHS_DATA_DIR"),
+                    String::from("paths.data_dir"),
+                    String::from("/var/lib/skipper")
+                ),
+                (
+                    String::from("SKIPPER_SERVER_POLL_INTERVAL"),
+                    String::from("server.poll_interval"),
+                    String::from("60")
+                ),
+                (
+                    String::from("SKIPPER_SECURITY_TLS__CA_BUNDLE"),
+                    String::from("security.tls.ca_bundle"),
+                    String::from("/etc/ssl/ca.pem")
+                ),
+            ]
+        );
+
+        let config_path = test_path("config/server.jsonc");
+        let config = Config::load_with_overrides(
+            Some(&config_path),
+            &[
+                "server.poll_interval=120",
+                "server.headers={\"X-Device\": \"1234\"}",
+                "policy.install_strategy=stream",
+                "security.tls.ca_bundle=/etc/ssl/ca.pem",
+            ],
+        )
+        .unwrap();
+        let server = config.server.unwrap();
+        assert_eq!(server.poll_interval, 120);
+        // objects are merged into the config file
+        assert_eq!(server.headers.len(), 2);
+        assert_eq!(config.policy.install_strategy, InstallStrategy::Stream);
+        let tls = config.security.tls.unwrap();
+        assert_eq!(tls.ca_bundle.as_deref(), Some("/etc/ssl/ca.pem"));
+        assert_eq!(tls.pinned_certs.len(), 1);
+
+        let err = Config::load_with_overrides(Some(&config_path), &["server.poll_interval"]);
+        assert!(matches!(err, Err(ConfigError::InvalidField { .. })));
+    }
+
+    #[test]
+    fn invalid_fields() {
+        init_logging();
+        let field = |buf: &str| match Config::parse(buf) {
+            Err(ConfigError::InvalidField { field, .. }) => field,
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("config should be invalid: {}", buf),
+        };
+        let slots = r#""slots": { "rootfs_a": "/dev/mmcblk0p2", "rootfs_b": "/dev/mmcblk0p3" }"#;
+
+        assert_eq!(field("{}"), "slots");
+        assert_eq!(
+            field(r#"{ "slots": { "rootfs_a": "/dev/a", "rootfs_b": "/dev/a" } }"#),
+            "slots.rootfs_b"
+        );
+        assert_eq!(
+            field(&format!(
+                r#"{{ {}, "paths": {{ "dat_dir": "/tmp" }} }}"#,
+                slots
+            )),
+            "paths"
+        );
+        assert_eq!(
+            field(&format!(r#"{{ {}, "rootfs_a": "/dev/a" }}"#, slots)),
+            "rootfs_a"
+        );
+        assert_eq!(
+            field(&format!(
+                r#"{{ {}, "server": {{ "url": "updates.example.com" }} }}"#,
+                slots
+            )),
+            "server.url"
+        );
+        assert_eq!(
+            field(&format!(
+                r#"{{ {}, "server": {{ "url": "http://example.com", "timeout": 0 }} }}"#,
+                slots
+            )),
+            "server.timeout"
+        );
+        assert_eq!(
+            field(&format!(
+                r#"{{ {}, "security": {{ "tls": {{ "client_cert": "/etc/device.pem" }} }} }}"#,
+                slots
+            )),
+            "security.tls.client_key"
+        );
+        assert_eq!(
+            field(&format!(
+                r#"{{ {}, "policy": {{ "background": {{ "max_pressure": 150 }} }} }}"#,
+                slots
+            )),
+            "policy.background.max_pressure"
+        );
+        assert!(Config::parse(&format!("{{ {} }}", slots)).is_ok());
+    }
+
+    #[test]
+    fn invalid_env_overrides() {
+        init_logging();
+        let load = |config: &str, vars: &[(&str, &str)]| {
+            let vars = vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()));
+            let overrides = env_overrides(vars);
+            let mut value = json::parse_jsonc(config).unwrap();
+            apply_env_overrides(&mut value, &overrides)?;
+            Config::from_value(value).map_err(|err| env_error(err, &overrides))
+        };
+        let slots = r#"{ "slots": { "rootfs_a": "/dev/a", "rootfs_b": "/dev/b" } }"#;
+
+        // a variable which makes the config invalid fails the load, naming the variable
+        let field = |vars: &[(&str, &str)]| match load(slots, vars) {
+            Err(ConfigError::InvalidField { field, .. }) => field,
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("config should be invalid: {:?}", vars),
+        };
+        assert_eq!(
+            field(&[
+                ("SKIPPER_PATHS_DATA_DIR", "/var/lib/skipper"),
+                ("SKIPPER_PATHS_DEBUG", "1")
+            ]),
+            "paths (set by SKIPPER_PATHS_DATA_DIR, SKIPPER_PATHS_DEBUG)"
+        );
+        assert_eq!(
+            field(&[("SKIPPER_SERVER_URL", "ftp://example.com")]),
+            "server.url (set by SKIPPER_SERVER_URL)"
+        );
+
+        // a config which is incomplete without the environment is validated with it
+        let config = load(
+            "{}",
+            &[
+                ("SKIPPER_SLOTS_ROOTFS_A", "/dev/a"),
+                ("SKIPPER_SLOTS_ROOTFS_B", "/dev/b"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.slots.rootfs_b, "/dev/b");
+    }
+
+    #[test]
+    fn flat_slots() {
+        init_logging();
+        let config = Config::parse(r#"{ "rootfs_a": "/dev/a", "rootfs_b": "/dev/b" }"#).unwrap();
+        assert_eq!(config.slots.rootfs_a, "/dev/a");
+        assert_eq!(config.slots.rootfs_b, "/dev/b");
+
+        let err = Config::parse(
+            r#"{ "slots": { "rootfs_a": "/dev/a", "rootfs_b": "/dev/b" }, "rootfs_a": "/dev/c" }"#,
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("config format has changed"));
     }
 }
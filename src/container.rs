@@ -0,0 +1,150 @@
+use std::io::{self, Read};
+use std::str::FromStr;
+
+use log::*;
+
+use crate::archive::ArchiveError;
+use crate::checksum::Checksum;
+use crate::cpio::CpioReader;
+use crate::tar::TarReader;
+
+/// The container which holds the checksums, manifest and payload files of an archive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Cpio,
+    // ustar, including pax and gnu extensions
+    Tar,
+}
+
+impl ArchiveFormat {
+    /// Detects the format from the first bytes of an archive, every cpio header starts with the
+    /// same octal digits, anything else is read as tar.
+    pub fn detect(magic: &[u8]) -> ArchiveFormat {
+        if magic.starts_with(b"07070") {
+            ArchiveFormat::Cpio
+        } else {
+            ArchiveFormat::Tar
+        }
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "cpio" => Ok(ArchiveFormat::Cpio),
+            "tar" => Ok(ArchiveFormat::Tar),
+            _ => Err(format!("unknown archive format: {}", format)),
+        }
+    }
+}
+
+/// The type of an archive entry, taken from the file type bits of its mode.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    /// cpio stores the target as the entry data, it's read with the header so nothing is left
+    /// to read.
+    Symlink {
+        target: String,
+    },
+    CharDevice {
+        major: u32,
+        minor: u32,
+    },
+    BlockDevice {
+        major: u32,
+        minor: u32,
+    },
+    Fifo,
+    /// A regular file which shares its inode with an earlier entry. newc archives store the
+    /// data with the last link and leave earlier links empty, so the link with the data is
+    /// returned first and the empty links follow it. odc archives may store the data with every
+    /// link, so any data belongs to the target as well. tar links have no data.
+    Hardlink {
+        target: String,
+    },
+}
+
+/// A file read from an archive, in any format. The checksum of the file data is calculated as
+/// it's read.
+pub trait ArchiveEntry: io::Read {
+    fn filename(&self) -> &str;
+    fn filesize(&self) -> u64;
+    fn kind(&self) -> &EntryKind;
+
+    /// Checks the data read against the expected checksum, the file must have been read to the
+    /// end.
+    fn finalise(&mut self, cksum_expected: Checksum) -> Result<(), ArchiveError>;
+}
+
+/// A wrapper around io::Read which counts the number of bytes read.
+#[derive(Debug)]
+pub(crate) struct PosReader<R: io::Read> {
+    pub count: usize,
+    pub inner: R,
+}
+
+impl<R: io::Read> io::Read for PosReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        trace!("reading pos: {}", self.count);
+        let count = self.inner.read(buf)?;
+        self.count += count;
+        Ok(count)
+    }
+}
+
+const MAGIC_LEN: usize = 6;
+
+// the magic bytes are read to detect the format, then put back in front of the reader
+type DetectedReader<R> = io::Chain<io::Cursor<Vec<u8>>, R>;
+
+/// Reads the entries of an archive in either format.
+pub enum ContainerReader<R: io::Read> {
+    Cpio(CpioReader<DetectedReader<R>>),
+    Tar(TarReader<DetectedReader<R>>),
+}
+
+impl<R: io::Read> ContainerReader<R> {
+    pub fn new(mut reader: R) -> Result<ContainerReader<R>, ArchiveError> {
+        let mut magic = Vec::with_capacity(MAGIC_LEN);
+        (&mut reader)
+            .take(MAGIC_LEN as u64)
+            .read_to_end(&mut magic)
+            .map_err(|err| ArchiveError::IOError {
+                source: err,
+                context: String::from("reading archive format"),
+            })?;
+        let format = ArchiveFormat::detect(&magic);
+        debug!("archive format: {:?}", format);
+
+        let reader = io::Cursor::new(magic).chain(reader);
+        Ok(match format {
+            ArchiveFormat::Cpio => ContainerReader::Cpio(CpioReader::new(reader)),
+            ArchiveFormat::Tar => ContainerReader::Tar(TarReader::new(reader)),
+        })
+    }
+
+    pub fn format(&self) -> ArchiveFormat {
+        match self {
+            ContainerReader::Cpio(_) => ArchiveFormat::Cpio,
+            ContainerReader::Tar(_) => ArchiveFormat::Tar,
+        }
+    }
+
+    /// Returns the next entry, or None at the end of the archive. The previous entry must have
+    /// been read to the end.
+    pub fn read_next_entry(&self) -> Result<Option<Box<dyn ArchiveEntry + '_>>, ArchiveError> {
+        let entry: Option<Box<dyn ArchiveEntry + '_>> = match self {
+            ContainerReader::Cpio(reader) => reader
+                .read_next_file()?
+                .map(|file| Box::new(file) as Box<dyn ArchiveEntry>),
+            ContainerReader::Tar(reader) => reader
+                .read_next_file()?
+                .map(|file| Box::new(file) as Box<dyn ArchiveEntry>),
+        };
+        Ok(entry)
+    }
+}
@@ -0,0 +1,348 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::daemon::{Daemon, DaemonError, DaemonStatus, Event};
+use crate::history::HistoryEntry;
+use crate::staging::InstallStrategy;
+
+#[derive(Error, Debug)]
+pub enum ControlError {
+    #[error("control: io error {context}, cause: {source}")]
+    IOError { source: io::Error, context: String },
+
+    #[error("control: protocol error, cause: {reason}")]
+    ProtocolError { reason: String },
+
+    #[error("control: request failed, cause: {message}")]
+    RequestError { message: String },
+}
+
+/// A request to the daemon. The protocol is one json object per line, each request is
+/// answered by a single response, except subscribe which is followed by a stream of events.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    Install {
+        source: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        strategy: Option<InstallStrategy>,
+    },
+    Cancel,
+    Commit,
+    Rollback,
+    History,
+    Subscribe,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Ok { message: Option<String> },
+    Status(DaemonStatus),
+    History { entries: Vec<HistoryEntry> },
+    Event(Event),
+    Error { message: String },
+}
+
+impl Response {
+    fn ok(message: Option<String>) -> Response {
+        Response::Ok { message }
+    }
+}
+
+impl From<DaemonError> for Response {
+    fn from(err: DaemonError) -> Self {
+        Response::Error {
+            message: err.to_string(),
+        }
+    }
+}
+
+fn map_io_err(context: &str) -> impl Fn(io::Error) -> ControlError + '_ {
+    move |err| ControlError::IOError {
+        source: err,
+        context: context.to_owned(),
+    }
+}
+
+fn write_message<T: Serialize>(stream: &mut UnixStream, message: &T) -> io::Result<()> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())
+}
+
+fn read_message<T: for<'de> Deserialize<'de>>(
+    reader: &mut BufReader<UnixStream>,
+) -> Result<Option<T>, ControlError> {
+    let mut line = String::new();
+    if reader
+        .read_line(&mut line)
+        .map_err(map_io_err("reading message"))?
+        == 0
+    {
+        return Ok(None);
+    }
+    serde_json::from_str(&line)
+        .map(Some)
+        .map_err(|err| ControlError::ProtocolError {
+            reason: err.to_string(),
+        })
+}
+
+fn handle_request(daemon: &Daemon, request: Request) -> Response {
+    let result = match request {
+        Request::Status => return Response::Status(daemon.status()),
+        Request::History => match daemon.history() {
+            Ok(entries) => return Response::History { entries },
+            Err(err) => Err(err),
+        },
+        Request::Install { source, strategy } => {
+            daemon.request_install(&source, strategy).map(|_| None)
+        }
+        Request::Cancel => daemon.cancel().map(|_| None),
+        Request::Commit => daemon.commit().map(Some),
+        Request::Rollback => daemon.rollback().map(Some),
+        Request::Subscribe => unreachable!("subscriptions are handled by the connection"),
+    };
+    match result {
+        Ok(message) => Response::ok(message),
+        Err(err) => err.into(),
+    }
+}
+
+fn handle_connection(daemon: Arc<Daemon>, stream: UnixStream) -> Result<(), ControlError> {
+    let mut reader = BufReader::new(stream.try_clone().map_err(map_io_err("cloning stream"))?);
+    let mut stream = stream;
+
+    loop {
+        let response = match read_message::<Request>(&mut reader) {
+            Ok(None) => return Ok(()),
+            Ok(Some(Request::Subscribe)) => break,
+            Ok(Some(request)) => {
+                debug!("control request: {:?}", request);
+                handle_request(&daemon, request)
+            }
+            Err(err) => Response::Error {
+                message: err.to_string(),
+            },
+        };
+        write_message(&mut stream, &response).map_err(map_io_err("writing response"))?;
+    }
+
+    // the connection is used for events only, until the client disconnects
+    let events = daemon.subscribe();
+    write_message(&mut stream, &Response::ok(None)).map_err(map_io_err("writing response"))?;
+    for event in events {
+        write_message(&mut stream, &Response::Event(event)).map_err(map_io_err("writing event"))?;
+    }
+    Ok(())
+}
+
+/// Listens for control connections on a unix socket, the socket is only accessible by the
+/// daemon's user.
+pub fn serve(daemon: Arc<Daemon>, path: &Path) -> Result<(), ControlError> {
+    let context = format!("binding control socket: {}", path.display());
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(map_io_err(&context))?;
+    }
+    // a socket left by a previous run would fail the bind
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            return Err(map_io_err(&context)(err));
+        }
+        _ => (),
+    }
+    let listener = UnixListener::bind(path).map_err(map_io_err(&context))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600)).map_err(map_io_err(&context))?;
+    info!("control socket listening on {}", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("failed to accept control connection: {}", err);
+                    continue;
+                }
+            };
+            let daemon = daemon.clone();
+            thread::spawn(move || {
+                if let Err(err) = handle_connection(daemon, stream) {
+                    debug!("control connection closed: {}", err);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// A client for the control socket.
+pub struct ControlClient {
+    stream: UnixStream,
+    reader: BufReader<UnixStream>,
+}
+
+impl ControlClient {
+    pub fn connect(path: &Path) -> Result<ControlClient, ControlError> {
+        let context = format!("connecting to control socket: {}", path.display());
+        let stream = UnixStream::connect(path).map_err(map_io_err(&context))?;
+        let reader = BufReader::new(stream.try_clone().map_err(map_io_err(&context))?);
+        Ok(ControlClient { stream, reader })
+    }
+
+    fn read_response(&mut self) -> Result<Response, ControlError> {
+        read_message(&mut self.reader)?.ok_or_else(|| ControlError::ProtocolError {
+            reason: String::from("connection closed by daemon"),
+        })
+    }
+
+    /// Sends a request, returning the response. Error responses are returned as errors.
+    pub fn request(&mut self, request: &Request) -> Result<Response, ControlError> {
+        write_message(&mut self.stream, request).map_err(map_io_err("writing request"))?;
+        match self.read_response()? {
+            Response::Error { message } => Err(ControlError::RequestError { message }),
+            response => Ok(response),
+        }
+    }
+
+    /// Subscribes to daemon events, the iterator ends when the daemon closes the connection.
+    pub fn subscribe(
+        mut self,
+    ) -> Result<impl Iterator<Item = Result<Event, ControlError>>, ControlError> {
+        self.request(&Request::Subscribe)?;
+        Ok(std::iter::from_fn(move || {
+            match read_message(&mut self.reader) {
+                Ok(Some(Response::Event(event))) => Some(Ok(event)),
+                Ok(Some(response)) => Some(Err(ControlError::ProtocolError {
+                    reason: format!("expected an event, got: {:?}", response),
+                })),
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::daemon::test::write_update;
+    use crate::daemon::Activity;
+    use crate::test_utils::*;
+
+    fn start_daemon() -> (Arc<Daemon>, std::path::PathBuf) {
+        let daemon = Arc::new(Daemon::new(None, &make_tempfile_path()).unwrap());
+        let socket = make_tempfile_path().join("control.sock");
+        serve(daemon.clone(), &socket).unwrap();
+
+        let run_daemon = daemon.clone();
+        thread::spawn(move || run_daemon.run());
+        (daemon, socket)
+    }
+
+    #[test]
+    fn protocol_format() {
+        let request: Request =
+            serde_json::from_str(r#"{ "command": "install", "source": "/tmp/a.cpio" }"#).unwrap();
+        assert_eq!(
+            request,
+            Request::Install {
+                source: String::from("/tmp/a.cpio"),
+                strategy: None,
+            }
+        );
+        let request: Request = serde_json::from_str(
+            r#"{ "command": "install", "source": "/tmp/a.cpio", "strategy": "staged" }"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            request,
+            Request::Install {
+                strategy: Some(InstallStrategy::Staged),
+                ..
+            }
+        ));
+
+        let response = Response::Event(Event::Committed {
+            version: String::from("1.0"),
+        });
+        let line = serde_json::to_string(&response).unwrap();
+        assert_eq!(
+            line,
+            r#"{"type":"event","event":"committed","version":"1.0"}"#
+        );
+        assert_eq!(serde_json::from_str::<Response>(&line).unwrap(), response);
+    }
+
+    #[test]
+    fn install_and_commit() {
+        init_logging();
+        let (_daemon, socket) = start_daemon();
+        let root = make_tempfile_path();
+        fs::create_dir(&root).unwrap();
+        let dest = make_tempfile_path();
+        let archive = write_update(&root, "1.0.0", &dest);
+
+        let events = ControlClient::connect(&socket)
+            .unwrap()
+            .subscribe()
+            .unwrap();
+        let mut client = ControlClient::connect(&socket).unwrap();
+        match client.request(&Request::Status).unwrap() {
+            Response::Status(status) => assert_eq!(status.activity, Activity::Idle),
+            response => panic!("unexpected response: {:?}", response),
+        }
+        assert!(client.request(&Request::Commit).is_err());
+
+        let request = Request::Install {
+            source: archive.to_str().unwrap().to_owned(),
+            strategy: None,
+        };
+        client.request(&request).unwrap();
+
+        // wait for the install to complete
+        for event in events {
+            match event.unwrap() {
+                Event::InstallFinished { version } => {
+                    assert_eq!(version, "1.0.0");
+                    break;
+                }
+                Event::InstallFailed { error } => panic!("install failed: {}", error),
+                _ => (),
+            }
+        }
+        assert!(dest.exists());
+
+        let response = client.request(&Request::Commit).unwrap();
+        assert_eq!(response, Response::ok(Some(String::from("1.0.0"))));
+        match client.request(&Request::History).unwrap() {
+            Response::History { entries } => {
+                assert_eq!(entries.len(), 1);
+                assert_eq!(entries[0].version.as_deref(), Some("1.0.0"));
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn invalid_request() {
+        init_logging();
+        let (_daemon, socket) = start_daemon();
+
+        let mut stream = UnixStream::connect(&socket).unwrap();
+        stream.write_all(b"{ \"command\": \"reboot\" }\n").unwrap();
+        let mut reader = BufReader::new(stream);
+        let response: Response = read_message(&mut reader).unwrap().unwrap();
+        assert!(matches!(response, Response::Error { .. }));
+    }
+}
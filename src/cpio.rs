@@ -1,4 +1,7 @@
 use std::cell;
+use std::collections::hash_map::{self, HashMap};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::io;
 use std::io::Read;
 use std::str;
@@ -7,34 +10,88 @@ use log::*;
 
 use crate::archive::ArchiveError;
 use crate::checksum::*;
+use crate::container::{ArchiveEntry, EntryKind, PosReader};
 
-const HEADER_SIZE: usize = 110;
-const MAGIC_NUMBER: &[u8] = b"070701";
+const NEWC_MAGIC: &[u8] = b"070701";
+const CRC_MAGIC: &[u8] = b"070702";
+const ODC_MAGIC: &[u8] = b"070707";
+const MAGIC_LEN: usize = 6;
 const TRAILER: &str = "TRAILER!!!";
 
-/// A wrapper around io::Read which counts the number of bytes read.
-#[derive(Debug)]
-struct PosReader<R: io::Read> {
-    pub count: usize,
-    inner: R,
+// symlink targets are read into memory, a longer target than PATH_MAX is refused
+const MAX_SYMLINK_TARGET: u32 = 4096;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFIFO: u32 = 0o010000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// The header formats which can be read, newc and crc headers are hex and padded to 4 bytes,
+/// odc headers are octal and unpadded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpioFormat {
+    Newc,
+    // newc with a checksum of the file data in the header
+    Crc,
+    // old portable format
+    Odc,
 }
 
-impl<R: io::Read> io::Read for PosReader<R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        trace!("reading pos: {}", self.count);
-        let count = self.inner.read(buf)?;
-        self.count += count;
-        Ok(count)
+impl CpioFormat {
+    fn alignment(self) -> usize {
+        match self {
+            CpioFormat::Newc | CpioFormat::Crc => 4,
+            CpioFormat::Odc => 1,
+        }
     }
 }
 
+// the fields common to every header format
+#[derive(Clone)]
+struct Header {
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u64,
+    filesize: u32,
+    dev: (u32, u32),
+    rdev: (u32, u32),
+    namesize: u32,
+    check: u32,
+}
+
+// odc stores device numbers as a single value, in the linux encoding
+fn split_dev(dev: u64) -> (u32, u32) {
+    let major = ((dev >> 8) & 0xfff) as u32;
+    let minor = ((dev & 0xff) | ((dev >> 12) & 0xfff00)) as u32;
+    (major, minor)
+}
+
 //#[derive(Debug)]
 pub struct CpioFile<'a, R: io::Read> {
     pub filename: String,
     pub filesize: u32,
+    pub format: CpioFormat,
+    pub kind: EntryKind,
+    // permission bits only, the file type is in kind
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+    pub ino: u32,
+    pub nlink: u32,
     remaining: usize,
     reader: &'a cell::RefCell<PosReader<R>>,
     cksum: Checksum,
+
+    // crc format only, the expected sum of the file data and the running sum
+    crc: Option<u32>,
+    crc_sum: u32,
 }
 
 impl<'a, R: io::Read> io::Read for CpioFile<'a, R> {
@@ -50,12 +107,36 @@ impl<'a, R: io::Read> io::Read for CpioFile<'a, R> {
         // update the running checksum
         self.cksum.update(&buf[0..bytes_read]);
 
+        if let Some(crc) = self.crc {
+            self.crc_sum = buf[0..bytes_read]
+                .iter()
+                .fold(self.crc_sum, |sum, byte| sum.wrapping_add(*byte as u32));
+            if self.remaining == 0 && self.crc_sum != crc {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("cpio header checksum mismatch in file {}", self.filename),
+                ));
+            }
+        }
+
         Ok(bytes_read)
     }
 }
 
-impl<'a, R: io::Read> CpioFile<'a, R> {
-    pub fn finalise(&mut self, cksum_expected: Checksum) -> Result<(), ArchiveError> {
+impl<'a, R: io::Read> ArchiveEntry for CpioFile<'a, R> {
+    fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    fn filesize(&self) -> u64 {
+        self.filesize as u64
+    }
+
+    fn kind(&self) -> &EntryKind {
+        &self.kind
+    }
+
+    fn finalise(&mut self, cksum_expected: Checksum) -> Result<(), ArchiveError> {
         assert_eq!(self.remaining, 0);
 
         self.cksum.finalise();
@@ -75,8 +156,21 @@ fn map_read_err(count: usize) -> impl FnOnce(io::Error) -> ArchiveError {
     }
 }
 
+fn parse_error<E: std::error::Error + 'static>(err: E) -> ArchiveError {
+    ArchiveError::ParseError(Box::new(err))
+}
+
 pub struct CpioReader<R: io::Read> {
     reader: cell::RefCell<PosReader<R>>,
+    // the data of the previous entry is padded to this alignment
+    alignment: cell::Cell<usize>,
+    // the name which holds the data for each (dev, ino) of a regular file with more than one link
+    links: cell::RefCell<HashMap<(u32, u32, u32), String>>,
+    // newc links seen before the link which holds the data, they have no data of their own
+    held: cell::RefCell<Vec<(Header, CpioFormat, String)>>,
+    // entries without data which are returned before the next header is read
+    queued: cell::RefCell<VecDeque<(Header, CpioFormat, String, EntryKind)>>,
+    trailer: cell::Cell<bool>,
 }
 
 impl<'a, R: io::Read> CpioReader<R> {
@@ -86,6 +180,11 @@ impl<'a, R: io::Read> CpioReader<R> {
                 count: 0,
                 inner: reader,
             }),
+            alignment: cell::Cell::new(1),
+            links: cell::RefCell::new(HashMap::new()),
+            held: cell::RefCell::new(Vec::new()),
+            queued: cell::RefCell::new(VecDeque::new()),
+            trailer: cell::Cell::new(false),
         }
     }
 
@@ -95,103 +194,311 @@ impl<'a, R: io::Read> CpioReader<R> {
             .read_exact(&mut buf)
             .map_err(map_read_err(reader.count))?;
 
-        let hexstr = str::from_utf8(&buf).map_err(|err| ArchiveError::ParseError(Box::new(err)))?;
-        let val = u32::from_str_radix(hexstr, 16)
-            .map_err(|err| ArchiveError::ParseError(Box::new(err)))?;
+        let hexstr = str::from_utf8(&buf).map_err(parse_error)?;
+        let val = u32::from_str_radix(hexstr, 16).map_err(parse_error)?;
+        Ok(val)
+    }
+
+    fn read_octal(
+        reader: &mut cell::RefMut<PosReader<R>>,
+        len: usize,
+    ) -> Result<u64, ArchiveError> {
+        let mut buf = [0u8; 11];
+        let buf = &mut buf[0..len];
+        reader.read_exact(buf).map_err(map_read_err(reader.count))?;
+
+        let octstr = str::from_utf8(buf).map_err(parse_error)?;
+        let val = u64::from_str_radix(octstr, 8).map_err(parse_error)?;
         Ok(val)
     }
 
+    fn skip_padding(
+        reader: &mut cell::RefMut<PosReader<R>>,
+        alignment: usize,
+    ) -> Result<(), ArchiveError> {
+        let mut trailing_buf = [0u8; 4];
+        let trailing = (alignment - (reader.count % alignment)) % alignment;
+        //trace!("trailing: {}", trailing);
+        reader
+            .read_exact(&mut trailing_buf[0..trailing])
+            .map_err(map_read_err(reader.count))?;
+        Ok(())
+    }
+
+    fn read_newc_header(reader: &mut cell::RefMut<PosReader<R>>) -> Result<Header, ArchiveError> {
+        Ok(Header {
+            ino: Self::read_hex_u32(reader)?,
+            mode: Self::read_hex_u32(reader)?,
+            uid: Self::read_hex_u32(reader)?,
+            gid: Self::read_hex_u32(reader)?,
+            nlink: Self::read_hex_u32(reader)?,
+            mtime: Self::read_hex_u32(reader)? as u64,
+            filesize: Self::read_hex_u32(reader)?,
+            dev: (Self::read_hex_u32(reader)?, Self::read_hex_u32(reader)?),
+            rdev: (Self::read_hex_u32(reader)?, Self::read_hex_u32(reader)?),
+            namesize: Self::read_hex_u32(reader)?,
+            check: Self::read_hex_u32(reader)?,
+        })
+    }
+
+    fn read_odc_header(reader: &mut cell::RefMut<PosReader<R>>) -> Result<Header, ArchiveError> {
+        // fields are at most 6 octal digits, so fit a u32, except mtime and filesize
+        let dev = Self::read_octal(reader, 6)?;
+        let ino = Self::read_octal(reader, 6)? as u32;
+        let mode = Self::read_octal(reader, 6)? as u32;
+        let uid = Self::read_octal(reader, 6)? as u32;
+        let gid = Self::read_octal(reader, 6)? as u32;
+        let nlink = Self::read_octal(reader, 6)? as u32;
+        let rdev = Self::read_octal(reader, 6)?;
+        let mtime = Self::read_octal(reader, 11)?;
+        let namesize = Self::read_octal(reader, 6)? as u32;
+        let filesize = Self::read_octal(reader, 11)?;
+        let filesize = u32::try_from(filesize).map_err(|_| ArchiveError::FormatError {
+            offset: reader.count,
+            reason: format!("unsupported file size: {}", filesize),
+        })?;
+        Ok(Header {
+            ino,
+            mode,
+            uid,
+            gid,
+            nlink,
+            mtime,
+            filesize,
+            dev: split_dev(dev),
+            rdev: split_dev(rdev),
+            namesize,
+            check: 0,
+        })
+    }
+
+    // newc writers store the data with the last link and leave the earlier links empty, so
+    // empty links are held until the data arrives and then queued as links to it
+    fn hold_link(&self, header: &Header, format: CpioFormat, filename: &str) -> bool {
+        if format == CpioFormat::Odc || header.mode & S_IFMT != S_IFREG || header.nlink < 2 {
+            return false;
+        }
+        let key = (header.dev.0, header.dev.1, header.ino);
+        if header.filesize > 0 || self.links.borrow().contains_key(&key) {
+            return false;
+        }
+        let mut held = self.held.borrow_mut();
+        let count = held
+            .iter()
+            .filter(|(held, _, _)| held.dev == header.dev && held.ino == header.ino)
+            .count();
+        // the last link of an empty file holds no data either
+        if count as u32 + 1 >= header.nlink {
+            return false;
+        }
+        held.push((header.clone(), format, filename.to_owned()));
+        true
+    }
+
+    // queues the held links to a file, or all of them once the trailer is reached
+    fn release_links(&self, header: Option<&Header>) {
+        let mut held = self.held.borrow_mut();
+        let (released, kept) = held.drain(..).partition(|(held, _, _)| match header {
+            Some(header) => held.dev == header.dev && held.ino == header.ino,
+            None => true,
+        });
+        *held = kept;
+        for (header, format, filename) in released {
+            let kind = self.link_kind(&header, &filename);
+            self.queued
+                .borrow_mut()
+                .push_back((header, format, filename, kind));
+        }
+    }
+
+    fn link_kind(&self, header: &Header, filename: &str) -> EntryKind {
+        let key = (header.dev.0, header.dev.1, header.ino);
+        match self.links.borrow_mut().entry(key) {
+            hash_map::Entry::Occupied(entry) => EntryKind::Hardlink {
+                target: entry.get().clone(),
+            },
+            hash_map::Entry::Vacant(entry) => {
+                entry.insert(filename.to_owned());
+                EntryKind::File
+            }
+        }
+    }
+
+    fn entry_kind(&self, header: &Header, filename: &str) -> Result<EntryKind, ArchiveError> {
+        let kind = match header.mode & S_IFMT {
+            S_IFREG if header.nlink > 1 => self.link_kind(header, filename),
+            S_IFREG => EntryKind::File,
+            S_IFDIR => EntryKind::Dir,
+            // the target is the file data, read once the header has been parsed
+            S_IFLNK => EntryKind::Symlink {
+                target: String::new(),
+            },
+            S_IFCHR => EntryKind::CharDevice {
+                major: header.rdev.0,
+                minor: header.rdev.1,
+            },
+            S_IFBLK => EntryKind::BlockDevice {
+                major: header.rdev.0,
+                minor: header.rdev.1,
+            },
+            S_IFIFO => EntryKind::Fifo,
+            file_type => {
+                return Err(ArchiveError::FormatError {
+                    offset: self.reader.borrow().count,
+                    reason: format!("unsupported file type {:o} for {}", file_type, filename),
+                })
+            }
+        };
+        Ok(kind)
+    }
+
+    fn new_file(
+        &'a self,
+        header: &Header,
+        format: CpioFormat,
+        filename: String,
+        kind: EntryKind,
+        crc: Option<u32>,
+    ) -> CpioFile<'a, R> {
+        CpioFile {
+            filesize: header.filesize,
+            format,
+            kind,
+            mode: header.mode & !S_IFMT,
+            uid: header.uid,
+            gid: header.gid,
+            mtime: header.mtime,
+            ino: header.ino,
+            nlink: header.nlink,
+            remaining: header.filesize as usize,
+            filename,
+            reader: &self.reader,
+            cksum: Checksum::new_hashable(),
+            crc,
+            crc_sum: 0,
+        }
+    }
+
     pub fn read_next_file(&'a self) -> Result<Option<CpioFile<'a, R>>, ArchiveError> {
+        loop {
+            if let Some((header, format, filename, kind)) = self.queued.borrow_mut().pop_front() {
+                return Ok(Some(self.new_file(&header, format, filename, kind, None)));
+            }
+            if self.trailer.get() {
+                return Ok(None);
+            }
+            if let Some(file) = self.read_entry()? {
+                return Ok(Some(file));
+            }
+        }
+    }
+
+    // returns None for the trailer and for held links, which are queued later
+    fn read_entry(&'a self) -> Result<Option<CpioFile<'a, R>>, ArchiveError> {
         // the previous file needs to be completely read before we get here or we'll fail
         //  if this is not the case, the cpio header checks should fail
         let mut reader = self.reader.borrow_mut();
 
         if reader.count > 0 {
-            let trailing = (4 - (reader.count % 4)) % 4;
-            //trace!("reading {} more bytes", trailing);
-            let mut trailing_buf = [0u8; 4];
-            reader
-                .read_exact(&mut trailing_buf[0..trailing as usize])
-                .map_err(map_read_err(reader.count))?;
+            Self::skip_padding(&mut reader, self.alignment.get())?;
         }
 
         let mut buf = [0u8; 256];
-        {
-            let mut buf = &mut buf[0..MAGIC_NUMBER.len()];
-            io::Read::read_exact(&mut *reader, &mut buf).map_err(map_read_err(reader.count))?;
-            debug!("magic: {}", str::from_utf8(&buf[..buf.len()]).unwrap());
+        let format = {
+            let buf = &mut buf[0..MAGIC_LEN];
+            io::Read::read_exact(&mut *reader, buf).map_err(map_read_err(reader.count))?;
+            debug!(
+                "magic: {}",
+                str::from_utf8(&buf[..buf.len()]).unwrap_or("invalid")
+            );
+
+            match &buf[..] {
+                NEWC_MAGIC => CpioFormat::Newc,
+                CRC_MAGIC => CpioFormat::Crc,
+                ODC_MAGIC => CpioFormat::Odc,
+                _ => {
+                    return Err(ArchiveError::FormatError {
+                        offset: reader.count,
+                        reason: "magic number mismatch".to_owned(),
+                    })
+                }
+            }
+        };
 
-            if buf != MAGIC_NUMBER {
+        let header = match format {
+            CpioFormat::Newc | CpioFormat::Crc => Self::read_newc_header(&mut reader)?,
+            CpioFormat::Odc => Self::read_odc_header(&mut reader)?,
+        };
+        let crc = match format {
+            CpioFormat::Newc if header.check != 0 => {
                 return Err(ArchiveError::FormatError {
                     offset: reader.count,
-                    reason: "magic number mismatch".to_owned(),
+                    reason: "check field non-zero".to_owned(),
                 });
             }
-        }
-
-        Self::read_hex_u32(&mut reader)?; //ino
-        Self::read_hex_u32(&mut reader)?; //mode
-        Self::read_hex_u32(&mut reader)?; //uid
-        Self::read_hex_u32(&mut reader)?; //gid
-        Self::read_hex_u32(&mut reader)?; //nlink
-        Self::read_hex_u32(&mut reader)?; //mtime
-        let filesize = Self::read_hex_u32(&mut reader)?;
-        Self::read_hex_u32(&mut reader)?; //dev-major
-        Self::read_hex_u32(&mut reader)?; //dev-minor
-        Self::read_hex_u32(&mut reader)?; //rdev-major
-        Self::read_hex_u32(&mut reader)?; //rdev-minor
-        let namesize = Self::read_hex_u32(&mut reader)?;
-        let check = Self::read_hex_u32(&mut reader)?;
-
-        if check != 0 {
-            return Err(ArchiveError::FormatError {
-                offset: reader.count,
-                reason: "check field non-zero".to_owned(),
-            });
-        }
+            // some tools leave the checksum empty, e.g. for entries without data
+            CpioFormat::Crc => Some(header.check).filter(|check| *check != 0),
+            _ => None,
+        };
+        let namesize = header.namesize;
 
         // this isn't a hard limit on cpio format, but we really shouldn't need filenames longer
         // than this.
-        if namesize as usize > buf.len() {
+        if namesize as usize > buf.len() || namesize == 0 {
             return Err(ArchiveError::FormatError {
                 offset: reader.count,
-                reason: format!("unexpectedly long filename size: {}", namesize).to_owned(),
+                reason: format!("unexpected filename size: {}", namesize).to_owned(),
             });
         }
 
-        let mut buf = &mut buf[0..namesize as usize];
-        reader
-            .read_exact(&mut buf)
-            .map_err(map_read_err(reader.count))?;
+        let buf = &mut buf[0..namesize as usize];
+        reader.read_exact(buf).map_err(map_read_err(reader.count))?;
 
-        let filename = str::from_utf8(&buf[..(buf.len() - 1)])
-            .map_err(|err| ArchiveError::ParseError(Box::new(err)))?;
+        let filename = str::from_utf8(&buf[..(buf.len() - 1)]).map_err(parse_error)?;
         debug!("filename: {}", filename);
 
-        // the size of the header is rounded up to the next 4-byte boundary
-        {
-            let bytes_read = HEADER_SIZE + namesize as usize;
-            let mut trailing_buf = [0u8; 4];
-            let trailing = (4 - (bytes_read % 4)) % 4;
-            //trace!("trailing: {}", trailing);
-            reader
-                .read_exact(&mut trailing_buf[0..trailing])
-                .map_err(map_read_err(reader.count))?;
-        }
+        // the size of the header is rounded up to the next 4-byte boundary for newc formats,
+        // the archive position is used since every entry starts aligned
+        Self::skip_padding(&mut reader, format.alignment())?;
+        self.alignment.set(format.alignment());
 
         if filename == TRAILER {
-            // trailer filename indicates end of archive
+            // trailer filename indicates end of archive, links still held have no data so the
+            // first of each is the file
+            self.trailer.set(true);
+            self.release_links(None);
             return Ok(None);
         }
 
-        let mut cpio_file = CpioFile {
-            filesize,
-            remaining: filesize as usize,
-            filename: String::from(filename),
-            reader: &self.reader,
-            cksum: Checksum::new_hashable(),
-        };
-        cpio_file.remaining = cpio_file.filesize as usize;
+        // the reader is borrowed again by entry_kind and to read symlink targets
+        drop(reader);
+        let filename = String::from(filename);
+        if self.hold_link(&header, format, &filename) {
+            return Ok(None);
+        }
+        let kind = self.entry_kind(&header, &filename)?;
+        if kind == EntryKind::File && header.nlink > 1 {
+            self.release_links(Some(&header));
+        }
+
+        let mut cpio_file = self.new_file(&header, format, filename, kind, crc);
+
+        if let EntryKind::Symlink { .. } = cpio_file.kind {
+            if header.filesize > MAX_SYMLINK_TARGET {
+                return Err(ArchiveError::FormatError {
+                    offset: self.reader.borrow().count,
+                    reason: format!("symlink target too long: {}", header.filesize),
+                });
+            }
+            let mut buf = Vec::with_capacity(header.filesize as usize);
+            let count = self.reader.borrow().count;
+            cpio_file
+                .read_to_end(&mut buf)
+                .map_err(map_read_err(count))?;
+            cpio_file.kind = EntryKind::Symlink {
+                target: String::from_utf8(buf).map_err(parse_error)?,
+            };
+        }
 
         Ok(Some(cpio_file))
     }
@@ -260,4 +567,130 @@ mod test {
             }
         }
     }
+
+    fn read_all(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>, ArchiveError> {
+        let reader = CpioReader::new(data);
+        let mut files = Vec::new();
+        while let Some(mut file) = reader.read_next_file()? {
+            let mut buf = Vec::new();
+            file.read_to_end(&mut buf).map_err(map_read_err(0))?;
+            files.push((file.filename.clone(), buf));
+        }
+        Ok(files)
+    }
+
+    #[test]
+    fn crc_and_odc_formats() {
+        init_logging();
+        let expected = vec![
+            (String::from("first-file"), b"data!\n".to_vec()),
+            (String::from("second-file"), b"more-data\n".to_vec()),
+        ];
+        for name in ["two-files.cpio", "two-files-crc.cpio", "two-files-odc.cpio"].iter() {
+            let data = fs::read(test_path(format!("cpio/{}", name))).unwrap();
+            assert_eq!(read_all(&data).unwrap(), expected, "{}", name);
+        }
+
+        let data = fs::read(test_path("cpio/two-files-crc.cpio")).unwrap();
+        let reader = CpioReader::new(&data[..]);
+        assert_eq!(
+            reader.read_next_file().unwrap().unwrap().format,
+            CpioFormat::Crc
+        );
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        init_logging();
+        let mut data = fs::read(test_path("cpio/two-files-crc.cpio")).unwrap();
+        let pos = data
+            .windows(6)
+            .position(|window| window == b"data!\n")
+            .unwrap();
+        data[pos] = b'D';
+        let err = read_all(&data).unwrap_err();
+        assert!(matches!(err, ArchiveError::IOError { .. }));
+
+        // the same change in a newc archive isn't detected by the reader
+        let mut data = fs::read(test_path("cpio/two-files.cpio")).unwrap();
+        data[pos] = b'D';
+        assert!(read_all(&data).is_ok());
+    }
+
+    #[test]
+    fn entry_types() {
+        init_logging();
+        for name in ["entry-types.cpio", "entry-types-odc.cpio"].iter() {
+            let file = fs::File::open(test_path(format!("cpio/{}", name))).unwrap();
+            let reader = CpioReader::new(file);
+            let mut entries = HashMap::new();
+            while let Some(mut file) = reader.read_next_file().unwrap() {
+                let mut buf = Vec::new();
+                file.read_to_end(&mut buf).unwrap();
+                assert_eq!((file.uid, file.gid), (1000, 1000));
+                assert_eq!(file.mtime, 1635120000);
+                entries.insert(file.filename.clone(), (file.kind.clone(), file.mode, buf));
+            }
+
+            assert_eq!(entries["dir"].0, EntryKind::Dir);
+            assert_eq!(entries["dir"].1, 0o750);
+            assert_eq!(entries["dir/fifo"].0, EntryKind::Fifo);
+            assert_eq!(
+                entries["dir/symlink"].0,
+                EntryKind::Symlink {
+                    target: String::from("file")
+                }
+            );
+            assert!(entries["dir/symlink"].2.is_empty());
+            assert_eq!(
+                entries["dir/null"].0,
+                EntryKind::CharDevice { major: 1, minor: 3 }
+            );
+            assert_eq!(
+                entries["dir/loop"].0,
+                EntryKind::BlockDevice { major: 7, minor: 0 }
+            );
+
+            assert_eq!(entries["dir/file"].1, 0o644);
+            if *name == "entry-types.cpio" {
+                // newc stores the data with the last link
+                assert_eq!(entries["dir/link"].0, EntryKind::File);
+                assert_eq!(entries["dir/link"].2, b"hello\n");
+                assert_eq!(
+                    entries["dir/file"].0,
+                    EntryKind::Hardlink {
+                        target: String::from("dir/link")
+                    }
+                );
+                assert!(entries["dir/file"].2.is_empty());
+            } else {
+                // odc stores the data with every link
+                assert_eq!(entries["dir/file"].0, EntryKind::File);
+                assert_eq!(entries["dir/file"].2, b"hello\n");
+                assert_eq!(
+                    entries["dir/link"].0,
+                    EntryKind::Hardlink {
+                        target: String::from("dir/file")
+                    }
+                );
+                assert_eq!(entries["dir/link"].2, b"hello\n");
+            }
+        }
+    }
+
+    #[test]
+    fn newc_link_order() {
+        init_logging();
+        // the empty link is held until the link with the data has been returned
+        let data = fs::read(test_path("cpio/entry-types.cpio")).unwrap();
+        let names: Vec<String> = read_all(&data)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        let link = names.iter().position(|name| name == "dir/link").unwrap();
+        let file = names.iter().position(|name| name == "dir/file").unwrap();
+        assert_eq!(file, link + 1);
+        assert_eq!(names.len(), 7);
+    }
 }
@@ -0,0 +1,1278 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::*;
+use rand::Rng;
+use reqwest::blocking::Client;
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::archive::{Archive, ArchiveError};
+use crate::cancel::{self, CancelToken};
+use crate::component::{InstalledComponents, COMPONENTS_FILENAME};
+use crate::config::{BackgroundConfig, Config, HttpConfig, ServerConfig, TlsConfig};
+use crate::error_report::ErrorReport;
+use crate::history::{self, HistoryEntry, HISTORY_FILENAME};
+use crate::http_reader::{self, HttpError, HttpReader};
+use crate::slot::{self, Slot, SlotStatus};
+use crate::staging::{self, InstallStrategy, Staging, StagingError};
+use crate::throttle::Throttle;
+use crate::tls;
+use crate::utils::unix_time;
+
+const STATE_FILENAME: &str = "daemon-state.json";
+
+// staged installs are downloaded here, within the data dir
+const STAGING_DIRNAME: &str = "staging";
+
+// reported as the filename in progress events while a staged install downloads
+const DOWNLOAD_PROGRESS_NAME: &str = "download";
+
+// caps the exponential backoff so the shift can't overflow
+const MAX_BACKOFF_SHIFT: u32 = 16;
+
+// used for local installs when there is no update server config
+const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+const UNKNOWN_VERSION: &str = "unknown";
+
+#[derive(Error, Debug)]
+pub enum DaemonError {
+    #[error("daemon: http error, cause: {source}")]
+    HttpError {
+        #[from]
+        source: HttpError,
+    },
+
+    #[error("daemon: archive error, cause: {source}")]
+    ArchiveError {
+        #[from]
+        source: ArchiveError,
+    },
+
+    #[error("daemon: staging error, cause: {source}")]
+    StagingError { source: StagingError },
+
+    #[error("daemon: io error {context}, cause: {source}")]
+    IOError { source: io::Error, context: String },
+
+    #[error("daemon: state file error in {path}, cause: {source}")]
+    StateError { path: String, source: io::Error },
+
+    #[error("daemon: unexpected server response, cause: {reason}")]
+    ResponseError { reason: String },
+
+    #[error("daemon: request rejected, cause: {reason}")]
+    RequestError { reason: String },
+}
+
+impl From<reqwest::Error> for DaemonError {
+    fn from(err: reqwest::Error) -> Self {
+        DaemonError::HttpError {
+            source: HttpError::from(err),
+        }
+    }
+}
+
+impl From<StagingError> for DaemonError {
+    fn from(err: StagingError) -> Self {
+        match err {
+            // a cancelled download is reported the same as a cancelled deploy
+            StagingError::IOError { source, .. } if cancel::is_cancelled_error(&source) => {
+                DaemonError::ArchiveError {
+                    source: ArchiveError::Cancelled,
+                }
+            }
+            StagingError::ArchiveError { source } => DaemonError::ArchiveError { source },
+            err => DaemonError::StagingError { source: err },
+        }
+    }
+}
+
+/// An update offered by the server, the urls may be relative to the server url. Mirrors of the
+/// url are used, in order, if downloads from the url fail.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct UpdateOffer {
+    pub version: String,
+    pub url: String,
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+}
+
+/// Daemon state which is persisted across restarts.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct DaemonState {
+    pub installed_version: Option<String>,
+    // installed to the inactive slot, but not yet committed
+    pub pending_version: Option<String>,
+    // the slot the pending version was installed to, if the install used the A/B slots
+    pub pending_slot: Option<Slot>,
+    // rolled back, so not offered for install again
+    pub rejected_version: Option<String>,
+    // unix time in seconds
+    pub last_check: Option<u64>,
+    #[serde(default)]
+    pub failures: u32,
+    pub last_error: Option<String>,
+}
+
+impl DaemonState {
+    /// Loads the state, a missing state file is treated as a fresh install.
+    pub fn load(path: &Path) -> Result<DaemonState, DaemonError> {
+        let map_err = |err: io::Error| DaemonError::StateError {
+            path: path.display().to_string(),
+            source: err,
+        };
+        let buf = match fs::read_to_string(path) {
+            Ok(buf) => buf,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(DaemonState::default()),
+            Err(err) => return Err(map_err(err)),
+        };
+        serde_json::from_str(&buf).map_err(|err| map_err(io::Error::from(err)))
+    }
+
+    /// Saves the state, via a rename so a partially written file is never seen.
+    pub fn save(&self, path: &Path) -> Result<(), DaemonError> {
+        let map_err = |err: io::Error| DaemonError::StateError {
+            path: path.display().to_string(),
+            source: err,
+        };
+        let tmp_path = path.with_extension("tmp");
+        let buf = serde_json::to_string_pretty(self).map_err(|err| map_err(err.into()))?;
+        fs::write(&tmp_path, buf).map_err(map_err)?;
+        fs::rename(&tmp_path, path).map_err(map_err)
+    }
+
+    fn is_known_version(&self, version: &str) -> bool {
+        [
+            &self.installed_version,
+            &self.pending_version,
+            &self.rejected_version,
+        ]
+        .iter()
+        .any(|known| known.as_deref() == Some(version))
+    }
+}
+
+/// What the daemon is currently doing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "activity", rename_all = "snake_case")]
+pub enum Activity {
+    Idle,
+    Checking,
+    // requested over the control socket, waiting for the daemon loop to start it
+    Queued {
+        source: String,
+    },
+    Installing {
+        source: String,
+        version: Option<String>,
+        filename: Option<String>,
+        bytes_written: u64,
+        size: u64,
+    },
+}
+
+/// Events published to subscribers of the control socket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    InstallStarted {
+        source: String,
+        version: Option<String>,
+    },
+    Progress {
+        filename: String,
+        bytes_written: u64,
+        size: u64,
+    },
+    InstallFinished {
+        version: String,
+    },
+    InstallFailed {
+        error: String,
+    },
+    InstallCancelled,
+    Committed {
+        version: String,
+    },
+    RolledBack {
+        version: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DaemonStatus {
+    #[serde(flatten)]
+    pub activity: Activity,
+    pub installed_version: Option<String>,
+    pub pending_version: Option<String>,
+    pub last_check: Option<u64>,
+    pub failures: u32,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PollResult {
+    UpToDate,
+    Installed(String),
+}
+
+// work queued for the daemon loop by the control socket
+enum Task {
+    Install(String, Option<InstallStrategy>),
+}
+
+fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// Polls the update server, and installs any newly offered version to the inactive slot.
+/// Installs may also be requested locally, see the control module. The daemon is shared
+/// between the polling loop and control connections, so all state is behind a lock.
+pub struct Daemon {
+    server: Option<ServerConfig>,
+    background: Option<BackgroundConfig>,
+    strategy: InstallStrategy,
+    staging: Staging,
+    tls: Option<TlsConfig>,
+    http: Option<HttpConfig>,
+    client: Client,
+    state_path: PathBuf,
+    components_path: PathBuf,
+    history_path: PathBuf,
+    // the slots which commit and rollback act on
+    slot_config: Option<&'static Config>,
+    state: Mutex<DaemonState>,
+    activity: Mutex<Activity>,
+    subscribers: Mutex<Vec<Sender<Event>>>,
+    // set while an install is running
+    cancel: Mutex<Option<CancelToken>>,
+    task_sender: Mutex<Sender<Task>>,
+    task_receiver: Mutex<Receiver<Task>>,
+}
+
+impl Daemon {
+    /// Creates a daemon, which only polls for updates if a server is configured.
+    pub fn new(server: Option<ServerConfig>, data_dir: &Path) -> Result<Daemon, DaemonError> {
+        fs::create_dir_all(data_dir).map_err(|err| DaemonError::StateError {
+            path: data_dir.display().to_string(),
+            source: err,
+        })?;
+        let state_path = data_dir.join(STATE_FILENAME);
+        let state = DaemonState::load(&state_path)?;
+        let timeout = server
+            .as_ref()
+            .map(|server| Duration::from_secs(server.timeout))
+            .unwrap_or(DEFAULT_HTTP_TIMEOUT);
+        let client = Client::builder().timeout(timeout).build()?;
+        let (task_sender, task_receiver) = mpsc::channel();
+
+        Ok(Daemon {
+            server,
+            background: None,
+            strategy: InstallStrategy::default(),
+            staging: Staging::new(&data_dir.join(STAGING_DIRNAME)),
+            tls: None,
+            http: None,
+            client,
+            state_path,
+            components_path: data_dir.join(COMPONENTS_FILENAME),
+            history_path: data_dir.join(HISTORY_FILENAME),
+            slot_config: Config::try_get(),
+            state: Mutex::new(state),
+            activity: Mutex::new(Activity::Idle),
+            subscribers: Mutex::new(Vec::new()),
+            cancel: Mutex::new(None),
+            task_sender: Mutex::new(task_sender),
+            task_receiver: Mutex::new(task_receiver),
+        })
+    }
+
+    /// The config whose slot statuses are set by commit and rollback, by default the loaded
+    /// config.
+    pub fn set_slot_config(&mut self, config: &'static Config) {
+        self.slot_config = Some(config);
+    }
+
+    /// Installs are throttled according to the background config.
+    pub fn set_background(&mut self, background: BackgroundConfig) {
+        self.background = Some(background);
+    }
+
+    /// The strategy used for installs which don't request one, including server updates.
+    pub fn set_install_strategy(&mut self, strategy: InstallStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// Server connections, for both update checks and downloads, use the tls settings.
+    pub fn set_tls(&mut self, tls: TlsConfig) -> Result<(), DaemonError> {
+        self.tls = Some(tls);
+        self.client = self.build_client()?;
+        Ok(())
+    }
+
+    /// Server connections use the proxy and connection settings, downloads are also limited
+    /// to the http rate limit.
+    pub fn set_http(&mut self, http: HttpConfig) -> Result<(), DaemonError> {
+        self.http = Some(http);
+        self.client = self.build_client()?;
+        Ok(())
+    }
+
+    // the client used for update checks
+    fn build_client(&self) -> Result<Client, DaemonError> {
+        let mut builder = Client::builder().timeout(self.http_timeout());
+        if let Some(tls) = &self.tls {
+            builder = tls::configure(builder, tls)?;
+        }
+        if let Some(http) = &self.http {
+            builder = http_reader::configure(builder, http)?;
+        }
+        Ok(builder.build()?)
+    }
+
+    pub fn state(&self) -> DaemonState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn status(&self) -> DaemonStatus {
+        let state = self.state.lock().unwrap();
+        DaemonStatus {
+            activity: self.activity.lock().unwrap().clone(),
+            installed_version: state.installed_version.clone(),
+            pending_version: state.pending_version.clone(),
+            last_check: state.last_check,
+            failures: state.failures,
+            last_error: state.last_error.clone(),
+        }
+    }
+
+    /// Returns every install recorded in the history file, oldest first.
+    pub fn history(&self) -> Result<Vec<HistoryEntry>, DaemonError> {
+        history::read(&self.history_path).map_err(|err| DaemonError::IOError {
+            source: err,
+            context: format!("reading install history: {}", self.history_path.display()),
+        })
+    }
+
+    /// Returns a channel which receives all events from now on.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn emit(&self, event: Event) {
+        // subscribers which have gone away are dropped
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+
+    fn set_activity(&self, activity: Activity) {
+        *self.activity.lock().unwrap() = activity;
+    }
+
+    fn update_state<F: FnOnce(&mut DaemonState)>(&self, update: F) -> Result<(), DaemonError> {
+        let mut state = self.state.lock().unwrap();
+        update(&mut state);
+        state.save(&self.state_path)
+    }
+
+    fn update_components<F: FnOnce(&mut InstalledComponents)>(
+        &self,
+        update: F,
+    ) -> Result<(), DaemonError> {
+        let mut components = InstalledComponents::load(&self.components_path)?;
+        update(&mut components);
+        Ok(components.save(&self.components_path)?)
+    }
+
+    fn http_timeout(&self) -> Duration {
+        self.server
+            .as_ref()
+            .map(|server| Duration::from_secs(server.timeout))
+            .unwrap_or(DEFAULT_HTTP_TIMEOUT)
+    }
+
+    /// Fetches the offered update, returning None if it is already installed.
+    pub fn check_for_update(&self) -> Result<Option<UpdateOffer>, DaemonError> {
+        let server = match &self.server {
+            Some(server) => server,
+            None => return Ok(None),
+        };
+        debug!("checking for update: {}", server.url);
+        if let Some(tls) = &self.tls {
+            tls::check_url(&server.url, tls)?;
+        }
+        let mut req = self.client.get(&server.url);
+        for (name, value) in &server.headers {
+            req = req.header(name.as_str(), value.as_str());
+        }
+        let resp = req.send()?;
+        if resp.status() == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            return Err(DaemonError::ResponseError {
+                reason: format!("server returned {}", resp.status()),
+            });
+        }
+
+        let mut offer: UpdateOffer =
+            serde_json::from_str(&resp.text()?).map_err(|err| DaemonError::ResponseError {
+                reason: err.to_string(),
+            })?;
+        let resolve = |url: &str| {
+            Url::parse(&server.url)
+                .and_then(|base| base.join(url))
+                .map(String::from)
+                .map_err(|err| DaemonError::ResponseError {
+                    reason: format!("invalid update url: {}, {}", url, err),
+                })
+        };
+        offer.url = resolve(&offer.url)?;
+        offer.mirrors = offer
+            .mirrors
+            .iter()
+            .map(|mirror| resolve(mirror))
+            .collect::<Result<_, _>>()?;
+
+        if self.state.lock().unwrap().is_known_version(&offer.version) {
+            debug!("version {} is already known", offer.version);
+            return Ok(None);
+        }
+        Ok(Some(offer))
+    }
+
+    fn deploy<R: io::Read>(
+        &self,
+        reader: R,
+        version: Option<&str>,
+        cancel: &CancelToken,
+        entry: &mut HistoryEntry,
+    ) -> Result<(String, Option<Slot>), DaemonError> {
+        let mut archive = Archive::new(reader)?;
+        entry.set_archive(&archive);
+        archive.set_cancel_token(cancel.clone());
+        archive.set_components_path(&self.components_path);
+        if let Some(background) = &self.background {
+            archive.set_throttle(Throttle::new(background));
+        }
+        let version = version
+            .map(String::from)
+            .or_else(|| archive.manifest().version.clone())
+            .unwrap_or_else(|| String::from(UNKNOWN_VERSION));
+
+        let mut last_percent = None;
+        let result = archive.deploy_with_progress(|progress| {
+            self.report_progress(
+                &mut last_percent,
+                progress.filename,
+                progress.bytes_written,
+                progress.size,
+            )
+        });
+        entry.set_payloads(archive.payload_results());
+        result?;
+        Ok((version, archive.updated_slot()))
+    }
+
+    // progress events are only published as each percent completes
+    fn report_progress(
+        &self,
+        last_percent: &mut Option<(String, u64)>,
+        progress_filename: &str,
+        progress_bytes: u64,
+        progress_size: u64,
+    ) {
+        let percent = (progress_bytes * 100)
+            .checked_div(progress_size)
+            .unwrap_or(100);
+        if last_percent.as_ref() == Some(&(progress_filename.to_owned(), percent)) {
+            return;
+        }
+        *last_percent = Some((progress_filename.to_owned(), percent));
+
+        if let Activity::Installing {
+            filename,
+            bytes_written,
+            size,
+            ..
+        } = &mut *self.activity.lock().unwrap()
+        {
+            *filename = Some(progress_filename.to_owned());
+            *bytes_written = progress_bytes;
+            *size = progress_size;
+        }
+        self.emit(Event::Progress {
+            filename: progress_filename.to_owned(),
+            bytes_written: progress_bytes,
+            size: progress_size,
+        });
+    }
+
+    fn open_file(path: &str) -> Result<File, DaemonError> {
+        File::open(path).map_err(|err| DaemonError::IOError {
+            source: err,
+            context: format!("opening archive: {}", path),
+        })
+    }
+
+    // downloads and verifies the whole archive before deploying it from the staging dir
+    fn deploy_staged(
+        &self,
+        source: &str,
+        reader: HttpReader,
+        version: Option<&str>,
+        cancel: &CancelToken,
+        entry: &mut HistoryEntry,
+    ) -> Result<(String, Option<Slot>), DaemonError> {
+        let mut last_percent = None;
+        let path = self.staging.download(source, reader, |downloaded, size| {
+            self.report_progress(&mut last_percent, DOWNLOAD_PROGRESS_NAME, downloaded, size)
+        })?;
+        self.staging.verify(&path)?;
+        let file = Daemon::open_file(&path.to_string_lossy())?;
+        let result = self.deploy(file, version, cancel, entry);
+        self.staging.clear();
+        result
+    }
+
+    fn deploy_from(
+        &self,
+        source: &str,
+        mirrors: &[String],
+        version: Option<&str>,
+        strategy: InstallStrategy,
+        cancel: &CancelToken,
+        entry: &mut HistoryEntry,
+    ) -> Result<(String, Option<Slot>), DaemonError> {
+        if is_url(source) {
+            let mut builder = HttpReader::builder(source, self.http_timeout());
+            if let Some(tls) = &self.tls {
+                builder = builder.tls(tls);
+            }
+            if let Some(http) = &self.http {
+                builder = builder.http_config(http);
+            }
+            for (name, value) in self.server.iter().flat_map(|server| &server.headers) {
+                builder = builder.header(name, value);
+            }
+            for mirror in mirrors {
+                builder = builder.mirror(mirror);
+            }
+            let mut reader = builder.build()?;
+            reader.set_cancel_token(cancel.clone());
+            // the lower of the background and http rate limits applies
+            let rates = [
+                self.background.as_ref().and_then(|bg| bg.download_rate),
+                self.http.as_ref().and_then(|http| http.rate_limit),
+            ];
+            if let Some(rate) = rates.iter().flatten().min() {
+                reader.set_rate_limit(*rate);
+            }
+            return match strategy {
+                InstallStrategy::Stream => self.deploy(reader, version, cancel, entry),
+                InstallStrategy::Staged => {
+                    self.deploy_staged(source, reader, version, cancel, entry)
+                }
+            };
+        }
+        // local archives are already staged, but are still verified before deploying
+        let path = source.strip_prefix("file://").unwrap_or(source);
+        if strategy == InstallStrategy::Staged {
+            staging::verify_archive(Path::new(path))?;
+        }
+        self.deploy(Daemon::open_file(path)?, version, cancel, entry)
+    }
+
+    /// Installs an archive from a url or local path, to the inactive slot, using the default
+    /// install strategy. The installed version is pending until committed.
+    pub fn install(&self, source: &str, version: Option<&str>) -> Result<String, DaemonError> {
+        self.install_with_strategy(source, version, self.strategy)
+    }
+
+    /// As install, with the archive either streamed or staged before deploying.
+    pub fn install_with_strategy(
+        &self,
+        source: &str,
+        version: Option<&str>,
+        strategy: InstallStrategy,
+    ) -> Result<String, DaemonError> {
+        self.install_from(source, &[], version, strategy)
+    }
+
+    fn install_from(
+        &self,
+        source: &str,
+        mirrors: &[String],
+        version: Option<&str>,
+        strategy: InstallStrategy,
+    ) -> Result<String, DaemonError> {
+        info!(
+            "installing {} from {} ({:?})",
+            version.unwrap_or(UNKNOWN_VERSION),
+            source,
+            strategy
+        );
+        self.set_activity(Activity::Installing {
+            source: source.to_owned(),
+            version: version.map(String::from),
+            filename: None,
+            bytes_written: 0,
+            size: 0,
+        });
+        self.emit(Event::InstallStarted {
+            source: source.to_owned(),
+            version: version.map(String::from),
+        });
+
+        let mut entry = HistoryEntry::new(source);
+        let cancel = CancelToken::new();
+        *self.cancel.lock().unwrap() = Some(cancel.clone());
+        let result = self.deploy_from(source, mirrors, version, strategy, &cancel, &mut entry);
+        let (result, pending_slot) = match result {
+            Ok((version, slot)) => (Ok(version), slot),
+            Err(err) => (Err(err), None),
+        };
+        *self.cancel.lock().unwrap() = None;
+        self.set_activity(Activity::Idle);
+
+        entry.finish(result.as_ref().err().map(ErrorReport::from).as_ref());
+        self.record_history(&entry);
+
+        if let Ok(version) = &result {
+            self.update_state(|state| {
+                state.pending_version = Some(version.clone());
+                state.pending_slot = pending_slot;
+            })?;
+        }
+
+        match &result {
+            Ok(version) => {
+                info!("version {} installed", version);
+                self.emit(Event::InstallFinished {
+                    version: version.clone(),
+                });
+            }
+            Err(DaemonError::ArchiveError {
+                source: ArchiveError::Cancelled,
+            }) => {
+                info!("install from {} cancelled", source);
+                self.emit(Event::InstallCancelled);
+            }
+            Err(err) => self.emit(Event::InstallFailed {
+                error: err.to_string(),
+            }),
+        }
+        result
+    }
+
+    // history and reports are best effort, they never fail an install
+    fn record_history(&self, entry: &HistoryEntry) {
+        if let Err(err) = history::append(&self.history_path, entry) {
+            warn!(
+                "failed to write install history {}: {}",
+                self.history_path.display(),
+                err
+            );
+        }
+        if let Some(server) = &self.server {
+            let result = history::report_url(server).and_then(|url| match url {
+                Some(url) => history::post_report(&self.client, &url, &server.headers, entry),
+                None => Ok(()),
+            });
+            if let Err(err) = result {
+                warn!("failed to send install report: {}", err);
+            }
+        }
+    }
+
+    /// Queues an install to be run by the daemon loop, the default strategy is used if none is
+    /// given.
+    pub fn request_install(
+        &self,
+        source: &str,
+        strategy: Option<InstallStrategy>,
+    ) -> Result<(), DaemonError> {
+        // the activity stays locked until the install is queued, so only one can be queued
+        let mut activity = self.activity.lock().unwrap();
+        if *activity != Activity::Idle {
+            return Err(DaemonError::RequestError {
+                reason: String::from("an update is already in progress"),
+            });
+        }
+        self.task_sender
+            .lock()
+            .unwrap()
+            .send(Task::Install(source.to_owned(), strategy))
+            .map_err(|_| DaemonError::RequestError {
+                reason: String::from("daemon loop is not running"),
+            })?;
+        *activity = Activity::Queued {
+            source: source.to_owned(),
+        };
+        Ok(())
+    }
+
+    /// Cancels the running install, the inactive slot is left marked invalid.
+    pub fn cancel(&self) -> Result<(), DaemonError> {
+        match &*self.cancel.lock().unwrap() {
+            Some(cancel) => {
+                cancel.cancel();
+                Ok(())
+            }
+            None => Err(DaemonError::RequestError {
+                reason: String::from("no install is in progress"),
+            }),
+        }
+    }
+
+    fn set_slot_status(&self, slot: Slot, status: SlotStatus) -> Result<(), DaemonError> {
+        let config = self.slot_config.ok_or_else(|| DaemonError::RequestError {
+            reason: format!("no slot config, cannot mark slot {:?} {:?}", slot, status),
+        })?;
+        Ok(slot::set_slot_status(config, slot, status)?)
+    }
+
+    /// Accepts the pending version as installed. The slot it was installed to is marked good,
+    /// so the bootloader keeps booting it, and the versions of components written to the slot
+    /// are recorded as installed.
+    pub fn commit(&self) -> Result<String, DaemonError> {
+        let mut state = self.state.lock().unwrap();
+        let version = state
+            .pending_version
+            .clone()
+            .ok_or_else(|| DaemonError::RequestError {
+                reason: String::from("no pending version to commit"),
+            })?;
+        if let Some(slot) = state.pending_slot {
+            self.set_slot_status(slot, SlotStatus::Good)?;
+            self.update_components(InstalledComponents::commit_pending)?;
+        }
+        state.installed_version = state.pending_version.take();
+        state.pending_slot = None;
+        state.save(&self.state_path)?;
+        drop(state);
+        info!("committed version {}", version);
+        self.emit(Event::Committed {
+            version: version.clone(),
+        });
+        Ok(version)
+    }
+
+    /// Discards the pending version, which won't be installed from the server again. The slot
+    /// it was installed to is marked invalid and the other slot good, so the bootloader boots
+    /// the other slot, even if the pending version is running.
+    pub fn rollback(&self) -> Result<String, DaemonError> {
+        let mut state = self.state.lock().unwrap();
+        let version = state
+            .pending_version
+            .clone()
+            .ok_or_else(|| DaemonError::RequestError {
+                reason: String::from("no pending version to roll back"),
+            })?;
+        if let Some(slot) = state.pending_slot {
+            self.set_slot_status(slot, SlotStatus::Invalid)?;
+            self.set_slot_status(slot.other(), SlotStatus::Good)?;
+            self.update_components(InstalledComponents::discard_pending)?;
+        }
+        state.rejected_version = state.pending_version.take();
+        state.pending_slot = None;
+        state.save(&self.state_path)?;
+        drop(state);
+        info!("rolled back version {}", version);
+        self.emit(Event::RolledBack {
+            version: version.clone(),
+        });
+        Ok(version)
+    }
+
+    /// Checks for, and installs, an update. The outcome is recorded in the persisted state.
+    pub fn poll(&self) -> Result<PollResult, DaemonError> {
+        self.set_activity(Activity::Checking);
+        let offer = self.check_for_update();
+        self.set_activity(Activity::Idle);
+        let result = offer.and_then(|offer| match offer {
+            Some(offer) => {
+                self.install_from(
+                    &offer.url,
+                    &offer.mirrors,
+                    Some(&offer.version),
+                    self.strategy,
+                )?;
+                Ok(PollResult::Installed(offer.version))
+            }
+            None => Ok(PollResult::UpToDate),
+        });
+
+        self.update_state(|state| {
+            state.last_check = Some(unix_time());
+            match &result {
+                Ok(_) => {
+                    state.failures = 0;
+                    state.last_error = None;
+                }
+                Err(err) => {
+                    state.failures += 1;
+                    state.last_error = Some(err.to_string());
+                }
+            }
+        })?;
+        result
+    }
+
+    /// The delay before the next poll, which backs off after failures. None if there is no
+    /// server to poll.
+    pub fn next_delay(&self) -> Option<Duration> {
+        let failures = self.state.lock().unwrap().failures;
+        self.server
+            .as_ref()
+            .map(|server| next_delay(server, failures))
+    }
+
+    /// Polls the server, and runs installs requested over the control socket.
+    pub fn run(&self) -> ! {
+        loop {
+            let delay = self.next_delay();
+            if let Some(delay) = delay {
+                debug!("next poll in {} secs", delay.as_secs());
+            }
+            let task = {
+                let receiver = self.task_receiver.lock().unwrap();
+                match delay {
+                    Some(delay) => receiver.recv_timeout(delay).ok(),
+                    None => receiver.recv().ok(),
+                }
+            };
+
+            match task {
+                Some(Task::Install(source, strategy)) => {
+                    let strategy = strategy.unwrap_or(self.strategy);
+                    if let Err(err) = self.install_with_strategy(&source, None, strategy) {
+                        error!("install from {} failed: {}", source, err);
+                    }
+                }
+                None => match self.poll() {
+                    Ok(PollResult::UpToDate) => debug!("no update available"),
+                    Ok(PollResult::Installed(version)) => info!("update to {} complete", version),
+                    Err(err) => error!("update failed: {}", err),
+                },
+            }
+        }
+    }
+}
+
+fn next_delay(server: &ServerConfig, failures: u32) -> Duration {
+    let base = match failures {
+        0 => server.poll_interval,
+        failures => {
+            let shift = u32::min(failures - 1, MAX_BACKOFF_SHIFT);
+            u64::min(
+                server.retry_interval.saturating_mul(1 << shift),
+                server.poll_interval,
+            )
+        }
+    };
+    // jitter spreads out polls from many devices which started at the same time
+    let jitter = server.poll_jitter;
+    let offset = rand::thread_rng().gen_range(0..=2 * jitter);
+    let delay = (base + offset).saturating_sub(jitter);
+    Duration::from_secs(u64::max(delay, 1))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::test_server::*;
+    use crate::test_utils::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn server_config(url: String) -> ServerConfig {
+        ServerConfig {
+            url,
+            report_url: None,
+            headers: Default::default(),
+            poll_interval: 600,
+            poll_jitter: 0,
+            retry_interval: 10,
+            timeout: 5,
+        }
+    }
+
+    /// Writes an archive with a single image payload to dest, returning the archive path.
+    pub fn write_update(root: &Path, version: &str, dest: &Path) -> PathBuf {
+        let manifest = format!(
+            r#"{{
+                "version": "{}",
+                "payloads": [
+                    {{ "type": "image", "filename": "rootfs.img", "dest": "{}" }}
+                ]
+            }}"#,
+            version,
+            dest.display()
+        );
+        let image = fs::read(test_path("archive/test.img")).unwrap();
+        let archive = make_archive(&manifest, &[("rootfs.img", &image)]);
+        let path = root.join("update.cpio");
+        fs::write(&path, archive).unwrap();
+        path
+    }
+
+    fn serve_update(root: &Path, version: &str, dest: &Path) {
+        write_update(root, version, dest);
+        let offer = format!(r#"{{ "version": "{}", "url": "update.cpio" }}"#, version);
+        fs::write(root.join("update.json"), offer).unwrap();
+    }
+
+    fn make_root() -> PathBuf {
+        let root = make_tempfile_path();
+        fs::create_dir(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn installs_new_version() {
+        init_logging();
+        let root = make_root();
+        let data_dir = make_tempfile_path();
+        let dest = make_tempfile_path();
+        serve_update(&root, "1.1.0", &dest);
+
+        let server = create_test_server(TestServerArgs::new(root.to_str().unwrap()));
+        let url = format!("http://127.0.0.1:{}/update.json", server.port);
+        let daemon = Daemon::new(Some(server_config(url.clone())), &data_dir).unwrap();
+
+        let result = daemon.poll().unwrap();
+        assert_eq!(result, PollResult::Installed(String::from("1.1.0")));
+        assert_eq!(
+            fs::read(&dest).unwrap(),
+            fs::read(test_path("archive/test.img")).unwrap()
+        );
+
+        // the pending version is persisted, so it is not installed again
+        let daemon = Daemon::new(Some(server_config(url)), &data_dir).unwrap();
+        assert_eq!(daemon.state().pending_version.as_deref(), Some("1.1.0"));
+        assert_eq!(daemon.poll().unwrap(), PollResult::UpToDate);
+        assert_eq!(daemon.history().unwrap().len(), 1);
+
+        assert_eq!(daemon.commit().unwrap(), "1.1.0");
+        assert_eq!(daemon.state().installed_version.as_deref(), Some("1.1.0"));
+        assert!(daemon.commit().is_err());
+    }
+
+    #[test]
+    fn history_and_report() {
+        init_logging();
+        let root = make_root();
+        let data_dir = make_tempfile_path();
+        let dest = make_tempfile_path();
+        serve_update(&root, "1.4.0", &dest);
+
+        let server = create_test_server(TestServerArgs::new(root.to_str().unwrap()));
+        let mut config = server_config(format!("http://127.0.0.1:{}/update.json", server.port));
+        config.report_url = Some(String::from("report.json"));
+        let daemon = Daemon::new(Some(config), &data_dir).unwrap();
+        daemon.poll().unwrap();
+        assert!(daemon.install("/does/not/exist.cpio", None).is_err());
+
+        let entries = history::read(&data_dir.join(HISTORY_FILENAME)).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].state, history::DeployState::Succeeded);
+        assert_eq!(entries[0].version.as_deref(), Some("1.4.0"));
+        assert!(entries[0].archive_hash.is_some());
+        assert_eq!(entries[0].payloads[0].filename, "rootfs.img");
+        assert_eq!(entries[0].bytes_written, fs::metadata(&dest).unwrap().len());
+        assert_eq!(entries[1].state, history::DeployState::Failed);
+        assert_eq!(entries[1].error_kind.as_deref(), Some("source_error"));
+
+        // the report of the latest install is posted to the server
+        let report: HistoryEntry =
+            serde_json::from_slice(&fs::read(root.join("report.json")).unwrap()).unwrap();
+        assert_eq!(report, entries[1]);
+    }
+
+    #[test]
+    fn authenticated_server() {
+        init_logging();
+        let root = make_root();
+        let dest = make_tempfile_path();
+        serve_update(&root, "1.2.0", &dest);
+
+        let server = create_test_server(TestServerArgs::new(root.to_str().unwrap()));
+        server.set_token("device-token");
+        let url = format!("http://127.0.0.1:{}/update.json", server.port);
+        let mut config = server_config(url);
+        let daemon = Daemon::new(Some(config.clone()), &make_tempfile_path()).unwrap();
+        assert!(daemon.poll().is_err());
+
+        config.headers.insert(
+            String::from("Authorization"),
+            String::from("Bearer device-token"),
+        );
+        let daemon = Daemon::new(Some(config), &make_tempfile_path()).unwrap();
+        assert_eq!(
+            daemon.poll().unwrap(),
+            PollResult::Installed(String::from("1.2.0"))
+        );
+        assert!(dest.exists());
+    }
+
+    #[test]
+    fn offer_with_mirrors() {
+        init_logging();
+        let root = make_root();
+        let dest = make_tempfile_path();
+        write_update(&root, "1.3.0", &dest);
+        let offer = r#"{ "version": "1.3.0", "url": "missing.cpio", "mirrors": ["update.cpio"] }"#;
+        fs::write(root.join("update.json"), offer).unwrap();
+
+        let server = create_test_server(TestServerArgs::new(root.to_str().unwrap()));
+        let url = format!("http://127.0.0.1:{}/update.json", server.port);
+        let daemon = Daemon::new(Some(server_config(url.clone())), &make_tempfile_path()).unwrap();
+        let offer = daemon.check_for_update().unwrap().unwrap();
+        assert_eq!(
+            offer.mirrors,
+            vec![url.replace("update.json", "update.cpio")]
+        );
+
+        daemon.poll().unwrap();
+        assert!(dest.exists());
+    }
+
+    #[test]
+    fn rollback_rejects_version() {
+        init_logging();
+        let root = make_root();
+        let data_dir = make_tempfile_path();
+        serve_update(&root, "2.0.0", &make_tempfile_path());
+
+        let server = create_test_server(TestServerArgs::new(root.to_str().unwrap()));
+        let url = format!("http://127.0.0.1:{}/update.json", server.port);
+        let daemon = Daemon::new(Some(server_config(url)), &data_dir).unwrap();
+
+        let events = daemon.subscribe();
+        daemon.poll().unwrap();
+        assert_eq!(daemon.rollback().unwrap(), "2.0.0");
+        assert_eq!(daemon.state().pending_version, None);
+        assert_eq!(daemon.poll().unwrap(), PollResult::UpToDate);
+
+        let events: Vec<Event> = events.try_iter().collect();
+        assert!(matches!(events[0], Event::InstallStarted { .. }));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::Progress { .. })));
+        assert!(events.contains(&Event::InstallFinished {
+            version: String::from("2.0.0")
+        }));
+        assert_eq!(
+            events.last().unwrap(),
+            &Event::RolledBack {
+                version: String::from("2.0.0")
+            }
+        );
+    }
+
+    #[test]
+    fn commit_and_rollback_set_slots() {
+        init_logging();
+        let data_dir = make_tempfile_path();
+        let config = Config::parse(&format!(
+            r#"{{
+                "slots": {{ "rootfs_a": "/dev/mmcblk0p2", "rootfs_b": "/dev/mmcblk0p3" }},
+                "paths": {{ "data_dir": "{}" }}
+            }}"#,
+            data_dir.display()
+        ))
+        .unwrap();
+        let config: &'static Config = Box::leak(Box::new(config));
+        let mut daemon = Daemon::new(None, &data_dir).unwrap();
+        daemon.set_slot_config(config);
+
+        let set_pending = |version: &str| {
+            daemon
+                .update_state(|state| {
+                    state.pending_version = Some(version.to_owned());
+                    state.pending_slot = Some(Slot::B);
+                })
+                .unwrap();
+            daemon
+                .update_components(|components| components.set_pending("rootfs", version))
+                .unwrap();
+        };
+        let components = || InstalledComponents::load(&data_dir.join(COMPONENTS_FILENAME)).unwrap();
+        set_pending("1.0.0");
+        daemon.commit().unwrap();
+        assert_eq!(
+            slot::slot_status(config, Slot::B).unwrap(),
+            Some(SlotStatus::Good)
+        );
+        assert_eq!(daemon.state().pending_slot, None);
+        assert_eq!(components().version("rootfs"), Some("1.0.0"));
+
+        // the bootloader falls back to the other slot, even if the rolled back one is running
+        set_pending("1.1.0");
+        daemon.rollback().unwrap();
+        assert_eq!(
+            slot::slot_status(config, Slot::B).unwrap(),
+            Some(SlotStatus::Invalid)
+        );
+        assert_eq!(
+            slot::slot_status(config, Slot::A).unwrap(),
+            Some(SlotStatus::Good)
+        );
+        assert_eq!(daemon.state().rejected_version.as_deref(), Some("1.1.0"));
+        assert_eq!(daemon.state().installed_version.as_deref(), Some("1.0.0"));
+        assert_eq!(components().version("rootfs"), Some("1.0.0"));
+        assert_eq!(components().pending_version("rootfs"), None);
+    }
+
+    #[test]
+    fn one_install_queued() {
+        init_logging();
+        let daemon = Daemon::new(None, &make_tempfile_path()).unwrap();
+        daemon.request_install("/tmp/a.cpio", None).unwrap();
+        assert!(matches!(daemon.status().activity, Activity::Queued { .. }));
+        assert!(daemon.request_install("/tmp/b.cpio", None).is_err());
+    }
+
+    #[test]
+    fn local_install() {
+        init_logging();
+        let root = make_root();
+        let dest = make_tempfile_path();
+        let path = write_update(&root, "3.0.0", &dest);
+
+        let daemon = Daemon::new(None, &make_tempfile_path()).unwrap();
+        assert_eq!(daemon.next_delay(), None);
+        let version = daemon.install(path.to_str().unwrap(), None).unwrap();
+        assert_eq!(version, "3.0.0");
+        assert!(dest.exists());
+
+        assert!(daemon.install("/does/not/exist.cpio", None).is_err());
+        let history = daemon.history().unwrap();
+        assert_eq!(history.len(), 2);
+        assert!(history[0].error.is_none());
+        assert!(history[1].error.is_some());
+    }
+
+    #[test]
+    fn staged_install() {
+        init_logging();
+        let root = make_root();
+        let data_dir = make_tempfile_path();
+        let dest = make_tempfile_path();
+        write_update(&root, "4.0.0", &dest);
+
+        let server = create_test_server(TestServerArgs::new(root.to_str().unwrap()));
+        let url = format!("http://127.0.0.1:{}/update.cpio", server.port);
+        let mut daemon = Daemon::new(None, &data_dir).unwrap();
+        daemon.set_install_strategy(InstallStrategy::Staged);
+
+        let events = daemon.subscribe();
+        assert_eq!(daemon.install(&url, None).unwrap(), "4.0.0");
+        assert!(events.try_iter().any(|event| matches!(
+            event,
+            Event::Progress { filename, .. } if filename == DOWNLOAD_PROGRESS_NAME
+        )));
+        assert!(dest.exists());
+        // the staged archive is removed once installed
+        let staging_dir = data_dir.join(STAGING_DIRNAME);
+        assert_eq!(fs::read_dir(staging_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn cancel_install() {
+        init_logging();
+        let root = make_root();
+        let dest = make_tempfile_path();
+        let manifest = format!(
+            r#"{{ "payloads": [ {{ "type": "image", "filename": "rootfs.img", "dest": "{}" }} ] }}"#,
+            dest.display()
+        );
+        let image = crate::mtd::test::rand_image(256 * 1024);
+        let archive = make_archive(&manifest, &[("rootfs.img", &image)]);
+        fs::write(root.join("update.cpio"), archive).unwrap();
+
+        let mut server_args = TestServerArgs::new(root.to_str().unwrap());
+        server_args.response_latency(0.002);
+        let server = create_test_server(server_args);
+        let url = format!("http://127.0.0.1:{}/update.cpio", server.port);
+
+        let daemon = Arc::new(Daemon::new(None, &make_tempfile_path()).unwrap());
+        assert!(daemon.cancel().is_err());
+        let events = daemon.subscribe();
+        let installer = daemon.clone();
+        let install = thread::spawn(move || {
+            installer.install(&url, None).map_err(|err| {
+                matches!(
+                    err,
+                    DaemonError::ArchiveError {
+                        source: ArchiveError::Cancelled
+                    }
+                )
+            })
+        });
+
+        for event in events.iter() {
+            match event {
+                Event::Progress { .. } => daemon.cancel().unwrap(),
+                Event::InstallCancelled => break,
+                event => assert!(matches!(event, Event::InstallStarted { .. })),
+            }
+        }
+        assert_eq!(install.join().unwrap(), Err(true));
+        assert!(fs::metadata(&dest).unwrap().len() < image.len() as u64);
+        let history = daemon.history().unwrap();
+        assert_eq!(history[0].state, history::DeployState::Cancelled);
+        assert!(history[0].error.is_some());
+    }
+
+    #[test]
+    fn failures_are_recorded() {
+        init_logging();
+        let root = make_root();
+        let data_dir = make_tempfile_path();
+
+        let server = create_test_server(TestServerArgs::new(root.to_str().unwrap()));
+        let url = format!("http://127.0.0.1:{}/update.json", server.port);
+        let daemon = Daemon::new(Some(server_config(url)), &data_dir).unwrap();
+
+        assert!(daemon.poll().is_err());
+        assert!(daemon.poll().is_err());
+        assert_eq!(daemon.state().failures, 2);
+        assert!(daemon.state().last_error.is_some());
+        assert_eq!(daemon.next_delay(), Some(Duration::from_secs(20)));
+
+        let state = DaemonState::load(&data_dir.join(STATE_FILENAME)).unwrap();
+        assert_eq!(state.failures, 2);
+
+        serve_update(&root, "1.1.0", &make_tempfile_path());
+        daemon.poll().unwrap();
+        assert_eq!(daemon.state().failures, 0);
+        assert_eq!(daemon.next_delay(), Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn delay_backoff_and_jitter() {
+        let mut server = server_config(String::new());
+        assert_eq!(next_delay(&server, 1), Duration::from_secs(10));
+        assert_eq!(next_delay(&server, 3), Duration::from_secs(40));
+        assert_eq!(next_delay(&server, 100), Duration::from_secs(600));
+
+        server.poll_jitter = 30;
+        for _ in 0..100 {
+            let delay = next_delay(&server, 0).as_secs();
+            assert!((570..=630).contains(&delay));
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crc32fast::Hasher;
+use log::*;
+
+use crate::archive::ArchiveError;
+
+// A delta file rebuilds a target image from a source image using a list of block-level
+// copy/insert instructions. The layout is:
+//
+//  header: magic (8 bytes) | target size (u64 LE)
+//  copy:   'C' | source offset (u64 LE) | length (u64 LE)
+//  insert: 'I' | length (u64 LE) | data (length bytes)
+//
+// Operations are applied in order, each one appending to the target.
+pub const DELTA_MAGIC: &[u8] = b"SKDELTA1";
+pub const DELTA_BLOCK_SIZE: usize = 4096;
+
+const HEADER_SIZE: usize = 16;
+const OP_COPY: u8 = b'C';
+const OP_COPY_SIZE: usize = 17;
+const OP_INSERT: u8 = b'I';
+const OP_INSERT_SIZE: usize = 9;
+
+// limit on how much insert data is buffered by the encoder before it is flushed
+const MAX_INSERT_LEN: usize = 1024 * 1024;
+
+/// Receives the operations decoded from a delta stream.
+pub trait DeltaSink {
+    fn copy(&mut self, src_offset: u64, len: u64) -> Result<(), ArchiveError>;
+
+    fn insert(&mut self, buf: &[u8]) -> Result<(), ArchiveError>;
+}
+
+enum DecodeState {
+    Header,
+    Op,
+    Insert { remaining: u64 },
+}
+
+/// A streaming decoder for delta files, which can be fed arbitrarily sized blocks.
+pub struct DeltaDecoder {
+    state: DecodeState,
+    pending: Vec<u8>,
+    pos: usize,
+    target_size: u64,
+    written: u64,
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[0..8]);
+    u64::from_le_bytes(bytes)
+}
+
+impl DeltaDecoder {
+    pub fn new() -> DeltaDecoder {
+        DeltaDecoder {
+            state: DecodeState::Header,
+            pending: Vec::with_capacity(HEADER_SIZE),
+            pos: 0,
+            target_size: 0,
+            written: 0,
+        }
+    }
+
+    // accumulates bytes from the input until a complete field of len bytes is available
+    fn take_bytes(&mut self, buf: &mut &[u8], len: usize) -> Option<Vec<u8>> {
+        let count = usize::min(len - self.pending.len(), buf.len());
+        self.pending.extend_from_slice(&buf[0..count]);
+        *buf = &buf[count..];
+        self.pos += count;
+
+        if self.pending.len() == len {
+            return Some(std::mem::take(&mut self.pending));
+        }
+        None
+    }
+
+    fn format_error(&self, reason: String) -> ArchiveError {
+        ArchiveError::FormatError {
+            offset: self.pos,
+            reason,
+        }
+    }
+
+    fn check_overflow(&self, len: u64) -> Result<(), ArchiveError> {
+        // the length is read from the stream, so the sum may overflow
+        let end = self.written.checked_add(len);
+        if end.is_none_or(|end| end > self.target_size) {
+            return Err(self.format_error(format!(
+                "delta operation overflows target size: {}",
+                self.target_size
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn feed<S: DeltaSink>(&mut self, buf: &[u8], sink: &mut S) -> Result<(), ArchiveError> {
+        let mut buf = buf;
+        while !buf.is_empty() {
+            match self.state {
+                DecodeState::Header => {
+                    if let Some(header) = self.take_bytes(&mut buf, HEADER_SIZE) {
+                        if &header[0..DELTA_MAGIC.len()] != DELTA_MAGIC {
+                            return Err(self.format_error("delta magic mismatch".to_owned()));
+                        }
+                        self.target_size = read_u64(&header[8..]);
+                        debug!("delta target size: {}", self.target_size);
+                        self.state = DecodeState::Op;
+                    }
+                }
+                DecodeState::Op => {
+                    let opcode = match self.pending.first() {
+                        Some(opcode) => *opcode,
+                        None => buf[0],
+                    };
+                    let op_size = match opcode {
+                        OP_COPY => OP_COPY_SIZE,
+                        OP_INSERT => OP_INSERT_SIZE,
+                        _ => {
+                            return Err(
+                                self.format_error(format!("unknown delta opcode: {:#04x}", opcode))
+                            )
+                        }
+                    };
+                    if let Some(op) = self.take_bytes(&mut buf, op_size) {
+                        let len = read_u64(&op[op_size - 8..]);
+                        self.check_overflow(len)?;
+                        if opcode == OP_COPY {
+                            let src_offset = read_u64(&op[1..]);
+                            if src_offset.checked_add(len).is_none() {
+                                return Err(self.format_error(format!(
+                                    "delta copy from offset {} len {} overflows",
+                                    src_offset, len
+                                )));
+                            }
+                            trace!("delta copy, offset: {}, len: {}", src_offset, len);
+                            sink.copy(src_offset, len)?;
+                            self.written += len;
+                        } else if len > 0 {
+                            trace!("delta insert, len: {}", len);
+                            self.state = DecodeState::Insert { remaining: len };
+                        }
+                    }
+                }
+                DecodeState::Insert { remaining } => {
+                    let count = u64::min(remaining, buf.len() as u64) as usize;
+                    sink.insert(&buf[0..count])?;
+                    buf = &buf[count..];
+                    self.pos += count;
+                    self.written += count as u64;
+
+                    let remaining = remaining - count as u64;
+                    self.state = if remaining == 0 {
+                        DecodeState::Op
+                    } else {
+                        DecodeState::Insert { remaining }
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the delta stream ended cleanly and produced the complete target.
+    pub fn finish(&self) -> Result<(), ArchiveError> {
+        if !matches!(self.state, DecodeState::Op) || !self.pending.is_empty() {
+            return Err(self.format_error("delta stream ended mid-operation".to_owned()));
+        }
+        if self.written != self.target_size {
+            return Err(self.format_error(format!(
+                "delta produced {} bytes, expected {}",
+                self.written, self.target_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Default for DeltaDecoder {
+    fn default() -> Self {
+        DeltaDecoder::new()
+    }
+}
+
+enum PendingOp {
+    None,
+    Copy { src_offset: u64, len: u64 },
+    Insert(Vec<u8>),
+}
+
+struct DeltaEncoder<'a, W: Write> {
+    out: &'a mut W,
+    pending: PendingOp,
+}
+
+impl<'a, W: Write> DeltaEncoder<'a, W> {
+    fn flush(&mut self) -> io::Result<()> {
+        match std::mem::replace(&mut self.pending, PendingOp::None) {
+            PendingOp::None => {}
+            PendingOp::Copy { src_offset, len } => {
+                self.out.write_all(&[OP_COPY])?;
+                self.out.write_all(&src_offset.to_le_bytes())?;
+                self.out.write_all(&len.to_le_bytes())?;
+            }
+            PendingOp::Insert(data) => {
+                self.out.write_all(&[OP_INSERT])?;
+                self.out.write_all(&(data.len() as u64).to_le_bytes())?;
+                self.out.write_all(&data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn copy(&mut self, src_offset: u64, len: u64) -> io::Result<()> {
+        if let PendingOp::Copy {
+            src_offset: pending_offset,
+            len: pending_len,
+        } = &mut self.pending
+        {
+            if *pending_offset + *pending_len == src_offset {
+                *pending_len += len;
+                return Ok(());
+            }
+        }
+        self.flush()?;
+        self.pending = PendingOp::Copy { src_offset, len };
+        Ok(())
+    }
+
+    fn insert(&mut self, buf: &[u8]) -> io::Result<()> {
+        if let PendingOp::Insert(data) = &mut self.pending {
+            if data.len() + buf.len() <= MAX_INSERT_LEN {
+                data.extend_from_slice(buf);
+                return Ok(());
+            }
+        }
+        self.flush()?;
+        self.pending = PendingOp::Insert(buf.to_vec());
+        Ok(())
+    }
+}
+
+fn read_block<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    // fill the buffer, unless EOF is reached first
+    let mut count = 0;
+    while count < buf.len() {
+        let read = reader.read(&mut buf[count..])?;
+        if read == 0 {
+            break;
+        }
+        count += read;
+    }
+    Ok(count)
+}
+
+fn block_hash(buf: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(buf);
+    hasher.finalize()
+}
+
+/// Generates a delta which rebuilds target from source, writing it to out.
+///
+/// Each block of the target is matched against the blocks of the source, matching blocks are
+/// encoded as copies and everything else is inserted verbatim.
+pub fn generate_delta<S, T, W>(source: &mut S, target: &mut T, out: &mut W) -> io::Result<()>
+where
+    S: Read + Seek,
+    T: Read + Seek,
+    W: Write,
+{
+    let mut buf = vec![0u8; DELTA_BLOCK_SIZE];
+    let mut src_buf = vec![0u8; DELTA_BLOCK_SIZE];
+
+    // index the full blocks of the source by their hash
+    let mut index: HashMap<u32, Vec<u64>> = HashMap::new();
+    source.seek(SeekFrom::Start(0))?;
+    let mut offset = 0u64;
+    loop {
+        let count = read_block(source, &mut buf)?;
+        if count < DELTA_BLOCK_SIZE {
+            break;
+        }
+        index.entry(block_hash(&buf)).or_default().push(offset);
+        offset += count as u64;
+    }
+
+    let target_size = target.seek(SeekFrom::End(0))?;
+    target.seek(SeekFrom::Start(0))?;
+    out.write_all(DELTA_MAGIC)?;
+    out.write_all(&target_size.to_le_bytes())?;
+
+    let mut encoder = DeltaEncoder {
+        out,
+        pending: PendingOp::None,
+    };
+    loop {
+        let count = read_block(target, &mut buf)?;
+        if count == 0 {
+            break;
+        }
+        let block = &buf[0..count];
+
+        // prefer the candidate which continues the current copy, to keep copies contiguous
+        let next_offset = match encoder.pending {
+            PendingOp::Copy { src_offset, len } => Some(src_offset + len),
+            _ => None,
+        };
+        let mut matched = None;
+        if count == DELTA_BLOCK_SIZE {
+            if let Some(candidates) = index.get(&block_hash(block)) {
+                let mut candidates = candidates.clone();
+                if let Some(next_offset) = next_offset {
+                    candidates.sort_by_key(|offset| *offset != next_offset);
+                }
+                for candidate in candidates {
+                    source.seek(SeekFrom::Start(candidate))?;
+                    read_block(source, &mut src_buf)?;
+                    if src_buf[..] == *block {
+                        matched = Some(candidate);
+                        break;
+                    }
+                }
+            }
+        }
+
+        match matched {
+            Some(src_offset) => encoder.copy(src_offset, count as u64)?,
+            None => encoder.insert(block)?,
+        }
+    }
+    encoder.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use rand::{Rng, RngCore};
+    use std::io::Cursor;
+
+    struct VecSink<'a> {
+        source: &'a [u8],
+        target: Vec<u8>,
+    }
+
+    impl<'a> DeltaSink for VecSink<'a> {
+        fn copy(&mut self, src_offset: u64, len: u64) -> Result<(), ArchiveError> {
+            let start = src_offset as usize;
+            self.target
+                .extend_from_slice(&self.source[start..start + len as usize]);
+            Ok(())
+        }
+
+        fn insert(&mut self, buf: &[u8]) -> Result<(), ArchiveError> {
+            self.target.extend_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    fn apply_delta(source: &[u8], delta: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut decoder = DeltaDecoder::new();
+        let mut sink = VecSink {
+            source,
+            target: Vec::new(),
+        };
+        for chunk in delta.chunks(chunk_size) {
+            decoder.feed(chunk, &mut sink).unwrap();
+        }
+        decoder.finish().unwrap();
+        sink.target
+    }
+
+    fn make_delta(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut delta = Vec::new();
+        generate_delta(
+            &mut Cursor::new(source),
+            &mut Cursor::new(target),
+            &mut delta,
+        )
+        .unwrap();
+        delta
+    }
+
+    fn rand_bytes(len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        rand::thread_rng().fill_bytes(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn roundtrip() {
+        init_logging();
+        let source = rand_bytes(DELTA_BLOCK_SIZE * 16);
+
+        // move some blocks around, modify a few bytes and append a partial block
+        let mut target = Vec::new();
+        target.extend_from_slice(&source[DELTA_BLOCK_SIZE * 8..]);
+        target.extend_from_slice(&source[0..DELTA_BLOCK_SIZE * 8]);
+        let mut rng = rand::thread_rng();
+        for _ in 0..4 {
+            let idx = rng.gen_range(0..target.len());
+            target[idx] = target[idx].wrapping_add(1);
+        }
+        target.extend_from_slice(&rand_bytes(100));
+
+        let delta = make_delta(&source, &target);
+        assert!(delta.len() < target.len() / 2);
+
+        for chunk_size in [1, 7, 2048, delta.len()] {
+            assert_eq!(apply_delta(&source, &delta, chunk_size), target);
+        }
+    }
+
+    #[test]
+    fn empty_target() {
+        init_logging();
+        let source = rand_bytes(DELTA_BLOCK_SIZE);
+        let delta = make_delta(&source, &[]);
+        assert_eq!(delta.len(), HEADER_SIZE);
+        assert_eq!(apply_delta(&source, &delta, 16), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn bad_magic() {
+        init_logging();
+        let mut delta = make_delta(&rand_bytes(10), &rand_bytes(10));
+        delta[0] = b'X';
+
+        let mut sink = VecSink {
+            source: &[],
+            target: Vec::new(),
+        };
+        let err = DeltaDecoder::new().feed(&delta, &mut sink).unwrap_err();
+        assert!(matches!(err, ArchiveError::FormatError { .. }));
+    }
+
+    #[test]
+    fn truncated() {
+        init_logging();
+        let source = rand_bytes(DELTA_BLOCK_SIZE);
+        let delta = make_delta(&source, &rand_bytes(DELTA_BLOCK_SIZE));
+
+        let mut decoder = DeltaDecoder::new();
+        let mut sink = VecSink {
+            source: &source,
+            target: Vec::new(),
+        };
+        decoder.feed(&delta[0..delta.len() - 1], &mut sink).unwrap();
+        assert!(decoder.finish().is_err());
+    }
+
+    #[test]
+    fn overflowing_ops() {
+        init_logging();
+        let mut header = DELTA_MAGIC.to_vec();
+        header.extend_from_slice(&16u64.to_le_bytes());
+        let mut sink = VecSink {
+            source: &[],
+            target: Vec::new(),
+        };
+
+        // an insert which wraps the written length past the target size check
+        let mut delta = header.clone();
+        delta.push(OP_INSERT);
+        delta.extend_from_slice(&8u64.to_le_bytes());
+        delta.extend_from_slice(&[0; 8]);
+        delta.push(OP_INSERT);
+        delta.extend_from_slice(&u64::MAX.to_le_bytes());
+        let err = DeltaDecoder::new().feed(&delta, &mut sink).unwrap_err();
+        assert!(matches!(err, ArchiveError::FormatError { .. }));
+
+        // a copy whose source range wraps
+        let mut delta = header;
+        delta.push(OP_COPY);
+        delta.extend_from_slice(&u64::MAX.to_le_bytes());
+        delta.extend_from_slice(&8u64.to_le_bytes());
+        let err = DeltaDecoder::new().feed(&delta, &mut sink).unwrap_err();
+        assert!(matches!(err, ArchiveError::FormatError { .. }));
+    }
+}
@@ -0,0 +1,101 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::*;
+
+use crate::archive::ArchiveError;
+
+const SYS_BLOCK_PATH: &str = "/sys/block";
+
+/// Returns the device name if the path is an eMMC hardware boot partition, e.g. mmcblk0boot1.
+pub fn boot_partition_name(dest: &Path) -> Option<String> {
+    // device nodes are often reached through symlinks
+    let dest = fs::canonicalize(dest).unwrap_or_else(|_| dest.to_path_buf());
+    let name = dest.file_name()?.to_str()?;
+
+    let rest = name.strip_prefix("mmcblk")?;
+    let (device, partition) = rest.split_once("boot")?;
+    let is_number = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    if is_number(device) && is_number(partition) {
+        return Some(name.to_owned());
+    }
+    None
+}
+
+/// Boot partitions are read-only by default. This guard makes the partition writable, and
+/// restores read-only mode when dropped.
+pub struct ForceRoGuard {
+    force_ro_path: PathBuf,
+}
+
+impl ForceRoGuard {
+    fn set_force_ro(path: &Path, value: &str) -> Result<(), ArchiveError> {
+        fs::write(path, value).map_err(|err| ArchiveError::IOError {
+            source: err,
+            context: format!("writing {} to {}", value, path.display()),
+        })
+    }
+}
+
+impl Drop for ForceRoGuard {
+    fn drop(&mut self) {
+        match ForceRoGuard::set_force_ro(&self.force_ro_path, "1") {
+            Ok(()) => debug!("restored {}", self.force_ro_path.display()),
+            Err(err) => warn!("failed to restore boot partition read-only mode: {}", err),
+        }
+    }
+}
+
+fn unlock_boot_partition_in(
+    sys_block: &Path,
+    dest: &Path,
+) -> Result<Option<ForceRoGuard>, ArchiveError> {
+    let name = match boot_partition_name(dest) {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+
+    let force_ro_path = sys_block.join(name).join("force_ro");
+    ForceRoGuard::set_force_ro(&force_ro_path, "0")?;
+    debug!("unlocked boot partition: {}", force_ro_path.display());
+    Ok(Some(ForceRoGuard { force_ro_path }))
+}
+
+/// Makes dest writable if it is an eMMC boot partition, returning a guard which locks it again.
+pub fn unlock_boot_partition(dest: &Path) -> Result<Option<ForceRoGuard>, ArchiveError> {
+    unlock_boot_partition_in(Path::new(SYS_BLOCK_PATH), dest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn partition_names() {
+        init_logging();
+        let name = |path: &str| boot_partition_name(Path::new(path));
+        assert_eq!(name("/dev/mmcblk0boot0").unwrap(), "mmcblk0boot0");
+        assert_eq!(name("/dev/mmcblk12boot1").unwrap(), "mmcblk12boot1");
+        assert!(name("/dev/mmcblk0p1").is_none());
+        assert!(name("/dev/mmcblk0boot").is_none());
+        assert!(name("/dev/sda").is_none());
+    }
+
+    #[test]
+    fn force_ro_restored() {
+        init_logging();
+        let sys_block = make_tempfile_path();
+        let force_ro = sys_block.join("mmcblk0boot0").join("force_ro");
+        fs::create_dir_all(force_ro.parent().unwrap()).unwrap();
+        fs::write(&force_ro, "1").unwrap();
+
+        let guard = unlock_boot_partition_in(&sys_block, Path::new("/dev/mmcblk0boot0")).unwrap();
+        assert_eq!(fs::read_to_string(&force_ro).unwrap(), "0");
+        drop(guard);
+        assert_eq!(fs::read_to_string(&force_ro).unwrap(), "1");
+
+        let guard = unlock_boot_partition_in(&sys_block, Path::new("/dev/mmcblk0p1")).unwrap();
+        assert!(guard.is_none());
+    }
+}
@@ -0,0 +1,295 @@
+//! Maps errors to stable exit codes and one line reports, for tools which run skip-deploy.
+//!
+//! | code | kind                       | retry |
+//! |------|----------------------------|-------|
+//! | 0    | success                    |       |
+//! | 1    | invalid arguments          | no    |
+//! | 2    | config error               | no    |
+//! | 3    | source could not be opened | no    |
+//! | 10   | network error              | yes   |
+//! | 11   | unexpected server response | yes   |
+//! | 12   | tls error                  | no    |
+//! | 13   | authentication rejected    | no    |
+//! | 20   | archive corrupt or invalid | no    |
+//! | 21   | delta source mismatch      | no    |
+//! | 22   | component version conflict | no    |
+//! | 30   | deploy failed              | no    |
+//! | 31   | install script failed      | no    |
+//! | 40   | cancelled                  | yes   |
+
+use std::io;
+
+use serde::Serialize;
+
+use crate::archive::ArchiveError;
+use crate::config::ConfigError;
+use crate::daemon::DaemonError;
+use crate::http_reader::HttpError;
+use crate::staging::StagingError;
+
+pub const EXIT_USAGE: i32 = 1;
+pub const EXIT_CONFIG: i32 = 2;
+pub const EXIT_SOURCE: i32 = 3;
+pub const EXIT_NETWORK: i32 = 10;
+pub const EXIT_SERVER_RESPONSE: i32 = 11;
+pub const EXIT_TLS: i32 = 12;
+pub const EXIT_AUTH: i32 = 13;
+pub const EXIT_ARCHIVE_CORRUPT: i32 = 20;
+pub const EXIT_DELTA_SOURCE: i32 = 21;
+pub const EXIT_COMPONENT: i32 = 22;
+pub const EXIT_DEPLOY: i32 = 30;
+pub const EXIT_SCRIPT: i32 = 31;
+pub const EXIT_CANCELLED: i32 = 40;
+
+/// A machine readable description of an error, printed by skip-deploy --json.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ErrorReport {
+    pub kind: &'static str,
+    pub exit_code: i32,
+    pub retryable: bool,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+}
+
+impl ErrorReport {
+    fn new(kind: &'static str, exit_code: i32, message: String) -> ErrorReport {
+        ErrorReport {
+            kind,
+            exit_code,
+            retryable: matches!(
+                exit_code,
+                EXIT_NETWORK | EXIT_SERVER_RESPONSE | EXIT_CANCELLED
+            ),
+            // reports are always a single line
+            message: message.replace('\n', " "),
+            filename: None,
+            offset: None,
+            context: None,
+        }
+    }
+
+    fn with_filename(mut self, filename: &str) -> ErrorReport {
+        self.filename = Some(filename.to_owned());
+        self
+    }
+
+    fn with_context(mut self, context: &str) -> ErrorReport {
+        self.context = Some(context.to_owned());
+        self
+    }
+
+    /// The archive source, a file or stdin, could not be opened.
+    pub fn source(err: &io::Error, source: &str) -> ErrorReport {
+        ErrorReport::new(
+            "source_error",
+            EXIT_SOURCE,
+            format!("failed to open {}, cause: {}", source, err),
+        )
+        .with_filename(source)
+    }
+
+    pub fn usage(message: &str) -> ErrorReport {
+        ErrorReport::new("usage_error", EXIT_USAGE, message.to_owned())
+    }
+}
+
+// errors from HttpReader reach the archive wrapped in an io::Error
+fn http_error_report(err: &io::Error) -> Option<ErrorReport> {
+    let inner = err.get_ref()?;
+    if let Some(http_err) = inner.downcast_ref::<HttpError>() {
+        return Some(ErrorReport::from(http_err));
+    }
+    if inner.is::<reqwest::Error>() {
+        return Some(ErrorReport::new(
+            "network_error",
+            EXIT_NETWORK,
+            err.to_string(),
+        ));
+    }
+    None
+}
+
+impl From<&ArchiveError> for ErrorReport {
+    fn from(err: &ArchiveError) -> Self {
+        let message = err.to_string();
+        match err {
+            ArchiveError::IOError { source, context } => {
+                let message = format!("{} {}, cause: {}", message, context, source);
+                let report = match http_error_report(source) {
+                    Some(report) => ErrorReport { message, ..report },
+                    None => ErrorReport::new("io_error", EXIT_DEPLOY, message),
+                };
+                report.with_context(context)
+            }
+            ArchiveError::ParseError(_) => {
+                ErrorReport::new("parse_error", EXIT_ARCHIVE_CORRUPT, message)
+            }
+            ArchiveError::FormatError { offset, .. } => {
+                let mut report = ErrorReport::new("format_error", EXIT_ARCHIVE_CORRUPT, message);
+                report.offset = Some(*offset);
+                report
+            }
+            ArchiveError::FileNotFoundError { .. } => {
+                ErrorReport::new("file_not_found", EXIT_ARCHIVE_CORRUPT, message)
+            }
+            ArchiveError::MetadataSizeError { filename, .. } => {
+                ErrorReport::new("metadata_size", EXIT_ARCHIVE_CORRUPT, message)
+                    .with_filename(filename)
+            }
+            ArchiveError::ChecksumFormatError { .. } => {
+                ErrorReport::new("checksum_format", EXIT_ARCHIVE_CORRUPT, message)
+            }
+            ArchiveError::ChecksumMissingError { filename } => {
+                ErrorReport::new("checksum_missing", EXIT_ARCHIVE_CORRUPT, message)
+                    .with_filename(filename)
+            }
+            ArchiveError::ChecksumMismatchError { filename } => {
+                ErrorReport::new("checksum_mismatch", EXIT_ARCHIVE_CORRUPT, message)
+                    .with_filename(filename)
+            }
+            ArchiveError::SourceChecksumMismatchError { path } => {
+                ErrorReport::new("delta_source_mismatch", EXIT_DELTA_SOURCE, message)
+                    .with_filename(path)
+            }
+            ArchiveError::ManifestParseError(_) => {
+                ErrorReport::new("manifest_parse", EXIT_ARCHIVE_CORRUPT, message)
+            }
+            ArchiveError::ManifestFormatError { .. } => {
+                ErrorReport::new("manifest_format", EXIT_ARCHIVE_CORRUPT, message)
+            }
+            ArchiveError::Utf8Error { .. } => {
+                ErrorReport::new("utf8_error", EXIT_ARCHIVE_CORRUPT, message)
+            }
+            ArchiveError::UnknownPayload(_) => {
+                ErrorReport::new("unknown_payload", EXIT_ARCHIVE_CORRUPT, message)
+            }
+            ArchiveError::PayloadDeployError { .. } => {
+                ErrorReport::new("payload_deploy", EXIT_DEPLOY, message)
+            }
+            ArchiveError::SlotError { .. } => ErrorReport::new("slot_error", EXIT_DEPLOY, message),
+            ArchiveError::ScriptError { filename, .. } => {
+                ErrorReport::new("script_error", EXIT_SCRIPT, message).with_filename(filename)
+            }
+            ArchiveError::ComponentError { .. } => {
+                ErrorReport::new("component_error", EXIT_COMPONENT, message)
+            }
+            ArchiveError::Cancelled => ErrorReport::new("cancelled", EXIT_CANCELLED, message),
+        }
+    }
+}
+
+impl From<&HttpError> for ErrorReport {
+    fn from(err: &HttpError) -> Self {
+        match err {
+            HttpError::RequestError { .. } => {
+                ErrorReport::new("network_error", EXIT_NETWORK, err.to_string())
+            }
+            HttpError::FormatError { .. } => {
+                ErrorReport::new("server_response", EXIT_SERVER_RESPONSE, err.to_string())
+            }
+            HttpError::TlsError { .. } => ErrorReport::new("tls_error", EXIT_TLS, err.to_string()),
+            HttpError::StatusError { status: 401 | 403 } => {
+                ErrorReport::new("auth_error", EXIT_AUTH, err.to_string())
+            }
+            HttpError::StatusError { .. } => {
+                ErrorReport::new("server_response", EXIT_SERVER_RESPONSE, err.to_string())
+            }
+        }
+    }
+}
+
+impl From<&ConfigError> for ErrorReport {
+    fn from(err: &ConfigError) -> Self {
+        match err {
+            ConfigError::IOError { .. } => {
+                ErrorReport::new("config_io", EXIT_CONFIG, err.to_string())
+            }
+            ConfigError::ConfigParseError { .. } => {
+                ErrorReport::new("config_parse", EXIT_CONFIG, err.to_string())
+            }
+            ConfigError::InvalidField { field, .. } => {
+                ErrorReport::new("config_invalid", EXIT_CONFIG, err.to_string()).with_context(field)
+            }
+        }
+    }
+}
+
+// used for the install history of the daemon, which has no exit code of its own
+impl From<&DaemonError> for ErrorReport {
+    fn from(err: &DaemonError) -> Self {
+        match err {
+            DaemonError::HttpError { source } => ErrorReport::from(source),
+            DaemonError::ArchiveError { source } => ErrorReport::from(source),
+            DaemonError::StagingError { source } => match source {
+                StagingError::HttpError { source } => ErrorReport::from(source),
+                StagingError::ArchiveError { source } => ErrorReport::from(source),
+                _ => ErrorReport::new("staging_error", EXIT_DEPLOY, err.to_string()),
+            },
+            DaemonError::IOError { context, .. } => {
+                ErrorReport::new("source_error", EXIT_SOURCE, err.to_string()).with_context(context)
+            }
+            DaemonError::StateError { path, .. } => {
+                ErrorReport::new("state_error", EXIT_DEPLOY, err.to_string()).with_filename(path)
+            }
+            DaemonError::ResponseError { .. } => {
+                ErrorReport::new("server_response", EXIT_SERVER_RESPONSE, err.to_string())
+            }
+            DaemonError::RequestError { .. } => {
+                ErrorReport::new("request_error", EXIT_USAGE, err.to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn archive_errors() {
+        let report = ErrorReport::from(&ArchiveError::ChecksumMismatchError {
+            filename: String::from("rootfs.img"),
+        });
+        assert_eq!(report.exit_code, EXIT_ARCHIVE_CORRUPT);
+        assert!(!report.retryable);
+        assert_eq!(report.filename.as_deref(), Some("rootfs.img"));
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"kind":"checksum_mismatch","exit_code":20,"retryable":false,"message":"checksum: mismatch error in file rootfs.img","filename":"rootfs.img"}"#
+        );
+
+        let report = ErrorReport::from(&ArchiveError::FormatError {
+            offset: 110,
+            reason: String::from("magic"),
+        });
+        assert_eq!(report.offset, Some(110));
+        assert_eq!(
+            ErrorReport::from(&ArchiveError::Cancelled).exit_code,
+            EXIT_CANCELLED
+        );
+    }
+
+    #[test]
+    fn network_errors_are_retryable() {
+        let err = ArchiveError::IOError {
+            source: io::Error::new(io::ErrorKind::NotFound, "no such device"),
+            context: String::from("opening /dev/mmcblk0p3"),
+        };
+        let report = ErrorReport::from(&err);
+        assert_eq!(report.kind, "io_error");
+        assert!(!report.retryable);
+        assert_eq!(report.context.as_deref(), Some("opening /dev/mmcblk0p3"));
+
+        let http_err = HttpError::FormatError {
+            reason: String::from("no content length"),
+        };
+        let report = ErrorReport::from(&http_err);
+        assert_eq!(report.exit_code, EXIT_SERVER_RESPONSE);
+        assert!(report.retryable);
+    }
+}
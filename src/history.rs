@@ -0,0 +1,279 @@
+//! An append-only log of every deploy attempt, kept in the data dir. Each line is a json
+//! object, so the log can be read with standard tools and a line cut short by a power loss only
+//! loses that entry.
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use log::*;
+use reqwest::blocking::Client;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::archive::Archive;
+use crate::config::ServerConfig;
+use crate::error_report::{ErrorReport, EXIT_CANCELLED};
+use crate::http_reader::HttpError;
+use crate::utils::unix_time;
+
+pub const HISTORY_FILENAME: &str = "history.jsonl";
+
+/// What happened to a payload during a deploy.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadOutcome {
+    Deployed,
+    // verified but not deployed, its component is up to date
+    Skipped,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PayloadResult {
+    pub filename: String,
+    pub outcome: PayloadOutcome,
+    pub bytes_written: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployState {
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// A single deploy attempt. The archive hash and version are missing if the archive could not
+/// be opened.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub source: String,
+    pub archive_hash: Option<String>,
+    pub version: Option<String>,
+    // unix times in seconds
+    pub started: u64,
+    pub finished: u64,
+    pub payloads: Vec<PayloadResult>,
+    pub bytes_written: u64,
+    pub state: DeployState,
+    // the kind of error report, see error_report
+    pub error_kind: Option<String>,
+    pub error: Option<String>,
+}
+
+impl HistoryEntry {
+    /// Starts an entry for a deploy from source, it's failed until finished.
+    pub fn new(source: &str) -> HistoryEntry {
+        HistoryEntry {
+            source: source.to_owned(),
+            archive_hash: None,
+            version: None,
+            started: unix_time(),
+            finished: 0,
+            payloads: Vec::new(),
+            bytes_written: 0,
+            state: DeployState::Failed,
+            error_kind: None,
+            error: None,
+        }
+    }
+
+    pub fn set_archive<R: io::Read>(&mut self, archive: &Archive<R>) {
+        self.archive_hash = Some(archive.archive_hash().to_owned());
+        self.version = archive.manifest().version.clone();
+    }
+
+    pub fn set_payloads(&mut self, payloads: Vec<PayloadResult>) {
+        self.bytes_written = payloads.iter().map(|result| result.bytes_written).sum();
+        self.payloads = payloads;
+    }
+
+    /// Records the outcome of the deploy, a cancelled deploy is not counted as failed.
+    pub fn finish(&mut self, error: Option<&ErrorReport>) {
+        self.finished = unix_time();
+        self.state = match error {
+            None => DeployState::Succeeded,
+            Some(report) if report.exit_code == EXIT_CANCELLED => DeployState::Cancelled,
+            Some(_) => DeployState::Failed,
+        };
+        self.error_kind = error.map(|report| report.kind.to_owned());
+        self.error = error.map(|report| report.message.clone());
+    }
+}
+
+/// Appends an entry to the history, the entry is synced before returning.
+pub fn append(path: &Path, entry: &HistoryEntry) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)?;
+
+    // a line cut short by a power loss is ended, so it doesn't swallow this entry
+    let mut line = String::new();
+    if file.seek(SeekFrom::End(0))? > 0 {
+        let mut last = [0u8];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            line.push('\n');
+        }
+    }
+    line.push_str(&serde_json::to_string(entry)?);
+    line.push('\n');
+    file.write_all(line.as_bytes())?;
+    file.sync_data()
+}
+
+/// Reads every entry, oldest first. A missing history has no entries, and lines which can't be
+/// parsed are skipped.
+pub fn read(path: &Path) -> io::Result<Vec<HistoryEntry>> {
+    let buf = match fs::read_to_string(path) {
+        Ok(buf) => buf,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    Ok(buf
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .filter_map(|(index, line)| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                warn!("skipping history line {}: {}", index + 1, err);
+                None
+            }
+        })
+        .collect())
+}
+
+/// The url which reports are sent to, if the server has one, resolved against the server url.
+pub fn report_url(server: &ServerConfig) -> Result<Option<String>, HttpError> {
+    let report_url = match &server.report_url {
+        Some(report_url) => report_url,
+        None => return Ok(None),
+    };
+    Url::parse(&server.url)
+        .and_then(|base| base.join(report_url))
+        .map(|url| Some(String::from(url)))
+        .map_err(|err| HttpError::FormatError {
+            reason: format!("invalid report url: {}, {}", report_url, err),
+        })
+}
+
+/// Sends an entry to the update server, as a json POST.
+pub fn post_report(
+    client: &Client,
+    url: &str,
+    headers: &BTreeMap<String, String>,
+    entry: &HistoryEntry,
+) -> Result<(), HttpError> {
+    debug!("posting install report to {}", url);
+    let mut req = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_string(entry).unwrap());
+    for (name, value) in headers {
+        req = req.header(name.as_str(), value.as_str());
+    }
+    let resp = req.send()?;
+    if !resp.status().is_success() {
+        return Err(HttpError::StatusError {
+            status: resp.status().as_u16(),
+        });
+    }
+    Ok(())
+}
+
+/// Formats a unix time as an RFC 3339 UTC time, e.g. 2021-06-01T12:00:00Z.
+pub fn format_time(secs: u64) -> String {
+    // converts days since the epoch to a civil date, from
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = secs / 86400 + 719_468;
+    let era = days / 146_097;
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::archive::ArchiveError;
+    use crate::test_utils::*;
+
+    #[test]
+    fn append_and_read() {
+        init_logging();
+        let path = make_tempfile_path();
+        assert!(read(&path).unwrap().is_empty());
+
+        let mut entry = HistoryEntry::new("http://updates.example.com/update.cpio");
+        entry.set_payloads(vec![
+            PayloadResult {
+                filename: String::from("rootfs.img"),
+                outcome: PayloadOutcome::Deployed,
+                bytes_written: 1024,
+            },
+            PayloadResult {
+                filename: String::from("fpga.bit"),
+                outcome: PayloadOutcome::Skipped,
+                bytes_written: 0,
+            },
+        ]);
+        entry.finish(None);
+        append(&path, &entry).unwrap();
+        assert_eq!(entry.bytes_written, 1024);
+        assert_eq!(entry.state, DeployState::Succeeded);
+
+        let mut failed = HistoryEntry::new("/tmp/update.cpio");
+        failed.finish(Some(&ErrorReport::from(
+            &ArchiveError::ChecksumMismatchError {
+                filename: String::from("rootfs.img"),
+            },
+        )));
+        append(&path, &failed).unwrap();
+
+        // a line cut short by a power loss is skipped
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"source\":\"/tmp/up").unwrap();
+
+        append(&path, &entry).unwrap();
+
+        let entries = read(&path).unwrap();
+        assert_eq!(entries, vec![entry.clone(), failed, entry]);
+        assert_eq!(entries[1].state, DeployState::Failed);
+        assert_eq!(entries[1].error_kind.as_deref(), Some("checksum_mismatch"));
+
+        let mut cancelled = HistoryEntry::new("/tmp/update.cpio");
+        cancelled.finish(Some(&ErrorReport::from(&ArchiveError::Cancelled)));
+        assert_eq!(cancelled.state, DeployState::Cancelled);
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(format_time(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_time(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_time(1_000_000_000), "2001-09-09T01:46:40Z");
+    }
+}
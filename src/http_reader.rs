@@ -3,38 +3,80 @@ use std::str::FromStr;
 use std::time::Duration;
 use log::*;
 use reqwest::header::*;
-use reqwest::blocking::Client;
+use reqwest::blocking::{Client, ClientBuilder, RequestBuilder, Response};
+use reqwest::{Proxy, StatusCode, Url};
 use thiserror::Error;
 
+use crate::cancel::CancelToken;
+use crate::config::{HttpConfig, TlsConfig};
+use crate::throttle::RateLimiter;
+use crate::tls;
+
 #[derive(Error, Debug)]
 pub enum HttpError {
     #[error("http: request error, cause: {source}")]
-    RequestError {
-        #[from]
-        source: reqwest::Error,
-    },
+    RequestError { source: reqwest::Error },
 
     #[error("http: unexpected response format, cause: {reason}")]
     FormatError { reason: String },
+
+    #[error("http: tls error, cause: {reason}")]
+    TlsError { reason: String },
+
+    #[error("http: server returned status {status}")]
+    StatusError { status: u16 },
+}
+
+// a rejected server certificate, e.g. one which isn't pinned, fails the request rather than
+// the client, so it's found in the error source chain
+fn tls_error_reason(err: &reqwest::Error) -> Option<String> {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(err);
+    while let Some(err) = source {
+        // io errors don't return their inner error as the source
+        let inner = err
+            .downcast_ref::<io::Error>()
+            .and_then(|err| err.get_ref())
+            .map(|inner| inner as &(dyn std::error::Error + 'static));
+        if let Some(err) = inner.unwrap_or(err).downcast_ref::<rustls::Error>() {
+            return Some(err.to_string());
+        }
+        source = inner.or_else(|| err.source());
+    }
+    None
 }
 
-const CHUNK_SIZE: u64 = 1024;
+impl From<reqwest::Error> for HttpError {
+    fn from(source: reqwest::Error) -> Self {
+        match tls_error_reason(&source) {
+            Some(reason) => HttpError::TlsError { reason },
+            None => HttpError::RequestError { source },
+        }
+    }
+}
+
+const DEFAULT_CHUNK_SIZE: u64 = 1024;
+
+// failed requests are retried this many times before moving to the next mirror
+const DEFAULT_ATTEMPTS: u32 = 3;
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
 
 struct RangeHeaderIterator {
     byte_pos: u64,
     content_length: u64,
+    chunk_size: u64,
 }
 
+// yields the range header, and the number of bytes in the range
 impl Iterator for RangeHeaderIterator {
-    type Item = String;
+    type Item = (String, u64);
     fn next(&mut self) -> Option<Self::Item> {
         let bytes_remaining = self.content_length - self.byte_pos;
         if bytes_remaining > 0 {
-            let chunk = std::cmp::min(CHUNK_SIZE, bytes_remaining);
+            let chunk = std::cmp::min(self.chunk_size, bytes_remaining);
             let range = format!("bytes={}-{}", self.byte_pos, self.byte_pos + chunk - 1);
 
             self.byte_pos += chunk;
-            return Some(range);
+            return Some((range, chunk));
         }
         None
     }
@@ -78,20 +120,35 @@ impl ChunkBuffer {
     }
 }
 
-pub struct HttpReader {
-    url: String,
-    client: Client,
-    ranges: RangeHeaderIterator,
-    buf: ChunkBuffer,
+/// Credentials added to each request. A url replaces the reader's url, e.g. a pre-signed url,
+/// headers are sent in addition to the reader's own headers.
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    pub url: Option<String>,
+    pub headers: Vec<(String, String)>,
 }
 
-impl HttpReader {
-    pub fn new(url: &str, timeout: Duration) -> Result<HttpReader, HttpError> {
-        let client_builder = Client::builder();
-        let client = client_builder.timeout(timeout).build()?;
+/// Supplies credentials when the reader is built, and again whenever the server rejects them
+/// with 401 or 403, e.g. when a token or signed url expires part way through a download.
+pub trait CredentialProvider: Send {
+    fn refresh(&mut self) -> Result<Credentials, HttpError>;
+}
 
-        // request headers
-        let resp = client.head(url).send()?;
+impl<F: FnMut() -> Result<Credentials, HttpError> + Send> CredentialProvider for F {
+    fn refresh(&mut self) -> Result<Credentials, HttpError> {
+        self()
+    }
+}
+
+// what every mirror must agree on, taken from the first successful HEAD request
+#[derive(Debug, PartialEq)]
+struct ContentInfo {
+    content_length: u64,
+    etag: Option<String>,
+}
+
+impl ContentInfo {
+    fn from_response(resp: &Response) -> Result<ContentInfo, HttpError> {
         let content_length = resp
             .headers()
             .get(CONTENT_LENGTH)
@@ -105,19 +162,397 @@ impl HttpReader {
                 reason: err.to_string(),
             }
         })?;
+        let etag = resp
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(String::from);
+        Ok(ContentInfo {
+            content_length,
+            etag,
+        })
+    }
+
+    // etags are only compared if both servers return one
+    fn matches(&self, other: &ContentInfo) -> bool {
+        self.content_length == other.content_length
+            && match (&self.etag, &other.etag) {
+                (Some(etag), Some(other)) => etag == other,
+                _ => true,
+            }
+    }
+}
+
+// hosts which match an entry, or are a subdomain of it, are not proxied
+fn is_no_proxy(host: &str, no_proxy: &[String]) -> bool {
+    no_proxy.iter().any(|entry| {
+        let entry = entry.trim_start_matches('.');
+        entry == "*" || host == entry || host.ends_with(&format!(".{}", entry))
+    })
+}
+
+/// Applies the proxy and connection pool settings to a client. The proxy from the config
+/// replaces any proxy set in the environment.
+pub fn configure(
+    mut builder: ClientBuilder,
+    http: &HttpConfig,
+) -> Result<ClientBuilder, HttpError> {
+    if let Some(proxy) = &http.proxy {
+        let proxy = Url::parse(proxy).map_err(|err| HttpError::FormatError {
+            reason: format!("invalid proxy url: {}, {}", proxy, err),
+        })?;
+        let no_proxy = http.no_proxy.clone();
+        builder = builder.proxy(Proxy::custom(move |url| match url.host_str() {
+            Some(host) if is_no_proxy(host, &no_proxy) => None,
+            _ => Some(proxy.clone()),
+        }));
+    }
+    if let Some(timeout) = http.pool_idle_timeout {
+        builder = builder.pool_idle_timeout(Duration::from_secs(timeout));
+    }
+    if let Some(max_idle) = http.pool_max_idle {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(keepalive) = http.tcp_keepalive {
+        builder = builder.tcp_keepalive(Duration::from_secs(keepalive));
+    }
+    Ok(builder)
+}
+
+pub struct HttpReaderBuilder {
+    urls: Vec<String>,
+    timeout: Duration,
+    tls: Option<TlsConfig>,
+    http: Option<HttpConfig>,
+    headers: Vec<(String, String)>,
+    credentials: Option<Box<dyn CredentialProvider>>,
+    attempts: u32,
+    retry_delay: Duration,
+}
 
-        Ok(HttpReader {
-            url: String::from(url),
+impl HttpReaderBuilder {
+    /// The client uses the CA bundle and client identity from the tls config. With pinned
+    /// certs, the server certificate of every connection is checked against the pins.
+    pub fn tls(mut self, tls: &TlsConfig) -> HttpReaderBuilder {
+        self.tls = Some(tls.clone());
+        self
+    }
+
+    /// The client uses the proxy and connection settings, and reads are limited to the rate
+    /// limit, from the http config.
+    pub fn http_config(mut self, http: &HttpConfig) -> HttpReaderBuilder {
+        self.http = Some(http.clone());
+        self
+    }
+
+    /// Adds a header to every request.
+    pub fn header(mut self, name: &str, value: &str) -> HttpReaderBuilder {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn bearer_token(self, token: &str) -> HttpReaderBuilder {
+        self.header(AUTHORIZATION.as_str(), &format!("Bearer {}", token))
+    }
+
+    pub fn credentials<P: CredentialProvider + 'static>(
+        mut self,
+        provider: P,
+    ) -> HttpReaderBuilder {
+        self.credentials = Some(Box::new(provider));
+        self
+    }
+
+    /// Adds a mirror of the url, mirrors are used in the order they're added once requests to
+    /// the previous url have repeatedly failed.
+    pub fn mirror(mut self, url: &str) -> HttpReaderBuilder {
+        self.urls.push(url.to_owned());
+        self
+    }
+
+    /// Requests which fail with a network error, a 5xx, 408 or 429 are attempted this many
+    /// times, with the delay in between, before moving on to the next mirror. Other failed
+    /// responses move on to the next mirror straight away.
+    pub fn retry(mut self, attempts: u32, delay: Duration) -> HttpReaderBuilder {
+        self.attempts = u32::max(attempts, 1);
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Builds the client, and requests the content length of the url.
+    pub fn build(self) -> Result<HttpReader, HttpError> {
+        let mut client_builder = Client::builder().timeout(self.timeout);
+        if let Some(tls) = &self.tls {
+            for url in &self.urls {
+                tls::check_url(url, tls)?;
+            }
+            client_builder = tls::configure(client_builder, tls)?;
+        }
+        if let Some(http) = &self.http {
+            client_builder = configure(client_builder, http)?;
+        }
+        let client = client_builder.build()?;
+        let chunk_size = self
+            .http
+            .as_ref()
+            .and_then(|http| http.chunk_size)
+            .unwrap_or(DEFAULT_CHUNK_SIZE);
+
+        let mut reader = HttpReader {
+            urls: self.urls,
+            mirror: 0,
             client,
+            tls: self.tls,
+            headers: self.headers,
+            credentials: Credentials::default(),
+            provider: self.credentials,
+            content: None,
+            attempts: self.attempts,
+            retry_delay: self.retry_delay,
             ranges: RangeHeaderIterator {
                 byte_pos: 0,
-                content_length,
+                content_length: 0,
+                chunk_size,
             },
-            buf: ChunkBuffer::new(CHUNK_SIZE as usize),
-        })
+            buf: ChunkBuffer::new(chunk_size as usize),
+            cancel: CancelToken::new(),
+            rate_limit: self
+                .http
+                .as_ref()
+                .and_then(|http| http.rate_limit)
+                .map(RateLimiter::new),
+        };
+
+        reader.refresh_credentials()?;
+
+        // request headers
+        let content = reader.send_with_retry(
+            |client, url| client.head(url),
+            |resp| ContentInfo::from_response(&resp),
+        )?;
+        reader.ranges.content_length = content.content_length;
+        reader.content = Some(content);
+        Ok(reader)
     }
 }
 
+pub struct HttpReader {
+    // the url followed by its mirrors, mirror is the index of the url in use
+    urls: Vec<String>,
+    mirror: usize,
+    client: Client,
+    tls: Option<TlsConfig>,
+    headers: Vec<(String, String)>,
+    credentials: Credentials,
+    provider: Option<Box<dyn CredentialProvider>>,
+    content: Option<ContentInfo>,
+    attempts: u32,
+    retry_delay: Duration,
+    ranges: RangeHeaderIterator,
+    buf: ChunkBuffer,
+    cancel: CancelToken,
+    rate_limit: Option<RateLimiter>,
+}
+
+impl HttpReader {
+    pub fn new(url: &str, timeout: Duration) -> Result<HttpReader, HttpError> {
+        HttpReader::builder(url, timeout).build()
+    }
+
+    /// As new, with the client configured by the tls config, see HttpReaderBuilder::tls.
+    pub fn with_tls(
+        url: &str,
+        timeout: Duration,
+        tls: &TlsConfig,
+    ) -> Result<HttpReader, HttpError> {
+        HttpReader::builder(url, timeout).tls(tls).build()
+    }
+
+    pub fn builder(url: &str, timeout: Duration) -> HttpReaderBuilder {
+        HttpReaderBuilder {
+            urls: vec![url.to_owned()],
+            timeout,
+            tls: None,
+            http: None,
+            headers: Vec::new(),
+            credentials: None,
+            attempts: DEFAULT_ATTEMPTS,
+            retry_delay: DEFAULT_RETRY_DELAY,
+        }
+    }
+
+    // credentials may replace the url, but not the mirrors
+    fn current_url(&self) -> &str {
+        match &self.credentials.url {
+            Some(url) if self.mirror == 0 => url,
+            _ => &self.urls[self.mirror],
+        }
+    }
+
+    // moves to the next mirror which serves the same content, returns false if there are none
+    fn next_mirror(&mut self) -> bool {
+        while self.mirror + 1 < self.urls.len() {
+            self.mirror += 1;
+            let url = self.urls[self.mirror].clone();
+            let content = self
+                .send(|client, url| client.head(url))
+                .and_then(|resp| ContentInfo::from_response(&resp));
+            match (content, &self.content) {
+                (Ok(content), Some(expected)) if !content.matches(expected) => {
+                    warn!(
+                        "mirror {} does not match, {:?} != {:?}",
+                        url, content, expected
+                    )
+                }
+                (Ok(_), _) => {
+                    info!("switched to mirror {}", url);
+                    return true;
+                }
+                (Err(err), _) => warn!("mirror {} failed, {}", url, err),
+            }
+        }
+        false
+    }
+
+    // sends a request and handles the response, retrying failures of either and then moving on
+    // to the next mirror
+    fn send_with_retry<T, F, H>(&mut self, request: F, handle: H) -> Result<T, HttpError>
+    where
+        F: Fn(&Client, &str) -> RequestBuilder,
+        H: Fn(Response) -> Result<T, HttpError>,
+    {
+        loop {
+            let mut attempt = 1;
+            let err = loop {
+                match self.send(&request).and_then(&handle) {
+                    Ok(value) => return Ok(value),
+                    Err(err) if !is_mirror_failure(&err) => return Err(err),
+                    Err(err)
+                        if !is_retryable(&err)
+                            || attempt >= self.attempts
+                            || self.cancel.is_cancelled() =>
+                    {
+                        break err
+                    }
+                    Err(err) => {
+                        debug!(
+                            "request to {} failed, retrying: {}",
+                            self.current_url(),
+                            err
+                        );
+                        attempt += 1;
+                        std::thread::sleep(self.retry_delay);
+                    }
+                }
+            };
+            warn!("request to {} failed: {}", self.current_url(), err);
+            if self.cancel.is_cancelled() || !self.next_mirror() {
+                return Err(err);
+            }
+        }
+    }
+
+    // a url from the credential provider must be https when certs are pinned, as the reader's
+    // own urls must be
+    fn refresh_credentials(&mut self) -> Result<(), HttpError> {
+        if let Some(provider) = self.provider.as_mut() {
+            let credentials = provider.refresh()?;
+            if let (Some(tls), Some(url)) = (&self.tls, &credentials.url) {
+                tls::check_url(url, tls)?;
+            }
+            self.credentials = credentials;
+        }
+        Ok(())
+    }
+
+    // sends a request with the current credentials, which are refreshed once if rejected
+    fn send<F: Fn(&Client, &str) -> RequestBuilder>(
+        &mut self,
+        request: F,
+    ) -> Result<Response, HttpError> {
+        let mut refreshed = false;
+        loop {
+            let mut req = request(&self.client, self.current_url());
+            for (name, value) in self.headers.iter().chain(&self.credentials.headers) {
+                req = req.header(name.as_str(), value.as_str());
+            }
+            let resp = req.send()?;
+
+            let status = resp.status();
+            let rejected = status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN;
+            match self.provider {
+                Some(_) if rejected && !refreshed => {
+                    debug!("request rejected with {}, refreshing credentials", status);
+                    self.refresh_credentials()?;
+                    refreshed = true;
+                }
+                _ if !status.is_success() => {
+                    return Err(HttpError::StatusError {
+                        status: status.as_u16(),
+                    })
+                }
+                _ => return Ok(resp),
+            }
+        }
+    }
+
+    /// The token is checked before each range request, see cancel::is_cancelled_error.
+    pub fn set_cancel_token(&mut self, cancel: CancelToken) {
+        self.cancel = cancel;
+    }
+
+    pub fn content_length(&self) -> u64 {
+        self.ranges.content_length
+    }
+
+    /// Continues reading from offset, e.g. to resume an interrupted download.
+    pub fn resume_from(&mut self, offset: u64) {
+        self.ranges.byte_pos = u64::min(offset, self.ranges.content_length);
+        self.buf.write_bytes(&[]);
+    }
+
+    /// Limits the download rate, in bytes per second.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: u64) {
+        self.rate_limit = Some(RateLimiter::new(bytes_per_sec));
+    }
+}
+
+// failures which may succeed on another attempt, e.g. a dropped connection or an overloaded
+// server
+fn is_retryable(err: &HttpError) -> bool {
+    match err {
+        HttpError::RequestError { .. } => true,
+        HttpError::StatusError { status } => *status >= 500 || *status == 408 || *status == 429,
+        _ => false,
+    }
+}
+
+// failures which may succeed from another mirror, e.g. one which doesn't have the file, or
+// ignores range requests
+fn is_mirror_failure(err: &HttpError) -> bool {
+    is_retryable(err)
+        || matches!(
+            err,
+            HttpError::StatusError { .. } | HttpError::FormatError { .. }
+        )
+}
+
+// reads the body of a range request, which must be exactly the requested range
+fn read_range(resp: Response, len: u64) -> Result<Vec<u8>, HttpError> {
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(HttpError::FormatError {
+            reason: format!("expected a partial response, got {}", resp.status()),
+        });
+    }
+    let body = resp.bytes()?;
+    if body.len() as u64 != len {
+        return Err(HttpError::FormatError {
+            reason: format!("expected a {} byte range, got {} bytes", len, body.len()),
+        });
+    }
+    Ok(body.to_vec())
+}
+
 impl io::Read for HttpReader {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         // DONE 1. read entire body into buffer, save to tmp
@@ -125,7 +560,7 @@ impl io::Read for HttpReader {
         // DONE 2. implement range requests, limiting buffer size
         // DONE 3. implement more complex testing
         //      - latency - delay in-between buffer fetch (infinite)
-        // 4. handle X retries on failed buffer fetch before abort
+        // DONE 4. handle X retries on failed buffer fetch before abort
         //      and configurable client timeouts
         // 5. possibly execute requests asynchronously,
         // 6. if doing async/threaded requests, make multiple range requests simultaneously
@@ -137,24 +572,30 @@ impl io::Read for HttpReader {
 
         // otherwise, read the next range and request it
         match self.ranges.next() {
-            Some(range) => {
+            Some((range, len)) => {
+                self.cancel.check_io()?;
                 debug!("requesting next range: {}", range);
 
-                let req = self
-                    .client
-                    .get(&self.url)
-                    .header(RANGE, range)
-                    .send()
-                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+                // a retried request reuses the same range, so the position is kept, and the body
+                // is read as part of the request so that a failure part way through is retried
+                let body = self
+                    .send_with_retry(
+                        |client, url| client.get(url).header(RANGE, range.as_str()),
+                        |resp| read_range(resp, len),
+                    )
+                    .map_err(io::Error::other)?;
 
                 // copy the body to the chunk buffer
-                self.buf.write_bytes(&req.bytes().unwrap());
+                self.buf.write_bytes(&body);
+                if let Some(rate_limit) = self.rate_limit.as_mut() {
+                    rate_limit.consume(self.buf.len() as u64);
+                }
                 // copy the chunk buffer to the output
-                return Ok(self.buf.read_bytes(buf));
+                Ok(self.buf.read_bytes(buf))
             }
             None => {
                 // all ranges read, return EOF
-                return Ok(0);
+                Ok(0)
             }
         }
     }
@@ -193,9 +634,309 @@ mod test {
         let err = HttpReader::new(&url, Duration::from_secs(1))
             .err()
             .expect("expected reader to time out!");
-        match err {
-            HttpError::RequestError { source } => { assert!(source.is_timeout()) },
-            _ => {}
+        if let HttpError::RequestError { source } = err {
+            assert!(source.is_timeout())
         }
     }
+
+    #[test]
+    fn test_resume() {
+        init_logging();
+        let server = create_test_server(TestServerArgs::new("http-roots/test1"));
+
+        let url = format!("http://127.0.0.1:{}/test-file", server.port);
+        let mut http_reader = HttpReader::new(&url, Duration::from_secs(1)).unwrap();
+        http_reader.resume_from(1000);
+        let mut buf = Vec::new();
+        http_reader.read_to_end(&mut buf).unwrap();
+
+        let expected = std::fs::read(test_path("http-roots/test1/test-file")).unwrap();
+        assert_eq!(http_reader.content_length(), 1024);
+        assert_eq!(buf, &expected[1000..]);
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        init_logging();
+        let server = create_test_server(TestServerArgs::new("http-roots/test1"));
+        server.set_token("secret");
+
+        let url = format!("http://127.0.0.1:{}/test-file", server.port);
+        let err = HttpReader::new(&url, Duration::from_secs(1)).err().unwrap();
+        assert!(matches!(err, HttpError::StatusError { status: 401 }));
+
+        let mut http_reader = HttpReader::builder(&url, Duration::from_secs(1))
+            .bearer_token("secret")
+            .header("X-Device-Id", "1234")
+            .build()
+            .unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(http_reader.read_to_end(&mut buf).unwrap(), 1024);
+    }
+
+    #[test]
+    fn test_refresh_credentials() {
+        init_logging();
+        let expected = crate::mtd::test::rand_image(5000);
+        let (server, url) = serve_file(&expected);
+        server.set_token("1");
+
+        // signed urls, which expire part way through the download
+        let signed_url = url.clone();
+        let mut generation = 0;
+        let mut http_reader = HttpReader::builder(&url, Duration::from_secs(1))
+            .credentials(move || {
+                generation += 1;
+                Ok(Credentials {
+                    url: Some(format!("{}?token={}", signed_url, generation)),
+                    headers: Vec::new(),
+                })
+            })
+            .build()
+            .unwrap();
+
+        let mut buf = vec![0u8; 3000];
+        http_reader.read_exact(&mut buf).unwrap();
+        server.set_token("2");
+        http_reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+
+        // a refresh which is still rejected fails the read
+        server.set_token("other");
+        http_reader.resume_from(0);
+        let err = http_reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(matches!(
+            err.get_ref().unwrap().downcast_ref::<HttpError>(),
+            Some(HttpError::StatusError { status: 401 })
+        ));
+    }
+
+    fn serve_file(data: &[u8]) -> (TestServer, String) {
+        let root = make_tempfile_path();
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("test-file"), data).unwrap();
+        let server = create_test_server(TestServerArgs::new(root.to_str().unwrap()));
+        let url = format!("http://127.0.0.1:{}/test-file", server.port);
+        (server, url)
+    }
+
+    #[test]
+    fn test_mirror_failover() {
+        init_logging();
+        let expected = crate::mtd::test::rand_image(5000);
+        let (server, url) = serve_file(&expected);
+        let (_mirror, mirror_url) = serve_file(&expected);
+
+        let mut http_reader = HttpReader::builder(&url, Duration::from_secs(1))
+            .mirror("http://127.0.0.1:1/test-file")
+            .mirror(&mirror_url)
+            .retry(2, Duration::from_millis(10))
+            .build()
+            .unwrap();
+        let mut buf = vec![0u8; 3000];
+        http_reader.read_exact(&mut buf).unwrap();
+
+        // the rest of the file is read from the mirror, skipping the dead one
+        drop(server);
+        http_reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn test_mirror_mismatch() {
+        init_logging();
+        let (server, url) = serve_file(&crate::mtd::test::rand_image(5000));
+        let (_mirror, mirror_url) = serve_file(&crate::mtd::test::rand_image(4000));
+
+        let mut http_reader = HttpReader::builder(&url, Duration::from_secs(1))
+            .mirror(&mirror_url)
+            .retry(1, Duration::from_millis(10))
+            .build()
+            .unwrap();
+        let mut buf = vec![0u8; 3000];
+        http_reader.read_exact(&mut buf).unwrap();
+
+        drop(server);
+        let err = http_reader.read_to_end(&mut buf).unwrap_err();
+        assert!(matches!(
+            err.get_ref().unwrap().downcast_ref::<HttpError>(),
+            Some(HttpError::RequestError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_mirror_body_failure() {
+        init_logging();
+        let expected = crate::mtd::test::rand_image(5000);
+        let (server, url) = serve_file(&expected);
+        let (_mirror, mirror_url) = serve_file(&expected);
+
+        let mut http_reader = HttpReader::builder(&url, Duration::from_secs(1))
+            .mirror(&mirror_url)
+            .retry(2, Duration::from_millis(10))
+            .build()
+            .unwrap();
+        let mut buf = vec![0u8; 3000];
+        http_reader.read_exact(&mut buf).unwrap();
+
+        // the connection drops part way through each body, the range is requested again and
+        // then from the mirror
+        server.truncate_bodies();
+        let requests = server.request_count();
+        http_reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+        assert_eq!(server.request_count(), requests + 2);
+    }
+
+    #[test]
+    fn test_mirror_ignores_ranges() {
+        init_logging();
+        let expected = crate::mtd::test::rand_image(5000);
+        let (server, url) = serve_file(&expected);
+        let (_mirror, mirror_url) = serve_file(&expected);
+        server.ignore_ranges();
+
+        let mut http_reader = HttpReader::builder(&url, Duration::from_secs(1))
+            .mirror(&mirror_url)
+            .retry(2, Duration::from_millis(10))
+            .build()
+            .unwrap();
+        let mut buf = Vec::new();
+        http_reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+
+        // without a mirror the read fails, rather than overflowing the chunk buffer
+        let mut http_reader = HttpReader::new(&url, Duration::from_secs(1)).unwrap();
+        let err = http_reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(matches!(
+            err.get_ref().unwrap().downcast_ref::<HttpError>(),
+            Some(HttpError::FormatError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_mirror_not_found() {
+        init_logging();
+        let expected = crate::mtd::test::rand_image(2000);
+        let (server, url) = serve_file(&expected);
+        let (_mirror, mirror_url) = serve_file(&expected);
+
+        // a missing file isn't retried, the mirror is used straight away
+        let missing = url.replace("test-file", "missing-file");
+        let mut http_reader = HttpReader::builder(&missing, Duration::from_secs(1))
+            .mirror(&mirror_url)
+            .retry(3, Duration::from_secs(10))
+            .build()
+            .unwrap();
+        assert_eq!(server.request_count(), 1);
+        let mut buf = Vec::new();
+        http_reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+
+        let err = HttpReader::builder(&missing, Duration::from_secs(1))
+            .retry(3, Duration::from_secs(10))
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(err, HttpError::StatusError { status: 404 }));
+        assert_eq!(server.request_count(), 2);
+    }
+
+    #[test]
+    fn test_connection_reuse() {
+        init_logging();
+        let expected = crate::mtd::test::rand_image(5000);
+        let (server, url) = serve_file(&expected);
+
+        let mut http_reader = HttpReader::new(&url, Duration::from_secs(1)).unwrap();
+        let mut buf = Vec::new();
+        http_reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+        assert_eq!(server.connection_count(), 1);
+        assert_eq!(server.request_count(), 6);
+
+        let http = HttpConfig {
+            chunk_size: Some(4096),
+            ..Default::default()
+        };
+        let mut http_reader = HttpReader::builder(&url, Duration::from_secs(1))
+            .http_config(&http)
+            .build()
+            .unwrap();
+        let mut buf = Vec::new();
+        http_reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+        assert_eq!(server.request_count(), 6 + 3);
+    }
+
+    #[test]
+    fn test_proxy() {
+        init_logging();
+        let expected = crate::mtd::test::rand_image(2000);
+        let (server, url) = serve_file(&expected);
+        let (proxy, proxy_url) = serve_file(&expected);
+
+        let mut http = HttpConfig {
+            proxy: Some(proxy_url.replace("/test-file", "")),
+            ..Default::default()
+        };
+        let mut http_reader = HttpReader::builder(&url, Duration::from_secs(1))
+            .http_config(&http)
+            .build()
+            .unwrap();
+        let mut buf = Vec::new();
+        http_reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+        assert_eq!(server.request_count(), 0);
+        assert_eq!(proxy.request_count(), 3);
+
+        http.no_proxy = vec![String::from("127.0.0.1")];
+        let mut http_reader = HttpReader::builder(&url, Duration::from_secs(1))
+            .http_config(&http)
+            .build()
+            .unwrap();
+        http_reader.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(server.request_count(), 3);
+        assert_eq!(proxy.request_count(), 3);
+    }
+
+    #[test]
+    fn test_no_proxy() {
+        let no_proxy = vec![String::from("example.com"), String::from(".local")];
+        assert!(is_no_proxy("example.com", &no_proxy));
+        assert!(is_no_proxy("updates.example.com", &no_proxy));
+        assert!(is_no_proxy("device.local", &no_proxy));
+        assert!(!is_no_proxy("badexample.com", &no_proxy));
+        assert!(is_no_proxy("anything", &[String::from("*")]));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        init_logging();
+        let server = create_test_server(TestServerArgs::new("http-roots/test1"));
+
+        let url = format!("http://127.0.0.1:{}/test-file", server.port);
+        let mut http_reader = HttpReader::new(&url, Duration::from_secs(1)).unwrap();
+        // the 1024 byte file is twice the allowed burst
+        http_reader.set_rate_limit(512);
+
+        let start = std::time::Instant::now();
+        http_reader.read_to_end(&mut Vec::new()).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[test]
+    fn test_cancel() {
+        init_logging();
+        let server = create_test_server(TestServerArgs::new("http-roots/test1"));
+
+        let url = format!("http://127.0.0.1:{}/test-file", server.port);
+        let mut http_reader = HttpReader::new(&url, Duration::from_secs(1)).unwrap();
+        let cancel = CancelToken::new();
+        http_reader.set_cancel_token(cancel.clone());
+
+        cancel.cancel();
+        let err = http_reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(crate::cancel::is_cancelled_error(&err));
+    }
 }
@@ -1,14 +1,151 @@
-use serde_json;
 use serde::de;
+use serde_json;
+
+/// Converts jsonc to json, by blanking out comments and trailing commas. Comments may be line
+/// (//) or block (/* */) comments, anywhere outside of strings. Every byte other than a newline
+/// is replaced by a space, so positions in serde errors are the same as in the original text.
+pub fn strip_jsonc(s: &str) -> String {
+    let mut out = s.as_bytes().to_vec();
+    let mut pos = 0;
+    let mut in_string = false;
+    // the last comma after a value, which is blanked out if it's trailing
+    let mut comma = None;
+    let mut last = b' ';
+
+    while pos < out.len() {
+        let byte = out[pos];
+        if in_string {
+            match byte {
+                b'\\' => pos += 1,
+                b'"' => in_string = false,
+                _ => (),
+            }
+            pos += 1;
+            continue;
+        }
 
-pub fn parse_jsonc<'a, T: de::DeserializeOwned>(s: &str) -> serde_json::Result<T> {
-    // TODO: would be nice to also handle /* block comments */
-    let mut stripped = String::new();
-    for line in s.lines() {
-        if line.trim_start().starts_with("//") {
+        let comment_end = match (byte, out.get(pos + 1)) {
+            (b'/', Some(b'/')) => Some(
+                out[pos..]
+                    .iter()
+                    .position(|&byte| byte == b'\n')
+                    .map_or(out.len(), |len| pos + len),
+            ),
+            // an unterminated block comment is left in place, for serde to report
+            (b'/', Some(b'*')) => out[pos + 2..]
+                .windows(2)
+                .position(|window| window == b"*/")
+                .map(|len| pos + 2 + len + 2),
+            _ => None,
+        };
+        if let Some(end) = comment_end {
+            for byte in &mut out[pos..end] {
+                if *byte != b'\n' {
+                    *byte = b' ';
+                }
+            }
+            pos = end;
             continue;
         }
-        stripped.push_str(line);
+
+        match byte {
+            b',' if !matches!(last, b'[' | b'{' | b',' | b':') => comma = Some(pos),
+            b']' | b'}' => {
+                if let Some(comma) = comma.take() {
+                    out[comma] = b' ';
+                }
+            }
+            byte if byte.is_ascii_whitespace() => (),
+            b'"' => {
+                in_string = true;
+                comma = None;
+            }
+            _ => comma = None,
+        }
+        if !byte.is_ascii_whitespace() {
+            last = byte;
+        }
+        pos += 1;
+    }
+    // only whole comments, made of whole characters, are replaced
+    String::from_utf8(out).expect("stripped jsonc is valid utf8")
+}
+
+pub fn parse_jsonc<T: de::DeserializeOwned>(s: &str) -> serde_json::Result<T> {
+    serde_json::from_str(&strip_jsonc(s))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn comments() {
+        let value: Value = parse_jsonc(
+            r#"{
+                // a line comment
+                "url": "http://host/path", // a trailing comment
+                /* a block comment */ "count": /* inline */ 2,
+                /*
+                 * a block comment over several lines, with "quotes" and // slashes
+                 */
+                "pattern": "/* not a comment */ // nor this",
+                "escaped": "a \"quoted // string\" \\", // ünïcode comment
+                "last": true
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "url": "http://host/path",
+                "count": 2,
+                "pattern": "/* not a comment */ // nor this",
+                "escaped": "a \"quoted // string\" \\",
+                "last": true
+            })
+        );
+    }
+
+    #[test]
+    fn trailing_commas() {
+        let value: Value = parse_jsonc(
+            r#"{
+                "list": [1, 2, 3,],
+                "nested": { "a": [], "b": {}, },
+                "commented": [
+                    "x", // the last entry
+                ],
+                "string": ",]",
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "list": [1, 2, 3],
+                "nested": { "a": [], "b": {} },
+                "commented": ["x"],
+                "string": ",]"
+            })
+        );
+
+        // only trailing commas are allowed, not empty entries
+        assert!(parse_jsonc::<Value>("[1,,]").is_err());
+        assert!(parse_jsonc::<Value>("[,]").is_err());
+    }
+
+    #[test]
+    fn error_positions() {
+        let err = parse_jsonc::<Value>(
+            "{\n    /* a\n       comment */ \"a\": 1, // note\n    \"b\": nope\n}",
+        )
+        .unwrap_err();
+        // serde stops at the first byte which can't be part of null
+        assert_eq!((err.line(), err.column()), (4, 11));
+
+        let err = parse_jsonc::<Value>("{\n    \"a\": 1 /* unterminated\n}").unwrap_err();
+        assert_eq!(err.line(), 2);
     }
-    serde_json::from_str(stripped.as_str())
-}
\ No newline at end of file
+}
@@ -2,6 +2,13 @@
 #[allow(dead_code)]
 mod cpio;
 
+#[allow(dead_code)]
+mod tar;
+
+pub mod container;
+
+pub mod component;
+
 #[allow(dead_code)]
 pub mod archive;
 
@@ -17,20 +24,44 @@ pub mod manifest;
 #[cfg(test)]
 mod test_utils;
 
-#[cfg(test)]
-#[macro_use]
-extern crate lazy_static;
-
 #[cfg(test)]
 mod test_server;
 
 #[allow(dead_code)]
 pub mod http_reader;
 
-#[cfg(test)]
+#[allow(dead_code)]
 mod linux;
 
 #[allow(dead_code)]
 pub mod checksum;
 
-pub mod utils;
\ No newline at end of file
+pub mod delta;
+
+pub mod slot;
+
+pub mod sparse;
+
+pub mod script;
+
+pub mod mtd;
+
+pub mod emmc;
+
+pub mod daemon;
+
+pub mod control;
+
+pub mod cancel;
+
+pub mod throttle;
+
+pub mod staging;
+
+pub mod error_report;
+
+pub mod history;
+
+pub mod tls;
+
+pub mod utils;
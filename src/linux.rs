@@ -1,22 +1,161 @@
-use std::process::Child;
+use std::fs::File;
+use std::io;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_long, c_ulong};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::os::unix::io::AsRawFd;
 
 extern "C" {
-    // see "man 2 kill" for function details
-    fn kill(pid: i32, sig: i32) -> i32;
-}
+    // see "man 2 lseek" for function details
+    fn lseek(fd: i32, offset: i64, whence: i32) -> i64;
+
+    // see "man 2 ioctl" for function details
+    fn ioctl(fd: i32, request: c_ulong, ...) -> i32;
+
+    // see "man 2 setpriority" for function details
+    fn setpriority(which: i32, who: u32, prio: i32) -> i32;
 
-#[repr(i32)]
-pub enum Signal {
-    INT = 2,
-    _KILL = 9,
-    _TERM = 15,
+    // see "man 2 syscall", used for syscalls without a libc wrapper
+    fn syscall(number: c_long, ...) -> c_long;
+
+    // see "man 3 statvfs" for function details
+    fn statvfs(path: *const c_char, buf: *mut StatVfs) -> c_int;
 }
 
-pub fn signal(process: &Child, signal: Signal) {
-    unsafe {
-        let ret_code = kill(process.id() as i32, signal as i32);
-        if ret_code != 0 {
-            panic!("call to kill syscall failed with retcode: {}", ret_code);
+const SEEK_DATA: i32 = 3;
+const SEEK_HOLE: i32 = 4;
+const ENXIO: i32 = 6;
+const EINVAL: i32 = 22;
+
+fn seek(file: &File, offset: u64, whence: i32) -> io::Result<Option<u64>> {
+    let ret = unsafe { lseek(file.as_raw_fd(), offset as i64, whence) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        // ENXIO indicates that there is no more data (or hole) after the offset
+        if err.raw_os_error() == Some(ENXIO) {
+            return Ok(None);
         }
+        return Err(err);
+    }
+    Ok(Some(ret as u64))
+}
+
+/// Returns the regions of a sparse file which contain data, as (start, end) offsets. Files on
+/// filesystems which can't report holes are treated as a single data region.
+///
+/// Note that this moves the file offset.
+pub fn data_regions(file: &File) -> io::Result<Vec<(u64, u64)>> {
+    let len = file.metadata()?.len();
+    let mut regions = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let start = match seek(file, offset, SEEK_DATA) {
+            Ok(Some(start)) => start,
+            Ok(None) => break,
+            Err(err) if err.raw_os_error() == Some(EINVAL) && offset == 0 => {
+                return Ok(vec![(0, len)])
+            }
+            Err(err) => return Err(err),
+        };
+        let end = seek(file, start, SEEK_HOLE)?.unwrap_or(len);
+        regions.push((start, end));
+        offset = end;
+    }
+    Ok(regions)
+}
+
+// encodes an ioctl request number, see asm-generic/ioctl.h
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+const fn ioc(dir: u32, ioc_type: u8, nr: u8, size: usize) -> u32 {
+    (dir << 30) | ((size as u32) << 16) | ((ioc_type as u32) << 8) | nr as u32
+}
+
+pub const fn ior<T>(ioc_type: u8, nr: u8) -> u32 {
+    ioc(IOC_READ, ioc_type, nr, std::mem::size_of::<T>())
+}
+
+pub const fn iow<T>(ioc_type: u8, nr: u8) -> u32 {
+    ioc(IOC_WRITE, ioc_type, nr, std::mem::size_of::<T>())
+}
+
+/// Calls an ioctl which takes a pointer argument, returning the (non-negative) return value.
+///
+/// # Safety
+///
+/// The request must expect a pointer to a value of type T.
+pub unsafe fn ioctl_ptr<T>(file: &File, request: u32, arg: &mut T) -> io::Result<i32> {
+    let ret = ioctl(file.as_raw_fd(), request as c_ulong, arg as *mut T);
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
     }
-}
\ No newline at end of file
+    Ok(ret)
+}
+
+// see "man 2 ioprio_set", there is no libc wrapper
+const IOPRIO_WHO_PROCESS: c_long = 1;
+const IOPRIO_CLASS_IDLE: c_long = 3;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+const PRIO_PROCESS: i32 = 0;
+
+// see "man 2 gettid", the id of the calling thread rather than the process
+fn gettid() -> c_long {
+    unsafe { syscall(libc::SYS_gettid) }
+}
+
+/// Sets the calling thread to the idle io scheduling class, so it only gets disk time when no
+/// other process needs it. Other threads of the process are unaffected.
+pub fn set_io_priority_idle() -> io::Result<()> {
+    let prio = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+    let ret = unsafe { syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, gettid(), prio) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sets the nice level of the calling thread, other threads of the process are unaffected.
+pub fn set_nice(nice: i32) -> io::Result<()> {
+    let ret = unsafe { setpriority(PRIO_PROCESS, gettid() as u32, nice) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// see sys/statvfs.h, the block counts are unsigned longs without large file support
+#[repr(C)]
+#[derive(Default)]
+struct StatVfs {
+    f_bsize: c_ulong,
+    f_frsize: c_ulong,
+    f_blocks: c_ulong,
+    f_bfree: c_ulong,
+    f_bavail: c_ulong,
+    f_files: c_ulong,
+    f_ffree: c_ulong,
+    f_favail: c_ulong,
+    f_fsid: c_ulong,
+    #[cfg(target_pointer_width = "32")]
+    f_unused: c_int,
+    f_flag: c_ulong,
+    f_namemax: c_ulong,
+    f_spare: [c_int; 6],
+}
+
+/// Returns the space available to unprivileged users on the filesystem containing path.
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut buf = StatVfs::default();
+    let ret = unsafe { statvfs(c_path.as_ptr(), &mut buf) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // c_ulong is only 32 bits on some targets
+    #[allow(clippy::unnecessary_cast)]
+    Ok(buf.f_bavail as u64 * buf.f_frsize as u64)
+}
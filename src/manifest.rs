@@ -1,18 +1,78 @@
-
 use serde::Deserialize;
 use serde_json::Result;
+use std::collections::BTreeMap;
+use std::time::Duration;
 
 use crate::json;
+use crate::script::DEFAULT_SCRIPT_TIMEOUT;
 
 #[derive(Deserialize)]
 pub struct Manifest {
+    pub version: Option<String>,
+    #[serde(default)]
+    pub components: Vec<ComponentInfo>,
     pub payloads: Vec<PayloadInfo>,
 }
 
+/// A separately versioned part of the product, e.g. a co-processor firmware. Requirements map
+/// other component names to version constraints, e.g. ">=1.2, <2".
+#[derive(Deserialize)]
+pub struct ComponentInfo {
+    pub name: String,
+    pub version: String,
+    #[serde(default)]
+    pub requires: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    pub fn find_component(&self, name: &str) -> Option<&ComponentInfo> {
+        self.components
+            .iter()
+            .find(|component| component.name == name)
+    }
+
+    pub fn find_payload(&self, filename: &str) -> Option<&PayloadInfo> {
+        self.payloads.iter().find(|info| info.filename == filename)
+    }
+
+    /// Returns true if the file is used as a pre or post install hook by any payload.
+    pub fn is_hook(&self, filename: &str) -> bool {
+        self.payloads.iter().any(|info| {
+            info.pre_install.as_deref() == Some(filename)
+                || info.post_install.as_deref() == Some(filename)
+        })
+    }
+}
+
 #[derive(Deserialize)]
 pub enum PayloadType {
     #[serde(rename = "image")]
-    Image
+    Image,
+
+    #[serde(rename = "delta")]
+    Delta,
+
+    #[serde(rename = "script")]
+    Script,
+
+    // raw nand/nor flash partition, e.g. /dev/mtd3
+    #[serde(rename = "mtd")]
+    Mtd,
+
+    // ubi volume, e.g. /dev/ubi0_1
+    #[serde(rename = "ubi_volume")]
+    UbiVolume,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum ImageEncoding {
+    #[default]
+    #[serde(rename = "raw")]
+    Raw,
+
+    // android sparse image format
+    #[serde(rename = "sparse")]
+    Sparse,
 }
 
 #[derive(Deserialize)]
@@ -21,10 +81,51 @@ pub struct PayloadInfo {
     pub payload_type: PayloadType,
 
     pub filename: String,
-    pub dest: String,
 
-    // TODO: need to have optional fields for different types of payloads
-    pub not_used: Option<String>,
+    // an optional payload may be left out of the archive
+    #[serde(default)]
+    pub optional: bool,
+
+    // payloads of a component are only deployed if the installed version of the component
+    // differs. Components written to the A/B slots are always deployed, and their version is
+    // pending until the slot is committed.
+    pub component: Option<String>,
+
+    // required for image, mtd and ubi_volume payloads, delta payloads default to the inactive
+    // slot
+    pub dest: Option<String>,
+
+    // image payloads only
+    #[serde(default)]
+    pub encoding: ImageEncoding,
+
+    // image payloads only, the image is written at offset bytes into dest. Images which would
+    // write more than max_size bytes from the offset are refused.
+    #[serde(default)]
+    pub offset: u64,
+    pub max_size: Option<u64>,
+
+    // delta payloads only, the image which the delta is applied against. Defaults to the
+    // active slot.
+    pub source: Option<String>,
+    pub source_size: Option<u64>,
+    pub source_checksum: Option<String>,
+
+    // script payloads to run before and after this payload is deployed. A script which is used
+    // as a hook must come before the payload in the archive.
+    pub pre_install: Option<String>,
+    pub post_install: Option<String>,
+
+    // script payloads only, in seconds
+    pub timeout: Option<u64>,
+}
+
+impl PayloadInfo {
+    pub fn script_timeout(&self) -> Duration {
+        self.timeout
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_SCRIPT_TIMEOUT)
+    }
 }
 
 pub fn parse_manifest(buf: &str) -> Result<Manifest> {
@@ -47,7 +148,8 @@ mod test {
 
         let val: Manifest = parse_manifest(&buf).unwrap();
         assert!(matches!(val.payloads[0].payload_type, PayloadType::Image));
+        assert_eq!(val.payloads[0].encoding, ImageEncoding::Raw);
         assert_eq!("rootfs.img", val.payloads[0].filename);
-        assert_eq!("/tmp/test-device", val.payloads[0].dest);
+        assert_eq!("/tmp/test-device", val.payloads[0].dest.as_ref().unwrap());
     }
 }
@@ -0,0 +1,371 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use log::*;
+
+use crate::linux::{self, ior, iow};
+
+// see linux/mtd/mtd-abi.h
+#[repr(C)]
+#[derive(Default)]
+struct MtdInfoUser {
+    mtd_type: u8,
+    flags: u32,
+    size: u32,
+    erasesize: u32,
+    writesize: u32,
+    oobsize: u32,
+    padding: u64,
+}
+
+#[repr(C)]
+struct EraseInfoUser64 {
+    start: u64,
+    length: u64,
+}
+
+const MEMGETINFO: u32 = ior::<MtdInfoUser>(b'M', 1);
+const MEMGETBADBLOCK: u32 = iow::<i64>(b'M', 11);
+const MEMSETBADBLOCK: u32 = iow::<i64>(b'M', 12);
+const MEMERASE64: u32 = iow::<EraseInfoUser64>(b'M', 20);
+
+// see mtd/ubi-user.h
+const UBI_IOCVOLUP: u32 = iow::<i64>(b'O', 0);
+
+const EIO: i32 = 5;
+const EOPNOTSUPP: i32 = 95;
+
+#[derive(Debug, Clone, Copy)]
+pub struct FlashInfo {
+    pub size: u64,
+    pub erase_size: u32,
+    pub write_size: u32,
+}
+
+/// Raw flash operations, implemented with the mtd ioctls for a real device.
+pub trait FlashDevice {
+    fn info(&self) -> FlashInfo;
+
+    fn is_bad_block(&mut self, offset: u64) -> io::Result<bool>;
+
+    fn mark_bad_block(&mut self, offset: u64) -> io::Result<()>;
+
+    fn erase_block(&mut self, offset: u64) -> io::Result<()>;
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()>;
+}
+
+/// An mtd character device, e.g. /dev/mtd3.
+pub struct MtdDevice {
+    file: File,
+    info: FlashInfo,
+}
+
+impl MtdDevice {
+    pub fn open(path: &Path) -> io::Result<MtdDevice> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        let mut info = MtdInfoUser::default();
+        unsafe { linux::ioctl_ptr(&file, MEMGETINFO, &mut info)? };
+        let info = FlashInfo {
+            size: info.size as u64,
+            erase_size: info.erasesize,
+            write_size: info.writesize,
+        };
+        debug!("opened mtd device: {}, {:?}", path.display(), info);
+        Ok(MtdDevice { file, info })
+    }
+}
+
+impl FlashDevice for MtdDevice {
+    fn info(&self) -> FlashInfo {
+        self.info
+    }
+
+    fn is_bad_block(&mut self, offset: u64) -> io::Result<bool> {
+        let mut offset = offset as i64;
+        match unsafe { linux::ioctl_ptr(&self.file, MEMGETBADBLOCK, &mut offset) } {
+            Ok(ret) => Ok(ret > 0),
+            // nor flash has no concept of bad blocks
+            Err(err) if err.raw_os_error() == Some(EOPNOTSUPP) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn mark_bad_block(&mut self, offset: u64) -> io::Result<()> {
+        let mut offset = offset as i64;
+        unsafe { linux::ioctl_ptr(&self.file, MEMSETBADBLOCK, &mut offset)? };
+        Ok(())
+    }
+
+    fn erase_block(&mut self, offset: u64) -> io::Result<()> {
+        let mut erase = EraseInfoUser64 {
+            start: offset,
+            length: self.info.erase_size as u64,
+        };
+        unsafe { linux::ioctl_ptr(&self.file, MEMERASE64, &mut erase)? };
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)
+    }
+}
+
+impl FlashDevice for Box<dyn FlashDevice> {
+    fn info(&self) -> FlashInfo {
+        (**self).info()
+    }
+
+    fn is_bad_block(&mut self, offset: u64) -> io::Result<bool> {
+        (**self).is_bad_block(offset)
+    }
+
+    fn mark_bad_block(&mut self, offset: u64) -> io::Result<()> {
+        (**self).mark_bad_block(offset)
+    }
+
+    fn erase_block(&mut self, offset: u64) -> io::Result<()> {
+        (**self).erase_block(offset)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        (**self).write_at(offset, buf)
+    }
+}
+
+/// Writes an image to flash an erase block at a time, skipping any bad blocks.
+pub struct FlashWriter<D: FlashDevice> {
+    device: D,
+    info: FlashInfo,
+    block: Vec<u8>,
+    // offset of the next physical erase block to be written
+    next_block: u64,
+}
+
+impl<D: FlashDevice> FlashWriter<D> {
+    pub fn new(device: D) -> FlashWriter<D> {
+        let info = device.info();
+        FlashWriter {
+            device,
+            info,
+            block: Vec::with_capacity(info.erase_size as usize),
+            next_block: 0,
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    // returns the offset of the next good block, which has been erased
+    fn next_good_block(&mut self) -> io::Result<u64> {
+        loop {
+            let offset = self.next_block;
+            if offset + self.info.erase_size as u64 > self.info.size {
+                return Err(io::Error::other(
+                    "image does not fit in flash, too many bad blocks",
+                ));
+            }
+            self.next_block += self.info.erase_size as u64;
+
+            if self.device.is_bad_block(offset)? {
+                info!("skipping bad block at offset: {:#x}", offset);
+                continue;
+            }
+            match self.device.erase_block(offset) {
+                Ok(()) => return Ok(offset),
+                Err(err) if err.raw_os_error() == Some(EIO) => {
+                    warn!("erase failed at offset: {:#x}, marking bad", offset);
+                    self.device.mark_bad_block(offset)?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        // the final block is padded to a whole erase block with the erased value
+        self.block.resize(self.info.erase_size as usize, 0xFF);
+        let offset = self.next_good_block()?;
+        trace!("writing erase block at offset: {:#x}", offset);
+
+        // erased pages are left unwritten, so they can still be programmed later
+        let block = std::mem::take(&mut self.block);
+        for (idx, page) in block.chunks(self.info.write_size as usize).enumerate() {
+            if page.iter().all(|b| *b == 0xFF) {
+                continue;
+            }
+            let page_offset = offset + (idx * self.info.write_size as usize) as u64;
+            self.device.write_at(page_offset, page)?;
+        }
+        self.block = block;
+        self.block.clear();
+        Ok(())
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let count = usize::min(buf.len(), self.info.erase_size as usize - self.block.len());
+            self.block.extend_from_slice(&buf[0..count]);
+            buf = &buf[count..];
+            if self.block.len() == self.info.erase_size as usize {
+                self.write_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes any buffered data, and erases the rest of the device so no stale data remains.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+        while self.next_block + self.info.erase_size as u64 <= self.info.size {
+            let offset = self.next_block;
+            self.next_block += self.info.erase_size as u64;
+            if !self.device.is_bad_block(offset)? {
+                self.device.erase_block(offset)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Opens a ubi volume, e.g. /dev/ubi0_1, and starts a volume update of size bytes. Exactly size
+/// bytes must then be written to the volume.
+pub fn begin_volume_update(path: &Path, size: u64) -> io::Result<File> {
+    let file = OpenOptions::new().write(true).open(path)?;
+
+    let mut size = size as i64;
+    unsafe { linux::ioctl_ptr(&file, UBI_IOCVOLUP, &mut size)? };
+    debug!(
+        "started ubi volume update: {}, size: {}",
+        path.display(),
+        size
+    );
+    Ok(file)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use std::collections::HashSet;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// A file-backed flash device, which enforces erase before write.
+    pub struct MockFlash {
+        pub path: PathBuf,
+        pub bad_blocks: HashSet<u64>,
+        pub fail_erase: HashSet<u64>,
+        erased: HashSet<u64>,
+        file: File,
+        info: FlashInfo,
+    }
+
+    impl MockFlash {
+        pub fn new(blocks: usize, erase_size: u32, write_size: u32) -> MockFlash {
+            let path = make_tempfile_path();
+            fs::write(&path, vec![0u8; blocks * erase_size as usize]).unwrap();
+            MockFlash {
+                file: OpenOptions::new().write(true).open(&path).unwrap(),
+                path,
+                bad_blocks: HashSet::new(),
+                fail_erase: HashSet::new(),
+                erased: HashSet::new(),
+                info: FlashInfo {
+                    size: blocks as u64 * erase_size as u64,
+                    erase_size,
+                    write_size,
+                },
+            }
+        }
+    }
+
+    impl FlashDevice for MockFlash {
+        fn info(&self) -> FlashInfo {
+            self.info
+        }
+
+        fn is_bad_block(&mut self, offset: u64) -> io::Result<bool> {
+            Ok(self.bad_blocks.contains(&offset))
+        }
+
+        fn mark_bad_block(&mut self, offset: u64) -> io::Result<()> {
+            self.bad_blocks.insert(offset);
+            Ok(())
+        }
+
+        fn erase_block(&mut self, offset: u64) -> io::Result<()> {
+            assert!(!self.bad_blocks.contains(&offset));
+            if self.fail_erase.contains(&offset) {
+                return Err(io::Error::from_raw_os_error(EIO));
+            }
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file
+                .write_all(&vec![0xFF; self.info.erase_size as usize])?;
+            self.erased.insert(offset);
+            Ok(())
+        }
+
+        fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+            let block = offset - offset % self.info.erase_size as u64;
+            assert!(self.erased.contains(&block), "write to unerased block");
+            assert_eq!(buf.len(), self.info.write_size as usize);
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.write_all(buf)
+        }
+    }
+
+    pub fn rand_image(len: usize) -> Vec<u8> {
+        (0..len).map(|_| rand::random::<u8>()).collect()
+    }
+
+    #[test]
+    fn skips_bad_blocks() {
+        init_logging();
+        let mut flash = MockFlash::new(8, 1024, 256);
+        flash.bad_blocks.insert(1024);
+        flash.fail_erase.insert(3072);
+
+        let image = rand_image(2500);
+        let mut writer = FlashWriter::new(flash);
+        for chunk in image.chunks(300) {
+            writer.write(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+
+        // blocks 1 and 3 are skipped
+        let flash = writer.device();
+        let data = fs::read(&flash.path).unwrap();
+        assert_eq!(&data[0..1024], &image[0..1024]);
+        assert_eq!(&data[2048..3072], &image[1024..2048]);
+        assert_eq!(&data[4096..4096 + 452], &image[2048..]);
+        assert!(data[4096 + 452..].iter().all(|b| *b == 0xFF));
+        assert!(flash.bad_blocks.contains(&3072));
+    }
+
+    #[test]
+    fn ioctl_numbers() {
+        // values from the kernel headers
+        assert_eq!(MEMGETINFO, 0x80204D01);
+        assert_eq!(MEMGETBADBLOCK, 0x40084D0B);
+        assert_eq!(MEMERASE64, 0x40104D14);
+        assert_eq!(UBI_IOCVOLUP, 0x40084F00);
+    }
+
+    #[test]
+    fn image_too_large() {
+        init_logging();
+        let mut flash = MockFlash::new(2, 1024, 256);
+        flash.bad_blocks.insert(0);
+
+        let mut writer = FlashWriter::new(flash);
+        assert!(writer.write(&rand_image(2048)).is_err());
+    }
+}
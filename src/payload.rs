@@ -1,12 +1,20 @@
 use std::{
-    fs::File,
-    io::{self, Write},
-    path::PathBuf,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
 };
 
 use log::debug;
 
 use crate::archive::ArchiveError;
+use crate::cancel::{self, CancelToken};
+use crate::checksum::Checksum;
+use crate::delta::{DeltaDecoder, DeltaSink};
+use crate::emmc::{self, ForceRoGuard};
+use crate::manifest::ImageEncoding;
+use crate::mtd::{self, FlashDevice, FlashWriter, MtdDevice};
+use crate::sparse::{SparseDecoder, SparseSink};
 
 // Represents the disk-image, file, directory payload data to be written to disk.
 pub trait Payload {
@@ -26,6 +34,13 @@ pub struct ImagePayload {
     remaining: u64,
     dest: PathBuf,
     dest_file: Option<File>,
+    sparse: Option<SparseDecoder>,
+    offset: u64,
+    max_size: Option<u64>,
+    // number of bytes written to dest, which differs from the image size for sparse images
+    written: u64,
+    // declared after dest_file, so that the file is closed before the partition is locked
+    force_ro: Option<ForceRoGuard>,
 }
 
 impl ImagePayload {
@@ -35,21 +50,149 @@ impl ImagePayload {
             remaining: image_size,
             dest,
             dest_file: None,
+            sparse: None,
+            offset: 0,
+            max_size: None,
+            written: 0,
+            force_ro: None,
         }
     }
+
+    /// Writes the image at an offset into dest, leaving everything before it untouched.
+    pub fn set_offset(&mut self, offset: u64) {
+        self.offset = offset;
+    }
+
+    /// Refuses images which would write more than max_size bytes, starting at the offset.
+    pub fn set_max_size(&mut self, max_size: Option<u64>) {
+        self.max_size = max_size;
+    }
+
+    pub fn set_encoding(&mut self, encoding: ImageEncoding) {
+        self.sparse = match encoding {
+            ImageEncoding::Raw => None,
+            ImageEncoding::Sparse => Some(SparseDecoder::new()),
+        };
+    }
+
+    fn finish_sparse(&mut self) -> Result<(), ArchiveError> {
+        let decoder = match &self.sparse {
+            Some(decoder) => decoder,
+            None => return Ok(()),
+        };
+        decoder.finish()?;
+
+        // a trailing "don't care" chunk is a seek past the end of a regular file, so the file
+        // needs to be extended to the full image size
+        let dest_file = self.dest_file.as_mut().unwrap();
+        let dest = &self.dest;
+        let map_err = |err| ArchiveError::IOError {
+            source: err,
+            context: format!("image writer, resizing dest: {}", dest.display()),
+        };
+        let metadata = dest_file.metadata().map_err(map_err)?;
+        let end = self.offset + decoder.expanded_size();
+        if metadata.is_file() && metadata.len() < end {
+            dest_file.set_len(end).map_err(map_err)?;
+        }
+        Ok(())
+    }
+
+    fn check_max_size(max_size: Option<u64>, written: u64) -> Result<(), ArchiveError> {
+        match max_size {
+            Some(max_size) if written > max_size => Err(ArchiveError::PayloadDeployError {
+                reason: format!("image exceeds max_size: {}", max_size),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn open_dest(&self) -> Result<File, ArchiveError> {
+        let map_err = |err| ArchiveError::IOError {
+            source: err,
+            context: format!("image writer, opening path: {}", self.dest.display()),
+        };
+        if self.offset == 0 {
+            return File::create(&self.dest).map_err(map_err);
+        }
+
+        // the data before the offset (e.g. a partition table) must be preserved
+        let mut dest_file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.dest)
+            .map_err(map_err)?;
+        dest_file
+            .seek(SeekFrom::Start(self.offset))
+            .map_err(map_err)?;
+        Ok(dest_file)
+    }
+}
+
+// expands sparse image chunks into the destination
+struct SparseWriter<'a> {
+    dest_file: &'a mut File,
+    dest: &'a Path,
+    written: &'a mut u64,
+    max_size: Option<u64>,
+}
+
+impl<'a> SparseWriter<'a> {
+    fn advance(&mut self, len: u64) -> Result<(), ArchiveError> {
+        *self.written += len;
+        ImagePayload::check_max_size(self.max_size, *self.written)
+    }
+}
+
+impl<'a> SparseSink for SparseWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> Result<(), ArchiveError> {
+        self.advance(buf.len() as u64)?;
+        self.dest_file
+            .write_all(buf)
+            .map_err(map_dest_err(self.dest))
+    }
+
+    fn fill(&mut self, value: [u8; 4], len: u64) -> Result<(), ArchiveError> {
+        self.advance(len)?;
+        let pattern: Vec<u8> = value.iter().cycle().take(4096).cloned().collect();
+        let mut remaining = len;
+        while remaining > 0 {
+            let count = u64::min(remaining, pattern.len() as u64) as usize;
+            self.dest_file
+                .write_all(&pattern[0..count])
+                .map_err(map_dest_err(self.dest))?;
+            remaining -= count as u64;
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, len: u64) -> Result<(), ArchiveError> {
+        self.advance(len)?;
+        self.dest_file
+            .seek(SeekFrom::Current(len as i64))
+            .map_err(map_dest_err(self.dest))?;
+        Ok(())
+    }
 }
 
 impl Payload for ImagePayload {
     fn write_begin(&mut self) -> Result<(), ArchiveError> {
+        // the size of a sparse image is checked as it is expanded
+        if self.sparse.is_none() {
+            Self::check_max_size(self.max_size, self.image_size)?;
+        }
+
+        self.force_ro = emmc::unlock_boot_partition(&self.dest)?;
+
         // open the destination file
         // TODO: I think this will fail for a block device
-        self.dest_file = Some(
-            File::create(&self.dest).map_err(|err| ArchiveError::IOError {
-                source: err,
-                context: format!("image writer, opening path: {}", &self.dest.display()),
-            })?,
+        self.dest_file = Some(self.open_dest()?);
+        debug!(
+            "opened destination: {}, offset: {}",
+            self.dest.display(),
+            self.offset
         );
-        debug!("opened destination: {}", self.dest.display());
         Ok(())
     }
 
@@ -62,11 +205,18 @@ impl Payload for ImagePayload {
             });
         }
 
-        self.dest_file
-            .as_mut()
-            .unwrap()
-            .write_all(buf)
-            .map_err(|err| {
+        let dest_file = self.dest_file.as_mut().unwrap();
+        if let Some(decoder) = &mut self.sparse {
+            let mut writer = SparseWriter {
+                dest_file,
+                dest: &self.dest,
+                written: &mut self.written,
+                max_size: self.max_size,
+            };
+            decoder.feed(buf, &mut writer)?;
+        } else {
+            self.written += buf.len() as u64;
+            dest_file.write_all(buf).map_err(|err| {
                 let pos = self.image_size - self.remaining;
                 ArchiveError::IOError {
                     source: err,
@@ -77,33 +227,380 @@ impl Payload for ImagePayload {
                     ),
                 }
             })?;
+        }
         debug!("wrote {} bytes to dest", buf.len());
 
         self.remaining -= buf.len() as u64;
         if self.remaining == 0 {
+            self.finish_sparse()?;
+            if self.force_ro.is_some() {
+                // data must reach the device before it is made read-only again
+                self.dest_file
+                    .as_mut()
+                    .unwrap()
+                    .sync_all()
+                    .map_err(map_dest_err(&self.dest))?;
+            }
             return Ok(Status::Complete);
         }
         Ok(Status::Pending)
     }
 }
 
-fn read_block<R: io::Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize, ArchiveError> {
-    let read_count = reader.read(buf).map_err(|err| ArchiveError::IOError {
+struct DeltaFiles {
+    source: File,
+    source_size: u64,
+    dest: File,
+    dest_path: PathBuf,
+    copy_buf: Vec<u8>,
+}
+
+fn map_dest_err(dest_path: &Path) -> impl FnOnce(io::Error) -> ArchiveError + '_ {
+    move |err| ArchiveError::IOError {
+        source: err,
+        context: format!("payload writer, writing to dest: {}", dest_path.display()),
+    }
+}
+
+impl DeltaSink for DeltaFiles {
+    fn copy(&mut self, src_offset: u64, len: u64) -> Result<(), ArchiveError> {
+        // only the verified region of the source may be copied from
+        let end = src_offset.checked_add(len);
+        if end.is_none_or(|end| end > self.source_size) {
+            return Err(ArchiveError::PayloadDeployError {
+                reason: format!(
+                    "delta copy from offset {} len {} exceeds source size {}",
+                    src_offset, len, self.source_size
+                ),
+            });
+        }
+
+        let read_err = |err| ArchiveError::IOError {
+            source: err,
+            context: format!("delta writer, reading source at offset: {}", src_offset),
+        };
+        self.source
+            .seek(SeekFrom::Start(src_offset))
+            .map_err(read_err)?;
+
+        let mut remaining = len;
+        while remaining > 0 {
+            let count = u64::min(remaining, self.copy_buf.len() as u64) as usize;
+            self.source
+                .read_exact(&mut self.copy_buf[0..count])
+                .map_err(read_err)?;
+            self.dest
+                .write_all(&self.copy_buf[0..count])
+                .map_err(map_dest_err(&self.dest_path))?;
+            remaining -= count as u64;
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, buf: &[u8]) -> Result<(), ArchiveError> {
+        self.dest
+            .write_all(buf)
+            .map_err(map_dest_err(&self.dest_path))
+    }
+}
+
+// Rebuilds an image by applying a delta to a source image, which must match the expected
+// checksum before anything is written.
+pub struct DeltaPayload {
+    remaining: u64,
+    source: PathBuf,
+    source_size: u64,
+    source_checksum: Checksum,
+    dest: PathBuf,
+    files: Option<DeltaFiles>,
+    decoder: DeltaDecoder,
+}
+
+impl DeltaPayload {
+    pub fn new(
+        delta_size: u64,
+        source: PathBuf,
+        source_size: u64,
+        source_checksum: Checksum,
+        dest: PathBuf,
+    ) -> DeltaPayload {
+        DeltaPayload {
+            remaining: delta_size,
+            source,
+            source_size,
+            source_checksum,
+            dest,
+            files: None,
+            decoder: DeltaDecoder::new(),
+        }
+    }
+
+    fn verify_source(&self, source: &mut File) -> Result<(), ArchiveError> {
+        // the source may be a block device larger than the image, so only the first
+        // source_size bytes are checked
+        let mut cksum = Checksum::new_hashable();
+        let mut buf = vec![0u8; 10240];
+        let mut remaining = self.source_size;
+        while remaining > 0 {
+            let count = u64::min(remaining, buf.len() as u64) as usize;
+            source
+                .read_exact(&mut buf[0..count])
+                .map_err(|err| ArchiveError::IOError {
+                    source: err,
+                    context: format!("delta writer, verifying source: {}", self.source.display()),
+                })?;
+            cksum.update(&buf[0..count]);
+            remaining -= count as u64;
+        }
+        cksum.finalise();
+
+        if cksum != self.source_checksum {
+            return Err(ArchiveError::SourceChecksumMismatchError {
+                path: self.source.display().to_string(),
+            });
+        }
+        debug!("verified delta source: {}", self.source.display());
+        Ok(())
+    }
+}
+
+impl Payload for DeltaPayload {
+    fn write_begin(&mut self) -> Result<(), ArchiveError> {
+        // writing to the source would corrupt it while it is being read
+        if let (Ok(source), Ok(dest)) =
+            (fs::canonicalize(&self.source), fs::canonicalize(&self.dest))
+        {
+            if source == dest {
+                return Err(ArchiveError::PayloadDeployError {
+                    reason: format!("delta source and dest are the same: {}", dest.display()),
+                });
+            }
+        }
+
+        let mut source = File::open(&self.source).map_err(|err| ArchiveError::IOError {
+            source: err,
+            context: format!("delta writer, opening source: {}", self.source.display()),
+        })?;
+        self.verify_source(&mut source)?;
+
+        let dest = File::create(&self.dest).map_err(|err| ArchiveError::IOError {
+            source: err,
+            context: format!("delta writer, opening path: {}", self.dest.display()),
+        })?;
+        debug!("opened destination: {}", self.dest.display());
+
+        self.files = Some(DeltaFiles {
+            source,
+            source_size: self.source_size,
+            dest,
+            dest_path: self.dest.clone(),
+            copy_buf: vec![0u8; 10240],
+        });
+        Ok(())
+    }
+
+    fn write_block(&mut self, buf: &[u8]) -> Result<Status, ArchiveError> {
+        if self.remaining < buf.len() as u64 {
+            return Err(ArchiveError::PayloadDeployError {
+                reason: String::from("payload write overflow"),
+            });
+        }
+
+        self.decoder.feed(buf, self.files.as_mut().unwrap())?;
+        debug!("applied {} delta bytes", buf.len());
+
+        self.remaining -= buf.len() as u64;
+        if self.remaining == 0 {
+            self.decoder.finish()?;
+            return Ok(Status::Complete);
+        }
+        Ok(Status::Pending)
+    }
+}
+
+// Writes an image to a raw flash (mtd) partition, erasing each block and skipping bad blocks.
+pub struct MtdPayload {
+    remaining: u64,
+    dest: PathBuf,
+    device: Option<Box<dyn FlashDevice>>,
+    writer: Option<FlashWriter<Box<dyn FlashDevice>>>,
+}
+
+impl MtdPayload {
+    pub fn new(image_size: u64, dest: PathBuf) -> MtdPayload {
+        MtdPayload {
+            remaining: image_size,
+            dest,
+            device: None,
+            writer: None,
+        }
+    }
+
+    // writes to the given device rather than opening dest, used for testing
+    fn with_device(image_size: u64, dest: PathBuf, device: Box<dyn FlashDevice>) -> MtdPayload {
+        let mut payload = MtdPayload::new(image_size, dest);
+        payload.device = Some(device);
+        payload
+    }
+}
+
+fn map_mtd_err(dest: &Path) -> impl FnOnce(io::Error) -> ArchiveError + '_ {
+    move |err| ArchiveError::IOError {
         source: err,
-        context: format!("image writer, reading from archive"),
+        context: format!("mtd writer, writing to dest: {}", dest.display()),
+    }
+}
+
+impl Payload for MtdPayload {
+    fn write_begin(&mut self) -> Result<(), ArchiveError> {
+        let device = match self.device.take() {
+            Some(device) => device,
+            None => {
+                let device = MtdDevice::open(&self.dest).map_err(|err| ArchiveError::IOError {
+                    source: err,
+                    context: format!("mtd writer, opening path: {}", self.dest.display()),
+                })?;
+                Box::new(device)
+            }
+        };
+
+        let info = device.info();
+        if self.remaining > info.size {
+            return Err(ArchiveError::PayloadDeployError {
+                reason: format!(
+                    "image size {} is larger than mtd device {}",
+                    self.remaining,
+                    self.dest.display()
+                ),
+            });
+        }
+        self.writer = Some(FlashWriter::new(device));
+        debug!("opened destination: {}", self.dest.display());
+        Ok(())
+    }
+
+    fn write_block(&mut self, buf: &[u8]) -> Result<Status, ArchiveError> {
+        if self.remaining < buf.len() as u64 {
+            return Err(ArchiveError::PayloadDeployError {
+                reason: String::from("payload write overflow"),
+            });
+        }
+
+        let writer = self.writer.as_mut().unwrap();
+        writer.write(buf).map_err(map_mtd_err(&self.dest))?;
+        self.remaining -= buf.len() as u64;
+        if self.remaining == 0 {
+            writer.finish().map_err(map_mtd_err(&self.dest))?;
+            return Ok(Status::Complete);
+        }
+        Ok(Status::Pending)
+    }
+}
+
+// Writes an image to a ubi volume using a volume update, which leaves the volume marked as
+// corrupted until the whole image is written.
+pub struct UbiVolumePayload {
+    image_size: u64,
+    remaining: u64,
+    dest: PathBuf,
+    dest_file: Option<File>,
+}
+
+impl UbiVolumePayload {
+    pub fn new(image_size: u64, dest: PathBuf) -> UbiVolumePayload {
+        UbiVolumePayload {
+            image_size,
+            remaining: image_size,
+            dest,
+            dest_file: None,
+        }
+    }
+
+    // writes to the given file rather than updating the volume at dest, used for testing
+    fn with_file(image_size: u64, dest: PathBuf, file: File) -> UbiVolumePayload {
+        let mut payload = UbiVolumePayload::new(image_size, dest);
+        payload.dest_file = Some(file);
+        payload
+    }
+}
+
+impl Payload for UbiVolumePayload {
+    fn write_begin(&mut self) -> Result<(), ArchiveError> {
+        if self.dest_file.is_some() {
+            return Ok(());
+        }
+        let map_err = |err| ArchiveError::IOError {
+            source: err,
+            context: format!("ubi writer, opening path: {}", self.dest.display()),
+        };
+
+        // the volume must already exist, a missing volume is never created as a regular file
+        let metadata = fs::metadata(&self.dest).map_err(map_err)?;
+        if !metadata.file_type().is_char_device() {
+            return Err(ArchiveError::PayloadDeployError {
+                reason: format!("dest is not a ubi volume: {}", self.dest.display()),
+            });
+        }
+        let dest_file = mtd::begin_volume_update(&self.dest, self.image_size).map_err(map_err)?;
+        self.dest_file = Some(dest_file);
+        debug!("opened destination: {}", self.dest.display());
+        Ok(())
+    }
+
+    fn write_block(&mut self, buf: &[u8]) -> Result<Status, ArchiveError> {
+        if self.remaining < buf.len() as u64 {
+            return Err(ArchiveError::PayloadDeployError {
+                reason: String::from("payload write overflow"),
+            });
+        }
+
+        self.dest_file
+            .as_mut()
+            .unwrap()
+            .write_all(buf)
+            .map_err(|err| ArchiveError::IOError {
+                source: err,
+                context: format!(
+                    "ubi writer, writing to dest: {}, pos: {}",
+                    self.dest.display(),
+                    self.image_size - self.remaining
+                ),
+            })?;
+
+        self.remaining -= buf.len() as u64;
+        if self.remaining == 0 {
+            return Ok(Status::Complete);
+        }
+        Ok(Status::Pending)
+    }
+}
+
+fn read_block<R: io::Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize, ArchiveError> {
+    let read_count = reader.read(buf).map_err(|err| {
+        if cancel::is_cancelled_error(&err) {
+            return ArchiveError::Cancelled;
+        }
+        ArchiveError::IOError {
+            source: err,
+            context: "image writer, reading from archive".to_string(),
+        }
     })?;
     debug!("read {} bytes from reader", read_count);
     Ok(read_count)
 }
 
+/// Writes a payload from the reader, checking for cancellation before each block.
 pub fn deploy_payload<'a, R: io::Read>(
     reader: &mut R,
     payload: Box<dyn Payload + 'a>,
+    cancel: &CancelToken,
 ) -> Result<(), ArchiveError> {
     let mut payload = payload;
+    cancel.check()?;
     payload.write_begin()?;
 
     loop {
+        cancel.check()?;
         let mut buf = vec![0u8; 2048];
         let read_count = read_block(reader, &mut buf)?;
 
@@ -124,6 +621,9 @@ pub fn deploy_payload<'a, R: io::Read>(
 #[cfg(test)]
 pub mod test {
     use super::*;
+    use crate::delta::generate_delta;
+    use crate::mtd::test::MockFlash;
+    use crate::sparse::generate_sparse;
     use crate::test_utils::*;
     use std::process::Command;
 
@@ -134,7 +634,7 @@ pub mod test {
         let dest_path = make_tempfile_path();
         let payload = ImagePayload::new(file_size, dest_path.clone());
         assert_eq!(
-            deploy_payload(&mut img_file, Box::new(payload)).unwrap(),
+            deploy_payload(&mut img_file, Box::new(payload), &CancelToken::new()).unwrap(),
             ()
         );
 
@@ -161,4 +661,255 @@ pub mod test {
         let path = test_path("archive/test-img-larger.img");
         do_image_test(&path);
     }
+
+    #[test]
+    fn test_deploy_sparse_image() {
+        init_logging();
+        // an image with a trailing hole, which must still be expanded to the full size
+        let image_path = make_tempfile_path();
+        let image = fs::read(test_path("archive/test-img-larger.img")).unwrap();
+        let mut image_file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&image_path)
+            .unwrap();
+        image_file.write_all(&image).unwrap();
+        image_file.set_len(256 * 1024).unwrap();
+
+        let sparse_path = make_tempfile_path();
+        generate_sparse(&mut image_file, &mut File::create(&sparse_path).unwrap()).unwrap();
+
+        let mut sparse_file = File::open(&sparse_path).unwrap();
+        let sparse_size = sparse_file.metadata().unwrap().len();
+        let dest_path = make_tempfile_path();
+        let mut payload = ImagePayload::new(sparse_size, dest_path.clone());
+        payload.set_encoding(ImageEncoding::Sparse);
+        deploy_payload(&mut sparse_file, Box::new(payload), &CancelToken::new()).unwrap();
+
+        assert_eq!(fs::read(image_path).unwrap(), fs::read(dest_path).unwrap());
+    }
+
+    #[test]
+    fn test_deploy_mtd() {
+        init_logging();
+        let mut flash = MockFlash::new(16, 4096, 512);
+        flash.bad_blocks.insert(4096);
+        let flash_path = flash.path.clone();
+
+        let image = fs::read(test_path("archive/test-img-larger.img")).unwrap();
+        let payload = MtdPayload::with_device(
+            image.len() as u64,
+            PathBuf::from("/dev/mtd-mock"),
+            Box::new(flash),
+        );
+        deploy_payload(&mut &image[..], Box::new(payload), &CancelToken::new()).unwrap();
+
+        // the second block is bad, so the image continues at the third
+        let data = fs::read(flash_path).unwrap();
+        assert_eq!(&data[0..4096], &image[0..4096]);
+        assert_eq!(&data[8192..8192 + image.len() - 4096], &image[4096..]);
+    }
+
+    #[test]
+    fn test_deploy_mtd_too_large() {
+        init_logging();
+        let flash = MockFlash::new(2, 4096, 512);
+        let image = fs::read(test_path("archive/test-img-larger.img")).unwrap();
+        let payload = MtdPayload::with_device(
+            image.len() as u64,
+            PathBuf::from("/dev/mtd-mock"),
+            Box::new(flash),
+        );
+        let err =
+            deploy_payload(&mut &image[..], Box::new(payload), &CancelToken::new()).unwrap_err();
+        assert!(matches!(err, ArchiveError::PayloadDeployError { .. }));
+    }
+
+    #[test]
+    fn test_deploy_ubi_volume_file() {
+        init_logging();
+        let path = test_path("archive/test.img");
+        let mut img_file = File::open(&path).unwrap();
+        let file_size = img_file.metadata().unwrap().len();
+
+        let dest_path = make_tempfile_path();
+        let payload = UbiVolumePayload::with_file(
+            file_size,
+            PathBuf::from("/dev/ubi-mock"),
+            File::create(&dest_path).unwrap(),
+        );
+        deploy_payload(&mut img_file, Box::new(payload), &CancelToken::new()).unwrap();
+        assert_eq!(fs::read(path).unwrap(), fs::read(dest_path).unwrap());
+    }
+
+    #[test]
+    fn test_deploy_ubi_volume_not_device() {
+        init_logging();
+        let image = fs::read(test_path("archive/test.img")).unwrap();
+        let deploy = |dest: &Path| {
+            let payload = UbiVolumePayload::new(image.len() as u64, dest.to_owned());
+            deploy_payload(&mut &image[..], Box::new(payload), &CancelToken::new()).unwrap_err()
+        };
+
+        // a missing volume is not created
+        let missing = make_tempfile_path();
+        assert!(matches!(deploy(&missing), ArchiveError::IOError { .. }));
+        assert!(!missing.exists());
+
+        let file = make_tempfile_path();
+        fs::write(&file, b"").unwrap();
+        let err = deploy(&file);
+        assert!(matches!(err, ArchiveError::PayloadDeployError { .. }));
+        assert!(fs::read(&file).unwrap().is_empty());
+
+        // a character device which isn't a ubi volume rejects the volume update ioctl
+        match deploy(Path::new("/dev/null")) {
+            ArchiveError::IOError { source, .. } => {
+                assert_eq!(source.raw_os_error(), Some(libc::ENOTTY))
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_deploy_image_offset() {
+        init_logging();
+        // the existing content before the offset must be preserved
+        let dest_path = make_tempfile_path();
+        let header = vec![0xAAu8; 8192];
+        fs::write(&dest_path, &header).unwrap();
+
+        let path = test_path("archive/test.img");
+        let image = fs::read(&path).unwrap();
+        let mut payload = ImagePayload::new(image.len() as u64, dest_path.clone());
+        payload.set_offset(8192);
+        payload.set_max_size(Some(image.len() as u64));
+        deploy_payload(
+            &mut File::open(path).unwrap(),
+            Box::new(payload),
+            &CancelToken::new(),
+        )
+        .unwrap();
+
+        let data = fs::read(dest_path).unwrap();
+        assert_eq!(&data[0..8192], &header[..]);
+        assert_eq!(&data[8192..], &image[..]);
+    }
+
+    #[test]
+    fn test_deploy_image_max_size() {
+        init_logging();
+        let dest_path = make_tempfile_path();
+        let path = test_path("archive/test.img");
+        let image = fs::read(&path).unwrap();
+
+        let mut payload = ImagePayload::new(image.len() as u64, dest_path.clone());
+        payload.set_offset(1024);
+        payload.set_max_size(Some(1024));
+        let err = deploy_payload(
+            &mut File::open(path).unwrap(),
+            Box::new(payload),
+            &CancelToken::new(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ArchiveError::PayloadDeployError { .. }));
+        assert!(!dest_path.exists());
+    }
+
+    #[test]
+    fn test_deploy_sparse_image_max_size() {
+        init_logging();
+        let image_path = make_tempfile_path();
+        let mut image_file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&image_path)
+            .unwrap();
+        image_file.set_len(64 * 1024).unwrap();
+
+        let sparse_path = make_tempfile_path();
+        generate_sparse(&mut image_file, &mut File::create(&sparse_path).unwrap()).unwrap();
+        let mut sparse_file = File::open(&sparse_path).unwrap();
+        let sparse_size = sparse_file.metadata().unwrap().len();
+
+        let mut payload = ImagePayload::new(sparse_size, make_tempfile_path());
+        payload.set_encoding(ImageEncoding::Sparse);
+        payload.set_max_size(Some(32 * 1024));
+        let err =
+            deploy_payload(&mut sparse_file, Box::new(payload), &CancelToken::new()).unwrap_err();
+        assert!(matches!(err, ArchiveError::PayloadDeployError { .. }));
+    }
+
+    fn make_delta(source: &Path, target: &Path) -> (File, u64) {
+        let delta_path = make_tempfile_path();
+        let mut delta_file = File::create(&delta_path).unwrap();
+        generate_delta(
+            &mut File::open(source).unwrap(),
+            &mut File::open(target).unwrap(),
+            &mut delta_file,
+        )
+        .unwrap();
+
+        let delta_file = File::open(&delta_path).unwrap();
+        let delta_size = delta_file.metadata().unwrap().len();
+        (delta_file, delta_size)
+    }
+
+    fn file_checksum(path: &Path) -> Checksum {
+        let mut cksum = Checksum::new_hashable();
+        cksum.update(&fs::read(path).unwrap());
+        cksum.finalise();
+        cksum
+    }
+
+    #[test]
+    fn test_deploy_delta() {
+        init_logging();
+        let source_path = test_path("archive/test-img-larger.img");
+        let target_path = test_path("archive/test.img");
+        let (mut delta_file, delta_size) = make_delta(&source_path, &target_path);
+
+        let dest_path = make_tempfile_path();
+        let source_size = source_path.metadata().unwrap().len();
+        let payload = DeltaPayload::new(
+            delta_size,
+            source_path.clone(),
+            source_size,
+            file_checksum(&source_path),
+            dest_path.clone(),
+        );
+        deploy_payload(&mut delta_file, Box::new(payload), &CancelToken::new()).unwrap();
+
+        assert_eq!(fs::read(target_path).unwrap(), fs::read(dest_path).unwrap());
+    }
+
+    #[test]
+    fn test_deploy_delta_source_mismatch() {
+        init_logging();
+        let source_path = test_path("archive/test-img-larger.img");
+        let target_path = test_path("archive/test.img");
+        let (mut delta_file, delta_size) = make_delta(&source_path, &target_path);
+
+        let dest_path = make_tempfile_path();
+        let source_size = source_path.metadata().unwrap().len();
+        let payload = DeltaPayload::new(
+            delta_size,
+            source_path,
+            source_size,
+            file_checksum(&target_path),
+            dest_path.clone(),
+        );
+        let err =
+            deploy_payload(&mut delta_file, Box::new(payload), &CancelToken::new()).unwrap_err();
+        assert!(matches!(
+            err,
+            ArchiveError::SourceChecksumMismatchError { .. }
+        ));
+        // nothing should have been written
+        assert!(!dest_path.exists());
+    }
 }
@@ -0,0 +1,215 @@
+use std::cell::Cell;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::archive::ArchiveError;
+use crate::utils::gen_rand_str;
+
+pub const DEFAULT_SCRIPT_TIMEOUT: Duration = Duration::from_secs(60);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A temporary directory which scripts are extracted to before being run, removed on drop. The
+/// directory is only created once the first script is extracted.
+pub struct ScriptDir {
+    path: PathBuf,
+    created: Cell<bool>,
+}
+
+impl Default for ScriptDir {
+    fn default() -> ScriptDir {
+        ScriptDir::new()
+    }
+}
+
+impl ScriptDir {
+    pub fn new() -> ScriptDir {
+        ScriptDir {
+            path: std::env::temp_dir().join(format!("skipper-scripts-{}", gen_rand_str(8))),
+            created: Cell::new(false),
+        }
+    }
+
+    /// The path which a script is extracted to, creating the directory if needed. The filename
+    /// must be a plain filename, so the script can't be written outside of the directory.
+    pub fn extract_path(&self, filename: &str) -> Result<PathBuf, ArchiveError> {
+        if filename.is_empty() || filename == "." || filename == ".." || filename.contains('/') {
+            return Err(ArchiveError::ManifestFormatError {
+                reason: format!("invalid script filename: {:?}", filename),
+            });
+        }
+        if !self.created.get() {
+            fs::create_dir(&self.path).map_err(|err| ArchiveError::IOError {
+                source: err,
+                context: format!("creating script directory: {}", self.path.display()),
+            })?;
+            self.created.set(true);
+        }
+        Ok(self.script_path(filename))
+    }
+
+    pub fn script_path(&self, filename: &str) -> PathBuf {
+        self.path.join(filename)
+    }
+
+    /// Marks an extracted script as executable, once its checksum has been verified.
+    pub fn make_executable(&self, filename: &str) -> Result<(), ArchiveError> {
+        let path = self.script_path(filename);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o700)).map_err(|err| {
+            ArchiveError::IOError {
+                source: err,
+                context: format!("setting script permissions: {}", path.display()),
+            }
+        })
+    }
+}
+
+impl Drop for ScriptDir {
+    fn drop(&mut self) {
+        if !self.created.get() {
+            return;
+        }
+        if let Err(err) = fs::remove_dir_all(&self.path) {
+            warn!(
+                "failed to remove script directory {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+fn log_output<R: Read + Send + 'static>(name: String, output: R, is_stderr: bool) {
+    // the threads are not joined, a script which leaves a background process running would
+    // otherwise block the deployment until the process exits
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines() {
+            match line {
+                Ok(line) if is_stderr => warn!("[{}] {}", name, line),
+                Ok(line) => info!("[{}] {}", name, line),
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+/// Runs a script, logging its output. The script is killed if it runs longer than timeout.
+pub fn run_script(path: &Path, name: &str, timeout: Duration) -> Result<(), ArchiveError> {
+    info!("running script: {}", name);
+    let map_err = |err: io::Error| ArchiveError::IOError {
+        source: err,
+        context: format!("running script: {}", name),
+    };
+
+    let mut child = Command::new(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(map_err)?;
+    log_output(name.to_owned(), child.stdout.take().unwrap(), false);
+    log_output(name.to_owned(), child.stderr.take().unwrap(), true);
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(map_err)? {
+            break status;
+        }
+        if start.elapsed() > timeout {
+            child.kill().map_err(map_err)?;
+            child.wait().map_err(map_err)?;
+            return Err(ArchiveError::ScriptError {
+                filename: name.to_owned(),
+                reason: format!("timed out after {} secs", timeout.as_secs_f32()),
+            });
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    if !status.success() {
+        return Err(ArchiveError::ScriptError {
+            filename: name.to_owned(),
+            reason: format!("script failed with {}", status),
+        });
+    }
+    debug!("script {} complete", name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn write_script(dir: &ScriptDir, filename: &str, content: &str) -> PathBuf {
+        let path = dir.extract_path(filename).unwrap();
+        fs::write(&path, content).unwrap();
+        dir.make_executable(filename).unwrap();
+        path
+    }
+
+    #[test]
+    fn success() {
+        init_logging();
+        let dir = ScriptDir::new();
+        let path = write_script(&dir, "ok.sh", "#!/bin/sh\necho hello\necho world >&2\n");
+        run_script(&path, "ok.sh", DEFAULT_SCRIPT_TIMEOUT).unwrap();
+    }
+
+    #[test]
+    fn failure() {
+        init_logging();
+        let dir = ScriptDir::new();
+        let path = write_script(&dir, "fail.sh", "#!/bin/sh\nexit 3\n");
+        let err = run_script(&path, "fail.sh", DEFAULT_SCRIPT_TIMEOUT).unwrap_err();
+        assert!(matches!(err, ArchiveError::ScriptError { .. }));
+    }
+
+    #[test]
+    fn timeout() {
+        init_logging();
+        let dir = ScriptDir::new();
+        let path = write_script(&dir, "slow.sh", "#!/bin/sh\nsleep 10\n");
+
+        let start = Instant::now();
+        let err = run_script(&path, "slow.sh", Duration::from_millis(200)).unwrap_err();
+        assert!(matches!(err, ArchiveError::ScriptError { .. }));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn dir_removed_on_drop() {
+        init_logging();
+        let dir = ScriptDir::new();
+        let path = write_script(&dir, "test.sh", "#!/bin/sh\n");
+        drop(dir);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn dir_created_lazily() {
+        init_logging();
+        let dir = ScriptDir::new();
+        assert!(!dir.path.exists());
+        write_script(&dir, "test.sh", "#!/bin/sh\n");
+        assert!(dir.path.is_dir());
+    }
+
+    #[test]
+    fn invalid_filenames() {
+        init_logging();
+        let dir = ScriptDir::new();
+        for filename in &["", ".", "..", "../test.sh", "dir/test.sh", "/test.sh"] {
+            let err = dir.extract_path(filename).unwrap_err();
+            assert!(matches!(err, ArchiveError::ManifestFormatError { .. }));
+        }
+        assert!(!dir.path.exists());
+    }
+}
@@ -6,14 +6,16 @@ use std::process::Command;
 use std::process::Stdio;
 use std::{fs, io, process};
 
-use clap::{App, Arg};
-use serde_json;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use skipper::utils;
 use thiserror::Error;
 
 use skipper::archive::CHECKSUMS_FILENAME;
 use skipper::checksum::Checksum;
-use skipper::manifest::{parse_manifest, Manifest};
+use skipper::container::ArchiveFormat;
+use skipper::delta;
+use skipper::manifest::{parse_manifest, ImageEncoding, Manifest, PayloadType};
+use skipper::sparse;
 
 #[derive(Error, Debug)]
 pub enum BuildError {
@@ -34,7 +36,7 @@ fn exit_on_error(err: BuildError) -> ! {
     let message = match &err {
         BuildError::IOError { source, message } => match source.kind() {
             io::ErrorKind::NotFound => {
-                format!("File not found: {}, {}", message, source.to_string())
+                format!("File not found: {}, {}", message, source)
             }
             _ => {
                 format!("Error: {}, {}", message, source)
@@ -66,12 +68,12 @@ fn read_manifest(path: &Path) -> Result<Manifest, BuildError> {
 
     let manifest = parse_manifest(&buf).map_err(|err| BuildError::JsonParseError {
         source: err,
-        message: format!("failed to parse manifest"),
+        message: "failed to parse manifest".to_string(),
     })?;
     Ok(manifest)
 }
 
-fn checksum_file(file_path: &PathBuf) -> Result<Checksum, BuildError> {
+fn checksum_file(file_path: &Path) -> Result<Checksum, BuildError> {
     let mut file =
         File::open(file_path).map_err(map_ioerr(file_path.to_string_lossy().to_string()))?;
     let mut read_buf = [0u8; 10240];
@@ -89,10 +91,7 @@ fn checksum_file(file_path: &PathBuf) -> Result<Checksum, BuildError> {
     Ok(cksum)
 }
 
-fn build_checksum_file(
-    archive_files: &Vec<PathBuf>,
-    work_dir: &PathBuf,
-) -> Result<PathBuf, BuildError> {
+fn build_checksum_file(archive_files: &[PathBuf], work_dir: &Path) -> Result<PathBuf, BuildError> {
     let cksum_file_path = work_dir.join(CHECKSUMS_FILENAME);
     let mut cksum_file =
         File::create(&cksum_file_path).map_err(map_ioerr(String::from(CHECKSUMS_FILENAME)))?;
@@ -104,7 +103,7 @@ fn build_checksum_file(
         // note: will panic if filename is not valid unicode
         let fname = filename.file_name().unwrap().to_str().unwrap();
 
-        write!(cksum_file, "{}\t{}\n", fname, cksum.to_string())
+        writeln!(cksum_file, "{}\t{}", fname, cksum)
             .map_err(map_ioerr(String::from(CHECKSUMS_FILENAME)))?;
     }
 
@@ -134,7 +133,12 @@ fn generate_archive(
     archive_files: &Vec<PathBuf>,
     work_dir: &Path,
     outfile_path: &Path,
+    format: ArchiveFormat,
 ) -> Result<(), BuildError> {
+    if format == ArchiveFormat::Tar {
+        return generate_tar(archive_files, work_dir, outfile_path);
+    }
+
     // $ echo -e "manifest.json\nimage-file" | cpio -ov --format=newc > test.cpio
     let mut proc = Command::new("cpio")
         .arg("-o")
@@ -162,21 +166,51 @@ fn generate_archive(
     });
 
     input_handle.join().unwrap();
-    let mut output = proc
+    let output = proc
         .wait_with_output()
         .expect("Failed to read from cpio stdout");
     let mut outfile = File::create(outfile_path).expect("Failed to create outfile");
     outfile
-        .write_all(&mut output.stdout)
+        .write_all(&output.stdout)
         .expect("Failed to write to outfile");
     Ok(())
 }
 
+// pax headers are used for sizes and names which don't fit in a ustar header
+fn generate_tar(
+    archive_files: &[PathBuf],
+    work_dir: &Path,
+    outfile_path: &Path,
+) -> Result<(), BuildError> {
+    // $ tar -c --format=pax -f - checksums manifest.jsonc image-file > test.tar
+    let output = Command::new("tar")
+        .arg("-c")
+        .arg("--format=pax")
+        .arg("-f")
+        .arg("-")
+        .args(archive_files)
+        .stdout(Stdio::piped())
+        .current_dir(work_dir)
+        .output()
+        .map_err(map_ioerr(String::from("failed to run tar")))?;
+    if !output.status.success() {
+        return Err(BuildError::ArgumentError {
+            message: format!(
+                "tar failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        });
+    }
+    fs::write(outfile_path, &output.stdout)
+        .map_err(map_ioerr(outfile_path.display().to_string()))?;
+    Ok(())
+}
+
 fn copy_to_workdir(src: &Path, work_dir: &Path) -> PathBuf {
     let src_filename = src.file_name().unwrap();
     let dest_path = work_dir.join(src_filename);
 
-    fs::copy(&src, &dest_path)
+    fs::copy(src, &dest_path)
         .map_err(map_ioerr(format!(
             "failed to copy {} to work_dir",
             src.display()
@@ -186,7 +220,51 @@ fn copy_to_workdir(src: &Path, work_dir: &Path) -> PathBuf {
     dest_path
 }
 
-fn build_archive(root_path: &Path, output: &Path) {
+fn sparse_to_workdir(src: &Path, work_dir: &Path) -> Result<PathBuf, BuildError> {
+    let mut src_file = File::open(src).map_err(map_ioerr(src.display().to_string()))?;
+    let mut magic = [0u8; 4];
+    let count = src_file
+        .read(&mut magic)
+        .map_err(map_ioerr(src.display().to_string()))?;
+
+    // images which are already sparse are packaged as they are
+    if sparse::is_sparse(&magic[0..count]) {
+        return Ok(copy_to_workdir(src, work_dir));
+    }
+
+    let dest_path = work_dir.join(src.file_name().unwrap());
+    let mut dest_file =
+        File::create(&dest_path).map_err(map_ioerr(dest_path.display().to_string()))?;
+    sparse::generate_sparse(&mut src_file, &mut dest_file).map_err(map_ioerr(format!(
+        "failed to convert {} to a sparse image",
+        src.display()
+    )))?;
+    Ok(dest_path)
+}
+
+// hooks are run from the archive as it streams, so each hook script has to be packaged before
+// the payloads which use it
+fn check_hooks(manifest: &Manifest) -> Result<(), BuildError> {
+    for (idx, payload_info) in manifest.payloads.iter().enumerate() {
+        let hooks = [&payload_info.pre_install, &payload_info.post_install];
+        for hook in hooks.iter().filter_map(|hook| hook.as_ref()) {
+            let preceding = manifest.payloads[..idx].iter().any(|info| {
+                info.filename == *hook && matches!(info.payload_type, PayloadType::Script)
+            });
+            if !preceding {
+                return Err(BuildError::ArgumentError {
+                    message: format!(
+                        "hook {} of payload {} must be a script listed before it in the manifest",
+                        hook, payload_info.filename
+                    ),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn build_archive(root_path: &Path, output: &Path, format: ArchiveFormat) {
     // TODO: should tidy this function up so it returns an error, and just exit at top level
     if !root_path.is_dir() {
         exit_on_error(BuildError::ArgumentError {
@@ -203,15 +281,24 @@ fn build_archive(root_path: &Path, output: &Path) {
     let manifest_path = copy_to_workdir(&manifest_path, &work_dir);
 
     let manifest = read_manifest(&manifest_path).unwrap_or_else(|err| exit_on_error(err));
+    check_hooks(&manifest).unwrap_or_else(|err| exit_on_error(err));
 
     let mut archive_files = vec![PathBuf::from(manifest_path.file_name().unwrap())];
     // generate list of files to go in the archive
     for payload_info in manifest.payloads {
         match payload_info.payload_type {
-            skipper::manifest::PayloadType::Image => {
+            PayloadType::Image
+            | PayloadType::Delta
+            | PayloadType::Script
+            | PayloadType::Mtd
+            | PayloadType::UbiVolume => {
                 // copy to work dir
                 let src_path = root_path.join(payload_info.filename);
-                let dest_path = copy_to_workdir(&src_path, &work_dir);
+                let dest_path = match payload_info.encoding {
+                    ImageEncoding::Sparse => sparse_to_workdir(&src_path, &work_dir)
+                        .unwrap_or_else(|err| exit_on_error(err)),
+                    ImageEncoding::Raw => copy_to_workdir(&src_path, &work_dir),
+                };
 
                 // push the filename
                 archive_files.push(PathBuf::from(dest_path.file_name().unwrap()));
@@ -225,13 +312,47 @@ fn build_archive(root_path: &Path, output: &Path) {
     archive_files.insert(0, PathBuf::from(checksums_path.file_name().unwrap()));
 
     // generate the archive
-    generate_archive(&archive_files, &work_dir, output).unwrap_or_else(|err| exit_on_error(err));
+    generate_archive(&archive_files, &work_dir, output, format)
+        .unwrap_or_else(|err| exit_on_error(err));
 
     cleanup_working_dir(&work_dir);
 }
 
+fn build_delta(source_path: &Path, target_path: &Path, output: &Path) -> Result<(), BuildError> {
+    let open = |path: &Path| File::open(path).map_err(map_ioerr(path.display().to_string()));
+    let mut source = open(source_path)?;
+    let mut target = open(target_path)?;
+    let mut outfile = File::create(output).map_err(map_ioerr(output.display().to_string()))?;
+
+    delta::generate_delta(&mut source, &mut target, &mut outfile).map_err(map_ioerr(format!(
+        "failed to generate delta {}",
+        output.display()
+    )))?;
+
+    // the manifest entry for the delta needs the size and checksum of the source image
+    let source_size = source
+        .metadata()
+        .map_err(map_ioerr(source_path.display().to_string()))?
+        .len();
+    let source_checksum = checksum_file(source_path)?;
+    println!("\"source_size\": {},", source_size);
+    println!("\"source_checksum\": \"{}\"", source_checksum);
+    Ok(())
+}
+
+fn delta_command(matches: &ArgMatches) {
+    let get_filename_path = |arg| Path::new(matches.value_of(arg).unwrap());
+    build_delta(
+        get_filename_path("source"),
+        get_filename_path("target"),
+        get_filename_path("output"),
+    )
+    .unwrap_or_else(|err| exit_on_error(err));
+}
+
 fn main() {
     let matches = App::new("skip-build")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("file-root")
                 .required(true)
@@ -246,8 +367,46 @@ fn main() {
                 .short("-o")
                 .help("output archive file"),
         )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["cpio", "tar"])
+                .default_value("cpio")
+                .help("archive container format"),
+        )
+        .subcommand(
+            SubCommand::with_name("delta")
+                .about("generate a delta payload which rebuilds the target image from the source")
+                .arg(
+                    Arg::with_name("source")
+                        .required(true)
+                        .takes_value(true)
+                        .short("-s")
+                        .help("image currently installed on the device"),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .required(true)
+                        .takes_value(true)
+                        .short("-t")
+                        .help("new image to be installed"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .required(true)
+                        .takes_value(true)
+                        .short("-o")
+                        .help("output delta file"),
+                ),
+        )
         .get_matches();
 
+    if let Some(delta_matches) = matches.subcommand_matches("delta") {
+        delta_command(delta_matches);
+        return;
+    }
+
     let get_filename_path = |arg| {
         let value = matches.value_of(arg).unwrap();
         Path::new(value)
@@ -255,5 +414,7 @@ fn main() {
     let file_root_path = get_filename_path("file-root");
     let output = get_filename_path("output");
 
-    build_archive(file_root_path, output);
+    // the value is checked by clap
+    let format = matches.value_of("format").unwrap().parse().unwrap();
+    build_archive(file_root_path, output, format);
 }
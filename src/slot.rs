@@ -0,0 +1,241 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::archive::ArchiveError;
+use crate::config::Config;
+
+const ROOT_ARG: &str = "root=";
+
+// slot statuses are kept in the data dir by default, for the bootloader integration to read
+const SLOT_STATUS_FILENAME: &str = "slots.json";
+
+/// One of the two (A/B) rootfs slots.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    pub fn device(self, config: &Config) -> &str {
+        match self {
+            Slot::A => &config.slots.rootfs_a,
+            Slot::B => &config.slots.rootfs_b,
+        }
+    }
+}
+
+/// Whether a slot may be booted. A slot is invalid while it is being written, and updated once
+/// a deploy to it has completed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlotStatus {
+    Good,
+    Updated,
+    Invalid,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SlotStatusFile {
+    a: Option<SlotStatus>,
+    b: Option<SlotStatus>,
+}
+
+impl SlotStatusFile {
+    fn status_mut(&mut self, slot: Slot) -> &mut Option<SlotStatus> {
+        match slot {
+            Slot::A => &mut self.a,
+            Slot::B => &mut self.b,
+        }
+    }
+}
+
+fn read_status_file(path: &Path) -> Result<SlotStatusFile, ArchiveError> {
+    let buf = match fs::read_to_string(path) {
+        Ok(buf) => buf,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(SlotStatusFile::default()),
+        Err(err) => {
+            return Err(ArchiveError::IOError {
+                source: err,
+                context: format!("reading slot status: {}", path.display()),
+            })
+        }
+    };
+    serde_json::from_str(&buf).map_err(|err| ArchiveError::SlotError {
+        reason: format!("invalid slot status file {}: {}", path.display(), err),
+    })
+}
+
+fn status_path(config: &Config) -> PathBuf {
+    match &config.bootloader.status_file {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(&config.paths.data_dir).join(SLOT_STATUS_FILENAME),
+    }
+}
+
+/// Returns the recorded status of a slot, None if it has never been recorded.
+pub fn slot_status(config: &Config, slot: Slot) -> Result<Option<SlotStatus>, ArchiveError> {
+    let mut statuses = read_status_file(&status_path(config))?;
+    Ok(*statuses.status_mut(slot))
+}
+
+pub fn set_slot_status(
+    config: &Config,
+    slot: Slot,
+    status: SlotStatus,
+) -> Result<(), ArchiveError> {
+    let path = status_path(config);
+    let mut statuses = read_status_file(&path)?;
+    *statuses.status_mut(slot) = Some(status);
+
+    // written via a rename, so the file is always complete
+    let map_err = |err: io::Error| ArchiveError::IOError {
+        source: err,
+        context: format!("writing slot status: {}", path.display()),
+    };
+    let tmp_path = path.with_extension("tmp");
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(map_err)?;
+    }
+    fs::write(&tmp_path, serde_json::to_string(&statuses).unwrap()).map_err(map_err)?;
+    fs::rename(&tmp_path, &path).map_err(map_err)?;
+    info!("slot {:?} marked {:?}", slot, status);
+    Ok(())
+}
+
+pub(crate) fn same_device(a: &str, b: &str) -> bool {
+    if a == b {
+        return true;
+    }
+    // device paths are often symlinks, e.g. /dev/disk/by-partlabel/rootfs_a
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+// an explicit slot selection passed by the bootloader takes precedence over the root device
+fn parse_active_slot(cmdline: &str, config: &Config) -> Result<Slot, ArchiveError> {
+    let slot_arg = format!("{}=", config.bootloader.slot_arg);
+    for arg in cmdline.split_whitespace() {
+        if let Some(slot) = arg.strip_prefix(&slot_arg) {
+            return match slot {
+                "a" | "A" => Ok(Slot::A),
+                "b" | "B" => Ok(Slot::B),
+                _ => Err(ArchiveError::SlotError {
+                    reason: format!("unknown slot name on kernel command line: {}", slot),
+                }),
+            };
+        }
+    }
+
+    let root = cmdline
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix(ROOT_ARG))
+        .ok_or_else(|| ArchiveError::SlotError {
+            reason: "no root device found on kernel command line".to_owned(),
+        })?;
+
+    if same_device(root, &config.slots.rootfs_a) {
+        Ok(Slot::A)
+    } else if same_device(root, &config.slots.rootfs_b) {
+        Ok(Slot::B)
+    } else {
+        Err(ArchiveError::SlotError {
+            reason: format!("root device {} does not match either rootfs slot", root),
+        })
+    }
+}
+
+/// Determines which slot the running system was booted from.
+pub fn active_slot(config: &Config) -> Result<Slot, ArchiveError> {
+    let cmdline_path = &config.bootloader.cmdline;
+    let cmdline = fs::read_to_string(cmdline_path).map_err(|err| ArchiveError::IOError {
+        source: err,
+        context: format!("reading kernel command line: {}", cmdline_path),
+    })?;
+    let slot = parse_active_slot(&cmdline, config)?;
+    debug!("active slot: {:?}", slot);
+    Ok(slot)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn test_config() -> Config {
+        Config::parse(
+            r#"{
+                "slots": { "rootfs_a": "/dev/mmcblk0p2", "rootfs_b": "/dev/mmcblk0p3" },
+                "paths": { "data_dir": "/tmp/skipper" }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn from_root_device() {
+        init_logging();
+        let config = test_config();
+
+        let slot = parse_active_slot("console=ttyS0 root=/dev/mmcblk0p3 rw", &config).unwrap();
+        assert_eq!(slot, Slot::B);
+        assert_eq!(slot.other().device(&config), "/dev/mmcblk0p2");
+
+        let err = parse_active_slot("root=/dev/sda1", &config).unwrap_err();
+        assert!(matches!(err, ArchiveError::SlotError { .. }));
+    }
+
+    #[test]
+    fn from_slot_arg() {
+        init_logging();
+        let config = test_config();
+
+        let slot = parse_active_slot("root=/dev/mmcblk0p3 skipper.slot=a", &config).unwrap();
+        assert_eq!(slot, Slot::A);
+        assert!(parse_active_slot("skipper.slot=c", &config).is_err());
+
+        let mut config = test_config();
+        config.bootloader.slot_arg = String::from("rauc.slot");
+        let slot = parse_active_slot("root=/dev/mmcblk0p2 rauc.slot=B", &config).unwrap();
+        assert_eq!(slot, Slot::B);
+    }
+
+    #[test]
+    fn status_persisted() {
+        init_logging();
+        let mut config = test_config();
+        config.paths.data_dir = make_tempfile_path().to_str().unwrap().to_owned();
+
+        assert_eq!(slot_status(&config, Slot::B).unwrap(), None);
+        set_slot_status(&config, Slot::B, SlotStatus::Invalid).unwrap();
+        set_slot_status(&config, Slot::A, SlotStatus::Good).unwrap();
+        assert_eq!(
+            slot_status(&config, Slot::B).unwrap(),
+            Some(SlotStatus::Invalid)
+        );
+        assert_eq!(
+            slot_status(&config, Slot::A).unwrap(),
+            Some(SlotStatus::Good)
+        );
+
+        let status_file = make_tempfile_path().join("slots.json");
+        config.bootloader.status_file = Some(status_file.to_str().unwrap().to_owned());
+        set_slot_status(&config, Slot::A, SlotStatus::Updated).unwrap();
+        assert!(status_file.exists());
+        assert_eq!(slot_status(&config, Slot::B).unwrap(), None);
+    }
+}
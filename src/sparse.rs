@@ -0,0 +1,597 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crc32fast::Hasher;
+use log::*;
+
+use crate::archive::ArchiveError;
+use crate::linux;
+
+// Android sparse image format, as produced by img2simg. The file header is followed by a list
+// of chunks, each describing a whole number of output blocks:
+//
+//  raw:        chunk data is written verbatim
+//  fill:       a 4-byte value is repeated over the chunk
+//  don't care: no data, the output is left untouched
+//  crc32:      a 4-byte crc of all output so far, used for verification
+pub const SPARSE_MAGIC: u32 = 0xED26FF3A;
+
+const FILE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+const MAJOR_VERSION: u16 = 1;
+
+const CHUNK_TYPE_RAW: u16 = 0xCAC1;
+const CHUNK_TYPE_FILL: u16 = 0xCAC2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xCAC3;
+const CHUNK_TYPE_CRC32: u16 = 0xCAC4;
+
+pub const SPARSE_BLOCK_SIZE: u32 = 4096;
+
+/// Receives the expanded output of a sparse image.
+pub trait SparseSink {
+    fn write(&mut self, buf: &[u8]) -> Result<(), ArchiveError>;
+
+    fn fill(&mut self, value: [u8; 4], len: u64) -> Result<(), ArchiveError>;
+
+    fn skip(&mut self, len: u64) -> Result<(), ArchiveError>;
+}
+
+enum DecodeState {
+    FileHeader,
+    ChunkHeader,
+    Raw {
+        remaining: u64,
+    },
+    Fill {
+        len: u64,
+    },
+    Crc32,
+    // skips header bytes beyond the size this decoder knows about
+    Padding {
+        remaining: usize,
+        next: Box<DecodeState>,
+    },
+}
+
+/// A streaming decoder for sparse images, which can be fed arbitrarily sized blocks.
+pub struct SparseDecoder {
+    state: DecodeState,
+    pending: Vec<u8>,
+    pos: usize,
+    block_size: u32,
+    chunk_header_size: usize,
+    total_blocks: u32,
+    chunks_remaining: u32,
+    written: u64,
+    crc: Hasher,
+}
+
+fn read_u16(buf: &[u8]) -> u16 {
+    u16::from_le_bytes([buf[0], buf[1]])
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+impl SparseDecoder {
+    pub fn new() -> SparseDecoder {
+        SparseDecoder {
+            state: DecodeState::FileHeader,
+            pending: Vec::with_capacity(FILE_HEADER_SIZE),
+            pos: 0,
+            block_size: 0,
+            chunk_header_size: CHUNK_HEADER_SIZE,
+            total_blocks: 0,
+            chunks_remaining: 0,
+            written: 0,
+            crc: Hasher::new(),
+        }
+    }
+
+    /// The size of the expanded image, only valid once the file header has been read.
+    pub fn expanded_size(&self) -> u64 {
+        self.total_blocks as u64 * self.block_size as u64
+    }
+
+    fn take_bytes(&mut self, buf: &mut &[u8], len: usize) -> Option<Vec<u8>> {
+        let count = usize::min(len - self.pending.len(), buf.len());
+        self.pending.extend_from_slice(&buf[0..count]);
+        *buf = &buf[count..];
+        self.pos += count;
+
+        if self.pending.len() == len {
+            return Some(std::mem::take(&mut self.pending));
+        }
+        None
+    }
+
+    fn format_error(&self, reason: String) -> ArchiveError {
+        ArchiveError::FormatError {
+            offset: self.pos,
+            reason,
+        }
+    }
+
+    fn with_padding(header_size: usize, expected: usize, next: DecodeState) -> DecodeState {
+        if header_size > expected {
+            DecodeState::Padding {
+                remaining: header_size - expected,
+                next: Box::new(next),
+            }
+        } else {
+            next
+        }
+    }
+
+    fn parse_file_header(&mut self, header: &[u8]) -> Result<DecodeState, ArchiveError> {
+        if read_u32(&header[0..]) != SPARSE_MAGIC {
+            return Err(self.format_error("sparse magic mismatch".to_owned()));
+        }
+        let major_version = read_u16(&header[4..]);
+        if major_version != MAJOR_VERSION {
+            return Err(self.format_error(format!(
+                "unsupported sparse major version: {}",
+                major_version
+            )));
+        }
+        let file_header_size = read_u16(&header[8..]) as usize;
+        let chunk_header_size = read_u16(&header[10..]) as usize;
+        if file_header_size < FILE_HEADER_SIZE || chunk_header_size < CHUNK_HEADER_SIZE {
+            return Err(self.format_error("sparse header sizes too small".to_owned()));
+        }
+        self.block_size = read_u32(&header[12..]);
+        if self.block_size == 0 || !self.block_size.is_multiple_of(4) {
+            return Err(
+                self.format_error(format!("invalid sparse block size: {}", self.block_size))
+            );
+        }
+        self.chunk_header_size = chunk_header_size;
+        self.total_blocks = read_u32(&header[16..]);
+        self.chunks_remaining = read_u32(&header[20..]);
+        debug!(
+            "sparse image, block size: {}, blocks: {}, chunks: {}",
+            self.block_size, self.total_blocks, self.chunks_remaining
+        );
+
+        Ok(Self::with_padding(
+            file_header_size,
+            FILE_HEADER_SIZE,
+            DecodeState::ChunkHeader,
+        ))
+    }
+
+    fn parse_chunk_header<S: SparseSink>(
+        &mut self,
+        header: &[u8],
+        sink: &mut S,
+    ) -> Result<DecodeState, ArchiveError> {
+        if self.chunks_remaining == 0 {
+            return Err(self.format_error("data found after last sparse chunk".to_owned()));
+        }
+        self.chunks_remaining -= 1;
+
+        let chunk_type = read_u16(&header[0..]);
+        let chunk_blocks = read_u32(&header[4..]) as u64;
+        let total_size = read_u32(&header[8..]) as u64;
+        let chunk_len = chunk_blocks * self.block_size as u64;
+        let data_size = total_size
+            .checked_sub(self.chunk_header_size as u64)
+            .ok_or_else(|| self.format_error("sparse chunk size too small".to_owned()))?;
+
+        if chunk_type != CHUNK_TYPE_CRC32 && self.written + chunk_len > self.expanded_size() {
+            return Err(self.format_error(format!(
+                "sparse chunk overflows image size: {}",
+                self.expanded_size()
+            )));
+        }
+
+        let expected_data_size = match chunk_type {
+            CHUNK_TYPE_RAW => chunk_len,
+            CHUNK_TYPE_FILL | CHUNK_TYPE_CRC32 => 4,
+            CHUNK_TYPE_DONT_CARE => 0,
+            _ => {
+                return Err(
+                    self.format_error(format!("unknown sparse chunk type: {:#06x}", chunk_type))
+                )
+            }
+        };
+        if data_size != expected_data_size {
+            return Err(self.format_error(format!(
+                "sparse chunk data size: {}, expected: {}",
+                data_size, expected_data_size
+            )));
+        }
+
+        let next = match chunk_type {
+            CHUNK_TYPE_RAW if chunk_len > 0 => DecodeState::Raw {
+                remaining: chunk_len,
+            },
+            CHUNK_TYPE_FILL => DecodeState::Fill { len: chunk_len },
+            CHUNK_TYPE_CRC32 => DecodeState::Crc32,
+            CHUNK_TYPE_DONT_CARE => {
+                trace!("sparse skip, len: {}", chunk_len);
+                sink.skip(chunk_len)?;
+                // unwritten regions are treated as zeroes for the purpose of the crc
+                self.update_crc_fill([0u8; 4], chunk_len);
+                self.written += chunk_len;
+                DecodeState::ChunkHeader
+            }
+            _ => DecodeState::ChunkHeader,
+        };
+        Ok(Self::with_padding(
+            self.chunk_header_size,
+            CHUNK_HEADER_SIZE,
+            next,
+        ))
+    }
+
+    fn update_crc_fill(&mut self, value: [u8; 4], len: u64) {
+        let pattern: Vec<u8> = value.iter().cycle().take(4096).cloned().collect();
+        let mut remaining = len;
+        while remaining > 0 {
+            let count = u64::min(remaining, pattern.len() as u64) as usize;
+            self.crc.update(&pattern[0..count]);
+            remaining -= count as u64;
+        }
+    }
+
+    pub fn feed<S: SparseSink>(&mut self, buf: &[u8], sink: &mut S) -> Result<(), ArchiveError> {
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let state = std::mem::replace(&mut self.state, DecodeState::ChunkHeader);
+            self.state = match state {
+                DecodeState::FileHeader => match self.take_bytes(&mut buf, FILE_HEADER_SIZE) {
+                    Some(header) => self.parse_file_header(&header)?,
+                    None => DecodeState::FileHeader,
+                },
+                DecodeState::ChunkHeader => match self.take_bytes(&mut buf, CHUNK_HEADER_SIZE) {
+                    Some(header) => self.parse_chunk_header(&header, sink)?,
+                    None => DecodeState::ChunkHeader,
+                },
+                DecodeState::Padding { remaining, next } => {
+                    let count = usize::min(remaining, buf.len());
+                    buf = &buf[count..];
+                    self.pos += count;
+                    if count == remaining {
+                        *next
+                    } else {
+                        DecodeState::Padding {
+                            remaining: remaining - count,
+                            next,
+                        }
+                    }
+                }
+                DecodeState::Raw { remaining } => {
+                    let count = u64::min(remaining, buf.len() as u64) as usize;
+                    sink.write(&buf[0..count])?;
+                    self.crc.update(&buf[0..count]);
+                    buf = &buf[count..];
+                    self.pos += count;
+                    self.written += count as u64;
+
+                    let remaining = remaining - count as u64;
+                    if remaining == 0 {
+                        DecodeState::ChunkHeader
+                    } else {
+                        DecodeState::Raw { remaining }
+                    }
+                }
+                DecodeState::Fill { len } => match self.take_bytes(&mut buf, 4) {
+                    Some(value) => {
+                        let value = [value[0], value[1], value[2], value[3]];
+                        trace!("sparse fill, value: {:?}, len: {}", value, len);
+                        sink.fill(value, len)?;
+                        self.update_crc_fill(value, len);
+                        self.written += len;
+                        DecodeState::ChunkHeader
+                    }
+                    None => DecodeState::Fill { len },
+                },
+                DecodeState::Crc32 => match self.take_bytes(&mut buf, 4) {
+                    Some(value) => {
+                        let expected = read_u32(&value);
+                        let actual = self.crc.clone().finalize();
+                        if expected != actual {
+                            return Err(self.format_error(format!(
+                                "sparse crc mismatch, expected: {:08X}, got: {:08X}",
+                                expected, actual
+                            )));
+                        }
+                        DecodeState::ChunkHeader
+                    }
+                    None => DecodeState::Crc32,
+                },
+            };
+        }
+        Ok(())
+    }
+
+    /// Checks that the sparse stream ended cleanly and produced the complete image.
+    pub fn finish(&self) -> Result<(), ArchiveError> {
+        if !matches!(self.state, DecodeState::ChunkHeader)
+            || !self.pending.is_empty()
+            || self.chunks_remaining != 0
+        {
+            return Err(self.format_error("sparse image ended mid-chunk".to_owned()));
+        }
+        if self.written != self.expanded_size() {
+            return Err(self.format_error(format!(
+                "sparse image produced {} bytes, expected {}",
+                self.written,
+                self.expanded_size()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Default for SparseDecoder {
+    fn default() -> Self {
+        SparseDecoder::new()
+    }
+}
+
+/// Returns true if the buffer starts with the sparse image magic.
+pub fn is_sparse(buf: &[u8]) -> bool {
+    buf.len() >= 4 && read_u32(buf) == SPARSE_MAGIC
+}
+
+#[derive(PartialEq)]
+enum ChunkKind {
+    Raw,
+    Fill([u8; 4]),
+    DontCare,
+}
+
+struct Chunk {
+    kind: ChunkKind,
+    blocks: u32,
+}
+
+fn classify_block(block: &[u8]) -> ChunkKind {
+    let value = [block[0], block[1], block[2], block[3]];
+    if block.chunks(4).all(|word| word == value) {
+        ChunkKind::Fill(value)
+    } else {
+        ChunkKind::Raw
+    }
+}
+
+fn write_chunk<W: Write>(out: &mut W, chunk: &Chunk, data: &[u8]) -> io::Result<()> {
+    let (chunk_type, payload): (u16, &[u8]) = match &chunk.kind {
+        ChunkKind::Raw => (CHUNK_TYPE_RAW, data),
+        ChunkKind::Fill(value) => (CHUNK_TYPE_FILL, value),
+        ChunkKind::DontCare => (CHUNK_TYPE_DONT_CARE, &[]),
+    };
+    out.write_all(&chunk_type.to_le_bytes())?;
+    out.write_all(&0u16.to_le_bytes())?;
+    out.write_all(&chunk.blocks.to_le_bytes())?;
+    out.write_all(&((CHUNK_HEADER_SIZE + payload.len()) as u32).to_le_bytes())?;
+    out.write_all(payload)
+}
+
+/// Converts a raw image into a sparse image. Holes in the input file become "don't care"
+/// chunks, and blocks of a repeated value become fill chunks. The image is padded with zeroes
+/// to a whole number of blocks.
+pub fn generate_sparse<W: Write + Seek>(input: &mut File, out: &mut W) -> io::Result<()> {
+    let block_size = SPARSE_BLOCK_SIZE as u64;
+    let len = input.metadata()?.len();
+    let total_blocks = len.div_ceil(block_size);
+    let regions = linux::data_regions(input)?;
+
+    let is_hole = |block: u64| {
+        let start = block * block_size;
+        let end = u64::min(start + block_size, len);
+        !regions
+            .iter()
+            .any(|(data_start, data_end)| *data_start < end && *data_end > start)
+    };
+
+    // the header is rewritten once the chunk count is known
+    let header_pos = out.stream_position()?;
+    out.write_all(&[0u8; FILE_HEADER_SIZE])?;
+
+    let mut total_chunks = 0u32;
+    let mut chunk: Option<Chunk> = None;
+    let mut data = Vec::new();
+    let mut block = vec![0u8; block_size as usize];
+    for block_idx in 0..total_blocks {
+        let kind = if is_hole(block_idx) {
+            ChunkKind::DontCare
+        } else {
+            block.iter_mut().for_each(|b| *b = 0);
+            input.seek(SeekFrom::Start(block_idx * block_size))?;
+            let count = usize::min(block.len(), (len - block_idx * block_size) as usize);
+            input.read_exact(&mut block[0..count])?;
+            classify_block(&block)
+        };
+
+        // extend the current chunk if possible, limiting the size of buffered raw data
+        if let Some(current) = &mut chunk {
+            if current.kind == kind && data.len() < 16 * 1024 * 1024 {
+                current.blocks += 1;
+                if kind == ChunkKind::Raw {
+                    data.extend_from_slice(&block);
+                }
+                continue;
+            }
+            write_chunk(out, current, &data)?;
+            total_chunks += 1;
+            data.clear();
+        }
+        if kind == ChunkKind::Raw {
+            data.extend_from_slice(&block);
+        }
+        chunk = Some(Chunk { kind, blocks: 1 });
+    }
+    if let Some(current) = &chunk {
+        write_chunk(out, current, &data)?;
+        total_chunks += 1;
+    }
+
+    let end_pos = out.stream_position()?;
+    out.seek(SeekFrom::Start(header_pos))?;
+    out.write_all(&SPARSE_MAGIC.to_le_bytes())?;
+    out.write_all(&MAJOR_VERSION.to_le_bytes())?;
+    out.write_all(&0u16.to_le_bytes())?;
+    out.write_all(&(FILE_HEADER_SIZE as u16).to_le_bytes())?;
+    out.write_all(&(CHUNK_HEADER_SIZE as u16).to_le_bytes())?;
+    out.write_all(&SPARSE_BLOCK_SIZE.to_le_bytes())?;
+    out.write_all(&(total_blocks as u32).to_le_bytes())?;
+    out.write_all(&total_chunks.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+    out.seek(SeekFrom::Start(end_pos))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use std::fs::{self, OpenOptions};
+    use std::io::Cursor;
+
+    struct VecSink {
+        out: Vec<u8>,
+        skipped: u64,
+    }
+
+    impl SparseSink for VecSink {
+        fn write(&mut self, buf: &[u8]) -> Result<(), ArchiveError> {
+            self.out.extend_from_slice(buf);
+            Ok(())
+        }
+
+        fn fill(&mut self, value: [u8; 4], len: u64) -> Result<(), ArchiveError> {
+            self.out
+                .extend(value.iter().cycle().take(len as usize).cloned());
+            Ok(())
+        }
+
+        fn skip(&mut self, len: u64) -> Result<(), ArchiveError> {
+            self.out.extend(std::iter::repeat_n(0u8, len as usize));
+            self.skipped += len;
+            Ok(())
+        }
+    }
+
+    fn expand(sparse: &[u8], chunk_size: usize) -> VecSink {
+        let mut decoder = SparseDecoder::new();
+        let mut sink = VecSink {
+            out: Vec::new(),
+            skipped: 0,
+        };
+        for chunk in sparse.chunks(chunk_size) {
+            decoder.feed(chunk, &mut sink).unwrap();
+        }
+        decoder.finish().unwrap();
+        sink
+    }
+
+    fn chunk(chunk_type: u16, blocks: u32, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&chunk_type.to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        buf.extend_from_slice(&blocks.to_le_bytes());
+        buf.extend_from_slice(&((CHUNK_HEADER_SIZE + data.len()) as u32).to_le_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    fn header(block_size: u32, blocks: u32, chunks: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&SPARSE_MAGIC.to_le_bytes());
+        buf.extend_from_slice(&1u16.to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        buf.extend_from_slice(&(FILE_HEADER_SIZE as u16).to_le_bytes());
+        buf.extend_from_slice(&(CHUNK_HEADER_SIZE as u16).to_le_bytes());
+        buf.extend_from_slice(&block_size.to_le_bytes());
+        buf.extend_from_slice(&blocks.to_le_bytes());
+        buf.extend_from_slice(&chunks.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn all_chunk_types() {
+        init_logging();
+        let raw: Vec<u8> = (0..16u8).collect();
+
+        let mut expected = raw.clone();
+        expected.extend_from_slice(&[1, 2, 3, 4].repeat(4));
+        expected.extend_from_slice(&[0u8; 16]);
+        let mut crc = Hasher::new();
+        crc.update(&expected);
+
+        let mut sparse = header(16, 3, 4);
+        sparse.extend(chunk(CHUNK_TYPE_RAW, 1, &raw));
+        sparse.extend(chunk(CHUNK_TYPE_FILL, 1, &[1, 2, 3, 4]));
+        sparse.extend(chunk(CHUNK_TYPE_DONT_CARE, 1, &[]));
+        sparse.extend(chunk(CHUNK_TYPE_CRC32, 0, &crc.finalize().to_le_bytes()));
+
+        for chunk_size in [1, 5, sparse.len()] {
+            let sink = expand(&sparse, chunk_size);
+            assert_eq!(sink.out, expected);
+            assert_eq!(sink.skipped, 16);
+        }
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        init_logging();
+        let mut sparse = header(16, 1, 2);
+        sparse.extend(chunk(CHUNK_TYPE_FILL, 1, &[1, 2, 3, 4]));
+        sparse.extend(chunk(CHUNK_TYPE_CRC32, 0, &[0, 0, 0, 0]));
+
+        let mut sink = VecSink {
+            out: Vec::new(),
+            skipped: 0,
+        };
+        let err = SparseDecoder::new().feed(&sparse, &mut sink).unwrap_err();
+        assert!(matches!(err, ArchiveError::FormatError { .. }));
+    }
+
+    #[test]
+    fn chunk_overflow() {
+        init_logging();
+        let mut sparse = header(16, 1, 1);
+        sparse.extend(chunk(CHUNK_TYPE_DONT_CARE, 2, &[]));
+
+        let mut sink = VecSink {
+            out: Vec::new(),
+            skipped: 0,
+        };
+        assert!(SparseDecoder::new().feed(&sparse, &mut sink).is_err());
+    }
+
+    #[test]
+    fn generate_roundtrip() {
+        init_logging();
+        // build an image with a hole in the middle and a zero-filled block at the end
+        let path = make_tempfile_path();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let image = fs::read(test_path("archive/test-img-larger.img")).unwrap();
+        file.write_all(&image).unwrap();
+        file.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+        file.write_all(&image).unwrap();
+        file.write_all(&[0u8; 8192]).unwrap();
+
+        let mut sparse = Cursor::new(Vec::new());
+        generate_sparse(&mut file, &mut sparse).unwrap();
+        let sparse = sparse.into_inner();
+        assert!(is_sparse(&sparse));
+        assert!(sparse.len() < 64 * 1024);
+
+        let mut expected = fs::read(&path).unwrap();
+        let padded_len = expected.len().div_ceil(4096) * 4096;
+        expected.resize(padded_len, 0);
+        assert_eq!(expand(&sparse, 1000).out, expected);
+    }
+}
@@ -0,0 +1,294 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::archive::{Archive, ArchiveError};
+use crate::http_reader::{HttpError, HttpReader};
+use crate::linux;
+
+// the staged archive may be in any container format, so the name has no format extension
+const STAGED_FILENAME: &str = "update.archive";
+const PARTIAL_FILENAME: &str = "update.archive.partial";
+
+// records what the partial download is, so it's only resumed from the same source
+const DOWNLOAD_INFO_FILENAME: &str = "download.json";
+
+#[derive(Error, Debug)]
+pub enum StagingError {
+    #[error("staging: http error, cause: {source}")]
+    HttpError {
+        #[from]
+        source: HttpError,
+    },
+
+    #[error("staging: archive error, cause: {source}")]
+    ArchiveError {
+        #[from]
+        source: ArchiveError,
+    },
+
+    #[error("staging: io error {context}, cause: {source}")]
+    IOError { source: io::Error, context: String },
+
+    #[error(
+        "staging: not enough space in {path}, {required} bytes required, {available} available"
+    )]
+    InsufficientSpace {
+        path: String,
+        required: u64,
+        available: u64,
+    },
+}
+
+/// How an archive is installed. Streamed archives are written to the inactive slot as they're
+/// downloaded, staged archives are downloaded and verified in full first.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum InstallStrategy {
+    #[default]
+    Stream,
+    Staged,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+struct DownloadInfo {
+    url: String,
+    content_length: u64,
+}
+
+/// Verifies all the checksums in an archive file, without deploying it.
+pub fn verify_archive(path: &Path) -> Result<(), StagingError> {
+    let file = File::open(path).map_err(|err| StagingError::IOError {
+        source: err,
+        context: format!("opening archive: {}", path.display()),
+    })?;
+    let archive = Archive::new(file)?;
+    archive.verify()?;
+    Ok(())
+}
+
+/// A directory on persistent storage, which archives are downloaded to before installing.
+pub struct Staging {
+    dir: PathBuf,
+}
+
+impl Staging {
+    pub fn new(dir: &Path) -> Staging {
+        Staging {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn map_err<'p>(&self, path: &'p Path) -> impl Fn(io::Error) -> StagingError + 'p {
+        move |err| StagingError::IOError {
+            source: err,
+            context: format!("staging file: {}", path.display()),
+        }
+    }
+
+    // returns the offset to resume the download from, 0 if there's no matching partial file
+    fn resume_offset(&self, info: &DownloadInfo) -> u64 {
+        let existing = fs::read_to_string(self.dir.join(DOWNLOAD_INFO_FILENAME))
+            .ok()
+            .and_then(|buf| serde_json::from_str::<DownloadInfo>(&buf).ok());
+        if existing.as_ref() != Some(info) {
+            return 0;
+        }
+        match fs::metadata(self.dir.join(PARTIAL_FILENAME)) {
+            Ok(metadata) if metadata.len() <= info.content_length => metadata.len(),
+            _ => 0,
+        }
+    }
+
+    /// Downloads the archive to the staging directory, resuming any previous partial download
+    /// of the same url. The reader is used as configured, e.g. with a cancel token.
+    pub fn download<F: FnMut(u64, u64)>(
+        &self,
+        url: &str,
+        mut reader: HttpReader,
+        mut progress: F,
+    ) -> Result<PathBuf, StagingError> {
+        fs::create_dir_all(&self.dir).map_err(self.map_err(&self.dir))?;
+        let info = DownloadInfo {
+            url: url.to_owned(),
+            content_length: reader.content_length(),
+        };
+        let offset = self.resume_offset(&info);
+        let partial_path = self.dir.join(PARTIAL_FILENAME);
+        let staged_path = self.dir.join(STAGED_FILENAME);
+
+        // an archive from a previous download is replaced
+        let required = info.content_length - offset;
+        let available = linux::available_space(&self.dir).map_err(self.map_err(&self.dir))?
+            + fs::metadata(&staged_path)
+                .map(|meta| meta.len())
+                .unwrap_or(0);
+        if available < required {
+            return Err(StagingError::InsufficientSpace {
+                path: self.dir.display().to_string(),
+                required,
+                available,
+            });
+        }
+        let _ = fs::remove_file(&staged_path);
+
+        let info_path = self.dir.join(DOWNLOAD_INFO_FILENAME);
+        fs::write(&info_path, serde_json::to_string(&info).unwrap())
+            .map_err(self.map_err(&info_path))?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&partial_path)
+            .map_err(self.map_err(&partial_path))?;
+        file.set_len(offset).map_err(self.map_err(&partial_path))?;
+        if offset > 0 {
+            info!("resuming download of {} from offset {}", url, offset);
+            reader.resume_from(offset);
+        }
+
+        let mut downloaded = offset;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let count = reader.read(&mut buf).map_err(|err| StagingError::IOError {
+                source: err,
+                context: format!("downloading {}", url),
+            })?;
+            if count == 0 {
+                break;
+            }
+            file.write_all(&buf[..count])
+                .map_err(self.map_err(&partial_path))?;
+            downloaded += count as u64;
+            progress(downloaded, info.content_length);
+        }
+        file.sync_all().map_err(self.map_err(&partial_path))?;
+
+        fs::rename(&partial_path, &staged_path).map_err(self.map_err(&staged_path))?;
+        let _ = fs::remove_file(&info_path);
+        debug!("downloaded {} to {}", url, staged_path.display());
+        Ok(staged_path)
+    }
+
+    /// Verifies all the checksums in a staged archive, removing it if it is corrupt.
+    pub fn verify(&self, path: &Path) -> Result<(), StagingError> {
+        verify_archive(path).inspect_err(|_| {
+            warn!("staged archive {} is invalid, removing", path.display());
+            let _ = fs::remove_file(path);
+        })
+    }
+
+    /// Removes any staged or partially downloaded archive.
+    pub fn clear(&self) {
+        for filename in [STAGED_FILENAME, PARTIAL_FILENAME, DOWNLOAD_INFO_FILENAME].iter() {
+            let _ = fs::remove_file(self.dir.join(filename));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_server::*;
+    use crate::test_utils::*;
+    use std::time::Duration;
+
+    fn serve_archive(root: &Path) -> Vec<u8> {
+        let manifest = format!(
+            r#"{{ "payloads": [ {{ "type": "image", "filename": "rootfs.img", "dest": "{}" }} ] }}"#,
+            make_tempfile_path().display()
+        );
+        let image = fs::read(test_path("archive/test-img-larger.img")).unwrap();
+        let archive = make_archive(&manifest, &[("rootfs.img", &image)]);
+        fs::create_dir_all(root).unwrap();
+        fs::write(root.join("update.cpio"), &archive).unwrap();
+        archive
+    }
+
+    #[test]
+    fn download_and_verify() {
+        init_logging();
+        let root = make_tempfile_path();
+        let archive = serve_archive(&root);
+        let server = create_test_server(TestServerArgs::new(root.to_str().unwrap()));
+        let url = format!("http://127.0.0.1:{}/update.cpio", server.port);
+
+        let staging = Staging::new(&make_tempfile_path());
+        let reader = HttpReader::new(&url, Duration::from_secs(5)).unwrap();
+        let mut last_progress = 0;
+        let path = staging
+            .download(&url, reader, |downloaded, _| last_progress = downloaded)
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), archive);
+        assert_eq!(last_progress, archive.len() as u64);
+        staging.verify(&path).unwrap();
+
+        staging.clear();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn resume_partial_download() {
+        init_logging();
+        let root = make_tempfile_path();
+        let archive = serve_archive(&root);
+        let server = create_test_server(TestServerArgs::new(root.to_str().unwrap()));
+        let url = format!("http://127.0.0.1:{}/update.cpio", server.port);
+
+        // a previous download was interrupted part way through
+        let dir = make_tempfile_path();
+        fs::create_dir_all(&dir).unwrap();
+        let info = DownloadInfo {
+            url: url.clone(),
+            content_length: archive.len() as u64,
+        };
+        fs::write(
+            dir.join(DOWNLOAD_INFO_FILENAME),
+            serde_json::to_string(&info).unwrap(),
+        )
+        .unwrap();
+        fs::write(dir.join(PARTIAL_FILENAME), &archive[..5000]).unwrap();
+
+        let staging = Staging::new(&dir);
+        let reader = HttpReader::new(&url, Duration::from_secs(5)).unwrap();
+        let mut first_progress = None;
+        let path = staging
+            .download(&url, reader, |downloaded, _| {
+                first_progress.get_or_insert(downloaded);
+            })
+            .unwrap();
+        assert!(first_progress.unwrap() > 5000);
+        assert_eq!(fs::read(&path).unwrap(), archive);
+    }
+
+    #[test]
+    fn corrupt_archive_removed() {
+        init_logging();
+        let root = make_tempfile_path();
+        let mut archive = serve_archive(&root);
+        let len = archive.len();
+        archive[len - 600] ^= 0xFF;
+        let path = root.join("corrupt.cpio");
+        fs::write(&path, &archive).unwrap();
+
+        let staging = Staging::new(&root);
+        let err = staging.verify(&path).unwrap_err();
+        assert!(matches!(
+            err,
+            StagingError::ArchiveError {
+                source: ArchiveError::ChecksumMismatchError { .. }
+            }
+        ));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn free_space() {
+        let available = linux::available_space(Path::new("/tmp")).unwrap();
+        assert!(available > 0);
+    }
+}
@@ -0,0 +1,458 @@
+use std::cell;
+use std::io;
+use std::io::Read;
+use std::str;
+
+use log::*;
+
+use crate::archive::ArchiveError;
+use crate::checksum::*;
+use crate::container::{ArchiveEntry, EntryKind, PosReader};
+
+const BLOCK_SIZE: usize = 512;
+
+// pax and gnu long name headers are read into memory
+const MAX_EXTENDED_HEADER: u64 = 64 * 1024;
+
+const USTAR_MAGIC: &[u8] = b"ustar\0";
+const GNU_MAGIC: &[u8] = b"ustar ";
+
+//#[derive(Debug)]
+pub struct TarFile<'a, R: io::Read> {
+    pub filename: String,
+    pub filesize: u64,
+    pub kind: EntryKind,
+    // permission bits only, the file type is in kind
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+    remaining: u64,
+    reader: &'a cell::RefCell<PosReader<R>>,
+    cksum: Checksum,
+}
+
+impl<'a, R: io::Read> io::Read for TarFile<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.borrow_mut();
+
+        // maximum to read is the end of the contained file
+        let max_read = u64::min(buf.len() as u64, self.remaining) as usize;
+        let bytes_read = reader.read(&mut buf[0..max_read])?;
+        self.remaining -= bytes_read as u64;
+
+        // update the running checksum
+        self.cksum.update(&buf[0..bytes_read]);
+        Ok(bytes_read)
+    }
+}
+
+impl<'a, R: io::Read> ArchiveEntry for TarFile<'a, R> {
+    fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    fn filesize(&self) -> u64 {
+        self.filesize
+    }
+
+    fn kind(&self) -> &EntryKind {
+        &self.kind
+    }
+
+    fn finalise(&mut self, cksum_expected: Checksum) -> Result<(), ArchiveError> {
+        assert_eq!(self.remaining, 0);
+
+        self.cksum.finalise();
+        if self.cksum != cksum_expected {
+            return Err(ArchiveError::ChecksumMismatchError {
+                filename: self.filename.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn map_read_err(count: usize) -> impl FnOnce(io::Error) -> ArchiveError {
+    move |err| ArchiveError::IOError {
+        source: err,
+        context: format!("tar reader, pos: {}", count),
+    }
+}
+
+fn parse_error<E: std::error::Error + 'static>(err: E) -> ArchiveError {
+    ArchiveError::ParseError(Box::new(err))
+}
+
+// a nul terminated string field, which may fill the whole field
+fn parse_str(field: &[u8]) -> Result<&str, ArchiveError> {
+    let len = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+    str::from_utf8(&field[..len]).map_err(parse_error)
+}
+
+// numeric fields are octal, padded with nuls or spaces. gnu tar stores values which don't fit
+// as big endian base-256, flagged by the high bit of the first byte.
+fn parse_number(field: &[u8]) -> Result<u64, ArchiveError> {
+    if field[0] & 0x80 != 0 {
+        let mut val: u64 = (field[0] & 0x7f) as u64;
+        for byte in &field[1..] {
+            val = val
+                .checked_mul(256)
+                .and_then(|val| val.checked_add(*byte as u64))
+                .ok_or_else(|| ArchiveError::ParseError("tar number overflow".into()))?;
+        }
+        return Ok(val);
+    }
+    let octstr = parse_str(field)?.trim_matches(|c| c == ' ' || c == '\0');
+    if octstr.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(octstr, 8).map_err(parse_error)
+}
+
+// the header checksum is the sum of the header bytes, with the checksum field read as spaces
+fn header_checksum_ok(header: &[u8; BLOCK_SIZE]) -> Result<bool, ArchiveError> {
+    let expected = parse_number(&header[148..156])?;
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(idx, byte)| {
+            if (148..156).contains(&idx) {
+                b' ' as u64
+            } else {
+                *byte as u64
+            }
+        })
+        .sum();
+    Ok(sum == expected)
+}
+
+/// Values from pax extended headers and gnu long name entries, which override the fields of
+/// the next header.
+#[derive(Default)]
+struct Overrides {
+    path: Option<String>,
+    linkpath: Option<String>,
+    size: Option<u64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mtime: Option<u64>,
+}
+
+impl Overrides {
+    // records are "<len> <key>=<value>\n", where len counts the whole record
+    fn parse_pax(&mut self, data: &[u8]) -> Result<(), ArchiveError> {
+        let invalid = || ArchiveError::ParseError("invalid pax extended header".into());
+        let mut data = data;
+        while !data.is_empty() {
+            let space = data
+                .iter()
+                .position(|byte| *byte == b' ')
+                .ok_or_else(invalid)?;
+            let len: usize = str::from_utf8(&data[..space])
+                .map_err(parse_error)?
+                .parse()
+                .map_err(parse_error)?;
+            if len <= space + 1 || len > data.len() || data[len - 1] != b'\n' {
+                return Err(invalid());
+            }
+            let record = str::from_utf8(&data[space + 1..len - 1]).map_err(parse_error)?;
+            let (key, value) = record.split_once('=').ok_or_else(invalid)?;
+            trace!("pax record: {}={}", key, value);
+            match key {
+                "path" => self.path = Some(value.to_owned()),
+                "linkpath" => self.linkpath = Some(value.to_owned()),
+                "size" => self.size = Some(value.parse().map_err(parse_error)?),
+                "uid" => self.uid = Some(value.parse().map_err(parse_error)?),
+                "gid" => self.gid = Some(value.parse().map_err(parse_error)?),
+                // times may have a fractional part
+                "mtime" => {
+                    let secs = value.split('.').next().unwrap_or_default();
+                    self.mtime = Some(secs.parse().map_err(parse_error)?);
+                }
+                _ => (),
+            }
+            data = &data[len..];
+        }
+        Ok(())
+    }
+}
+
+/// Reads ustar archives, with pax extended headers and gnu long names. Entries are streamed,
+/// as with CpioReader.
+pub struct TarReader<R: io::Read> {
+    reader: cell::RefCell<PosReader<R>>,
+}
+
+impl<'a, R: io::Read> TarReader<R> {
+    pub fn new(reader: R) -> TarReader<R> {
+        TarReader {
+            reader: cell::RefCell::new(PosReader {
+                count: 0,
+                inner: reader,
+            }),
+        }
+    }
+
+    // the data of each entry is padded to the block size
+    fn skip_padding(reader: &mut cell::RefMut<PosReader<R>>) -> Result<(), ArchiveError> {
+        let mut trailing_buf = [0u8; BLOCK_SIZE];
+        let trailing = (BLOCK_SIZE - (reader.count % BLOCK_SIZE)) % BLOCK_SIZE;
+        reader
+            .read_exact(&mut trailing_buf[0..trailing])
+            .map_err(map_read_err(reader.count))?;
+        Ok(())
+    }
+
+    fn read_extended_data(
+        reader: &mut cell::RefMut<PosReader<R>>,
+        size: u64,
+    ) -> Result<Vec<u8>, ArchiveError> {
+        if size > MAX_EXTENDED_HEADER {
+            return Err(ArchiveError::FormatError {
+                offset: reader.count,
+                reason: format!("extended header too large: {}", size),
+            });
+        }
+        let mut data = vec![0u8; size as usize];
+        reader
+            .read_exact(&mut data)
+            .map_err(map_read_err(reader.count))?;
+        Ok(data)
+    }
+
+    pub fn read_next_file(&'a self) -> Result<Option<TarFile<'a, R>>, ArchiveError> {
+        // the previous file needs to be completely read before we get here, otherwise the
+        // header checksum will fail
+        let mut reader = self.reader.borrow_mut();
+        let mut overrides = Overrides::default();
+
+        loop {
+            Self::skip_padding(&mut reader)?;
+
+            let mut header = [0u8; BLOCK_SIZE];
+            reader
+                .read_exact(&mut header)
+                .map_err(map_read_err(reader.count))?;
+
+            // the archive ends with two zero blocks, the first is enough to stop reading
+            if header.iter().all(|byte| *byte == 0) {
+                return Ok(None);
+            }
+
+            let magic = &header[257..263];
+            if magic != USTAR_MAGIC && magic != GNU_MAGIC {
+                return Err(ArchiveError::FormatError {
+                    offset: reader.count,
+                    reason: "magic number mismatch".to_owned(),
+                });
+            }
+            if !header_checksum_ok(&header)? {
+                return Err(ArchiveError::FormatError {
+                    offset: reader.count,
+                    reason: "header checksum mismatch".to_owned(),
+                });
+            }
+
+            let typeflag = header[156];
+            let size = match overrides.size {
+                Some(size) => size,
+                None => parse_number(&header[124..136])?,
+            };
+            match typeflag {
+                b'x' => {
+                    let data = Self::read_extended_data(&mut reader, size)?;
+                    overrides.parse_pax(&data)?;
+                    continue;
+                }
+                // global headers apply to the whole archive, none of their fields are needed
+                b'g' => {
+                    Self::read_extended_data(&mut reader, size)?;
+                    continue;
+                }
+                b'L' | b'K' => {
+                    let data = Self::read_extended_data(&mut reader, size)?;
+                    let name = parse_str(&data)?.to_owned();
+                    if typeflag == b'L' {
+                        overrides.path = Some(name);
+                    } else {
+                        overrides.linkpath = Some(name);
+                    }
+                    continue;
+                }
+                _ => (),
+            }
+
+            let filename = match overrides.path.take() {
+                Some(path) => path,
+                None => {
+                    let name = parse_str(&header[0..100])?;
+                    // gnu tar uses the prefix field for other values
+                    let prefix = match magic {
+                        USTAR_MAGIC => parse_str(&header[345..500])?,
+                        _ => "",
+                    };
+                    if prefix.is_empty() {
+                        name.to_owned()
+                    } else {
+                        format!("{}/{}", prefix, name)
+                    }
+                }
+            };
+            // directories are named with a trailing slash
+            let filename = filename.trim_end_matches('/').to_owned();
+            debug!("filename: {}", filename);
+
+            let linkname = match overrides.linkpath.take() {
+                Some(linkpath) => linkpath,
+                None => parse_str(&header[157..257])?.to_owned(),
+            };
+            let device = || -> Result<(u32, u32), ArchiveError> {
+                Ok((
+                    parse_number(&header[329..337])? as u32,
+                    parse_number(&header[337..345])? as u32,
+                ))
+            };
+            let kind = match typeflag {
+                b'0' | b'\0' | b'7' => EntryKind::File,
+                b'1' => EntryKind::Hardlink { target: linkname },
+                b'2' => EntryKind::Symlink { target: linkname },
+                b'3' => {
+                    let (major, minor) = device()?;
+                    EntryKind::CharDevice { major, minor }
+                }
+                b'4' => {
+                    let (major, minor) = device()?;
+                    EntryKind::BlockDevice { major, minor }
+                }
+                b'5' => EntryKind::Dir,
+                b'6' => EntryKind::Fifo,
+                _ => {
+                    return Err(ArchiveError::FormatError {
+                        offset: reader.count,
+                        reason: format!(
+                            "unsupported entry type {} for {}",
+                            typeflag as char, filename
+                        ),
+                    })
+                }
+            };
+
+            let mode = parse_number(&header[100..108])? as u32 & 0o7777;
+            let uid = match overrides.uid {
+                Some(uid) => uid,
+                None => parse_number(&header[108..116])? as u32,
+            };
+            let gid = match overrides.gid {
+                Some(gid) => gid,
+                None => parse_number(&header[116..124])? as u32,
+            };
+            let mtime = match overrides.mtime {
+                Some(mtime) => mtime,
+                None => parse_number(&header[136..148])?,
+            };
+
+            return Ok(Some(TarFile {
+                filename,
+                filesize: size,
+                kind,
+                mode,
+                uid,
+                gid,
+                mtime,
+                remaining: size,
+                reader: &self.reader,
+                cksum: Checksum::new_hashable(),
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use std::collections::HashMap;
+    use std::fs;
+
+    // kind, mode and data of each entry, by filename
+    type Entries = HashMap<String, (EntryKind, u32, Vec<u8>)>;
+
+    fn read_entries(data: &[u8]) -> Result<Entries, ArchiveError> {
+        let reader = TarReader::new(data);
+        let mut entries = HashMap::new();
+        while let Some(mut file) = reader.read_next_file()? {
+            let mut buf = Vec::new();
+            file.read_to_end(&mut buf).unwrap();
+            assert_eq!((file.uid, file.gid), (1000, 1000));
+            assert_eq!(file.mtime, 1635120000);
+            entries.insert(file.filename.clone(), (file.kind.clone(), file.mode, buf));
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn entry_types() {
+        init_logging();
+        let long_name = "dir/a-directory-with-a-name-long-enough-that-the-full-path-needs-more-\
+                         than-one-hundred-bytes/file";
+        for name in ["entry-types.tar", "entry-types-gnu.tar"].iter() {
+            let data = fs::read(test_path(format!("tar/{}", name))).unwrap();
+            let entries = read_entries(&data).unwrap();
+
+            assert_eq!(entries["dir"].0, EntryKind::Dir, "{}", name);
+            assert_eq!(entries["dir"].1, 0o750);
+            assert_eq!(entries["dir/file"].0, EntryKind::File);
+            assert_eq!(entries["dir/file"].2, b"hello\n");
+            assert_eq!(entries[long_name].2, b"long\n");
+            assert_eq!(
+                entries["dir/link"].0,
+                EntryKind::Hardlink {
+                    target: String::from("dir/file")
+                }
+            );
+            assert_eq!(
+                entries["dir/symlink"].0,
+                EntryKind::Symlink {
+                    target: String::from("file")
+                }
+            );
+            assert_eq!(
+                entries["dir/null"].0,
+                EntryKind::CharDevice { major: 1, minor: 3 }
+            );
+            assert_eq!(
+                entries["dir/loop"].0,
+                EntryKind::BlockDevice { major: 7, minor: 0 }
+            );
+            assert_eq!(entries["dir/fifo"].0, EntryKind::Fifo);
+        }
+    }
+
+    #[test]
+    fn corrupt_header() {
+        init_logging();
+        let mut data = fs::read(test_path("tar/entry-types.tar")).unwrap();
+        data[0] ^= 0xFF;
+        let err = read_entries(&data).unwrap_err();
+        assert!(matches!(err, ArchiveError::FormatError { .. }));
+
+        assert!(matches!(
+            read_entries(&[]).unwrap_err(),
+            ArchiveError::IOError { .. }
+        ));
+    }
+
+    #[test]
+    fn base256_numbers() {
+        let mut field = [0u8; 12];
+        field[0] = 0x80;
+        field[7] = 0x02;
+        assert_eq!(parse_number(&field).unwrap(), 0x02_0000_0000);
+        assert_eq!(parse_number(b"00000000012\0").unwrap(), 10);
+        assert_eq!(parse_number(b"     12 \0").unwrap(), 10);
+    }
+}
@@ -1,70 +1,99 @@
 use std::{
-    collections::HashSet,
-    io::BufRead,
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
     path::PathBuf,
-    process::{Child, Command, Stdio},
-    sync::Mutex,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
 };
 
 use log::debug;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
 
-use crate::{
-    linux::{self},
-    test_utils::test_path,
-};
-
-lazy_static! {
-    static ref SERVER_PORTS: Mutex<HashSet<u32>> = Mutex::new(HashSet::new());
-}
+use crate::test_utils::test_path;
 
-fn get_server_port() -> u32 {
-    // loop from 8000 until we get a free port
-    // TODO: this should really look at available ports on the system
-    let mut ports = SERVER_PORTS.lock().unwrap();
-    for port in 8000u32..=8080 {
-        if !ports.contains(&port) {
-            ports.insert(port);
-            return port;
-        }
-    }
-    panic!("all test server ports are in use")
+/// A request received by the test server.
+#[derive(Debug)]
+struct TestRequest {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
 }
 
-fn free_server_port(port: u32) {
-    let mut ports = SERVER_PORTS.lock().unwrap();
-    if !ports.contains(&port) {
-        panic!("server port was not found in map");
-    }
-    ports.remove(&port);
+struct ServerState {
+    server_root: PathBuf,
+    response_latency: Option<f32>,
+    tls: Mutex<Option<SslAcceptor>>,
+    // when set, requests must have a matching bearer token or token query parameter
+    token: Mutex<Option<String>>,
+    ignore_ranges: AtomicBool,
+    truncate_bodies: AtomicBool,
+    connections: AtomicUsize,
+    requests: AtomicUsize,
+    shutdown: AtomicBool,
 }
 
+/// An http server which serves files from a root directory, supporting HEAD requests and
+/// single range GET requests. POST request bodies are written to the file at the path. It stands in for an update server in tests, and for a proxy, as
+/// requests for absolute urls are served from the same root.
 pub struct TestServer {
-    process: Child,
+    state: Arc<ServerState>,
     pub port: u32,
 }
 
-impl Drop for TestServer {
-    fn drop(&mut self) {
-        // send the INT signal, which is how the process is usually stopped on a terminal
-        linux::signal(&self.process, linux::Signal::INT);
-        // TODO: this should wait for some time and then send a SIGKILL if it hasn't terminated yet
-        // currently it will just block
-        let ret_code = self
-            .process
-            .wait()
-            .expect("failed to wait for server process to exit");
+impl TestServer {
+    /// Requires a token for all requests from now on, requests with any other token get 401.
+    pub fn set_token(&self, token: &str) {
+        *self.state.token.lock().unwrap() = Some(token.to_owned());
+    }
+
+    /// Responds to range requests from now on with the whole file, as a server without range
+    /// support would.
+    pub fn ignore_ranges(&self) {
+        self.state.ignore_ranges.store(true, Ordering::SeqCst);
+    }
+
+    /// Closes the connection half way through each response body from now on.
+    pub fn truncate_bodies(&self) {
+        self.state.truncate_bodies.store(true, Ordering::SeqCst);
+    }
+
+    /// Serves connections accepted from now on with the cert and key in test/tls, e.g.
+    /// other-server.pem, which isn't signed by ca.pem.
+    pub fn set_tls_cert(&self, cert: &str, key: &str) {
+        *self.state.tls.lock().unwrap() = Some(create_acceptor(false, cert, key));
+    }
+
+    /// The number of connections accepted so far.
+    pub fn connection_count(&self) -> usize {
+        self.state.connections.load(Ordering::SeqCst)
+    }
 
-        free_server_port(self.port);
+    /// The number of requests received so far.
+    pub fn request_count(&self) -> usize {
+        self.state.requests.load(Ordering::SeqCst)
+    }
+}
 
-        debug!("server exited with ret code: {}", ret_code);
-        // we don't assert a zero exit code, because rust returns a None exit code if the process
-        // was killed by a signal
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.state.shutdown.store(true, Ordering::SeqCst);
+        // wake the accept loop so it sees the shutdown flag
+        let _ = TcpStream::connect(("127.0.0.1", self.port as u16));
+        debug!("test server on port {} stopped", self.port);
     }
 }
 
 pub struct TestServerArgs {
     server_root: PathBuf,
     response_latency: Option<f32>,
+    tls: Option<bool>,
 }
 
 impl TestServerArgs {
@@ -72,6 +101,7 @@ impl TestServerArgs {
         TestServerArgs {
             server_root: PathBuf::from(server_root),
             response_latency: None,
+            tls: None,
         }
     }
 
@@ -79,51 +109,255 @@ impl TestServerArgs {
         self.response_latency = Some(latency);
         self
     }
+
+    /// Serves https with the certs in test/tls, the server cert is signed by ca.pem. Clients
+    /// must present a cert signed by the same ca if client certs are required.
+    pub fn tls(&mut self, require_client_cert: bool) -> &Self {
+        self.tls = Some(require_client_cert);
+        self
+    }
 }
 
-pub fn create_test_server(args: TestServerArgs) -> TestServer {
-    let server_port = get_server_port();
-    let server_module = test_path("http-server");
-    let server_root = test_path(args.server_root);
+fn create_acceptor(require_client_cert: bool, cert: &str, key: &str) -> SslAcceptor {
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    acceptor
+        .set_private_key_file(test_path(format!("tls/{}", key)), SslFiletype::PEM)
+        .unwrap();
+    acceptor
+        .set_certificate_chain_file(test_path(format!("tls/{}", cert)))
+        .unwrap();
+    if require_client_cert {
+        acceptor.set_ca_file(test_path("tls/ca.pem")).unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    acceptor.build()
+}
 
-    debug!(
-        "starting test server on port: {}, root path: {}",
-        server_port,
-        server_root.to_str().unwrap()
-    );
+fn read_request<S: Read>(reader: &mut BufReader<S>) -> io::Result<Option<TestRequest>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let path = parts.next().unwrap_or_default().to_owned();
 
-    let mut server = Command::new("python3");
-    server
-        .env("PYTHONPATH", server_module)
-        .arg("-u")
-        .arg("-m")
-        .arg("RangeHTTPServer")
-        .arg(server_port.to_string());
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_owned());
+        }
+    }
+
+    let content_length = headers
+        .get("content-length")
+        .and_then(|len| len.parse().ok())
+        .unwrap_or(0u64);
+    let mut body = Vec::new();
+    reader.take(content_length).read_to_end(&mut body)?;
+
+    Ok(Some(TestRequest {
+        method,
+        path,
+        headers,
+        body,
+    }))
+}
+
+fn parse_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let start: u64 = start.parse().ok()?;
+    let end = match end {
+        "" => len - 1,
+        end => u64::min(end.parse().ok()?, len - 1),
+    };
+    if start > end {
+        return None;
+    }
+    Some((start, end))
+}
 
-    if let Some(latency) = args.response_latency {
-        server.arg("--response-latency").arg(latency.to_string());
+fn write_response<S: Write>(
+    stream: &mut S,
+    status: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> io::Result<()> {
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
     }
+    response.push_str("\r\n");
+    stream.write_all(response.as_bytes())?;
+    stream.write_all(body)
+}
 
-    server
-        .current_dir(server_root)
-        .stdout(Stdio::piped())
-        // the server logs requests to stderr, which becomes noise in the test output
-        .stderr(Stdio::null());
+fn handle_request<S: Write>(
+    state: &ServerState,
+    stream: &mut S,
+    request: &TestRequest,
+) -> io::Result<()> {
+    // proxied requests are for absolute urls
+    let path = match request.path.split_once("://") {
+        Some((_, url)) => &url[url.find('/').unwrap_or(url.len())..],
+        None => &request.path,
+    };
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    if let Some(token) = &*state.token.lock().unwrap() {
+        let bearer = format!("Bearer {}", token);
+        let param = format!("token={}", token);
+        let authorized = request.headers.get("authorization") == Some(&bearer)
+            || query.split('&').any(|pair| pair == param);
+        if !authorized {
+            return write_response(
+                stream,
+                "401 Unauthorized",
+                &[("Content-Length", String::from("0"))],
+                &[],
+            );
+        }
+    }
+    let file_path = state.server_root.join(path.trim_start_matches('/'));
+    if request.method == "POST" {
+        fs::write(&file_path, &request.body)?;
+        return write_response(
+            stream,
+            "204 No Content",
+            &[("Content-Length", String::from("0"))],
+            &[],
+        );
+    }
+    let data = match fs::read(&file_path) {
+        Ok(data) => data,
+        Err(_) => {
+            return write_response(
+                stream,
+                "404 Not Found",
+                &[("Content-Length", String::from("0"))],
+                &[],
+            )
+        }
+    };
 
-    // start the server
-    let mut server = server.spawn().expect("failed to start server process");
+    let range = request
+        .headers
+        .get("range")
+        .filter(|_| !state.ignore_ranges.load(Ordering::SeqCst))
+        .and_then(|range| parse_range(range, data.len() as u64));
+    let (status, body, mut headers) = match range {
+        Some((start, end)) => (
+            "206 Partial Content",
+            &data[start as usize..=end as usize],
+            vec![(
+                "Content-Range",
+                format!("bytes {}-{}/{}", start, end, data.len()),
+            )],
+        ),
+        None => ("200 OK", &data[..], Vec::new()),
+    };
+    headers.push(("Content-Length", body.len().to_string()));
+    headers.push(("Accept-Ranges", String::from("bytes")));
 
-    let stdout = server.stdout.as_mut().unwrap();
-    let mut reader = std::io::BufReader::new(stdout);
+    let body = if request.method == "HEAD" { &[] } else { body };
+    if !body.is_empty() && state.truncate_bodies.load(Ordering::SeqCst) {
+        write_response(stream, status, &headers, &body[..body.len() / 2])?;
+        return Err(io::Error::other("response body truncated"));
+    }
+    write_response(stream, status, &headers, body)
+}
 
-    // note the -u flag to the python interpreter is required, else the piped
-    // output is buffered and we never see it until the process exits
-    let mut line = String::new();
-    reader.read_line(&mut line).unwrap();
-    assert!(line.starts_with("Serving HTTP"));
+fn handle_connection(state: Arc<ServerState>, stream: TcpStream) -> io::Result<()> {
+    state.connections.fetch_add(1, Ordering::SeqCst);
+    let tcp_stream = stream.try_clone()?;
+    let acceptor = state.tls.lock().unwrap().clone();
+    match acceptor {
+        Some(acceptor) => {
+            let stream = acceptor.accept(stream).map_err(io::Error::other)?;
+            serve_requests(&state, stream, tcp_stream)
+        }
+        None => serve_requests(&state, stream, tcp_stream),
+    }
+}
 
-    TestServer {
-        process: server,
-        port: server_port,
+fn serve_requests<S: Read + Write>(
+    state: &ServerState,
+    stream: S,
+    tcp_stream: TcpStream,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    while let Some(request) = read_request(&mut reader)? {
+        // kept alive connections are closed once the server is stopped
+        if state.shutdown.load(Ordering::SeqCst) {
+            return tcp_stream.shutdown(Shutdown::Both);
+        }
+        debug!("test server request: {} {}", request.method, request.path);
+        state.requests.fetch_add(1, Ordering::SeqCst);
+
+        if let Some(latency) = state.response_latency {
+            if latency < 0f32 {
+                // negative latency never responds
+                while !state.shutdown.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                return tcp_stream.shutdown(Shutdown::Both);
+            }
+            thread::sleep(Duration::from_secs_f32(latency));
+        }
+        handle_request(state, reader.get_mut(), &request)?;
     }
+    Ok(())
+}
+
+pub fn create_test_server(args: TestServerArgs) -> TestServer {
+    let server_root = test_path(args.server_root);
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind test server");
+    let port = listener.local_addr().unwrap().port() as u32;
+
+    debug!(
+        "starting test server on port: {}, root path: {}",
+        port,
+        server_root.to_str().unwrap()
+    );
+
+    let state = Arc::new(ServerState {
+        server_root,
+        response_latency: args.response_latency,
+        tls: Mutex::new(
+            args.tls
+                .map(|require| create_acceptor(require, "server.pem", "server.key")),
+        ),
+        token: Mutex::new(None),
+        ignore_ranges: AtomicBool::new(false),
+        truncate_bodies: AtomicBool::new(false),
+        connections: AtomicUsize::new(0),
+        requests: AtomicUsize::new(0),
+        shutdown: AtomicBool::new(false),
+    });
+
+    let server_state = state.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if server_state.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let conn_state = server_state.clone();
+            thread::spawn(move || {
+                if let Err(err) = handle_connection(conn_state, stream) {
+                    debug!("test server connection error: {}", err);
+                }
+            });
+        }
+    });
+
+    TestServer { state, port }
 }
@@ -1,5 +1,6 @@
-use std::path;
+use crate::checksum::Checksum;
 use crate::utils::gen_rand_str;
+use std::path;
 
 pub fn init_logging() {
     let _ = env_logger::builder().is_test(true).try_init();
@@ -18,4 +19,75 @@ pub fn make_tempfile_path() -> path::PathBuf {
     let mut tmp_path = path::PathBuf::from("/tmp");
     tmp_path.push(format!("{}.img", gen_rand_str(TMPFILE_NAMELEN)));
     tmp_path
-}
\ No newline at end of file
+}
+
+fn pad4(buf: &mut Vec<u8>) {
+    while !buf.len().is_multiple_of(4) {
+        buf.push(0);
+    }
+}
+
+fn write_cpio_entry(buf: &mut Vec<u8>, ino: u32, filename: &str, data: &[u8]) {
+    let mode = if filename == "TRAILER!!!" {
+        0
+    } else {
+        0o100644
+    };
+    buf.extend_from_slice(b"070701");
+    let fields = [
+        ino,
+        mode,
+        0,
+        0,
+        1,
+        0,
+        data.len() as u32,
+        0,
+        0,
+        0,
+        0,
+        filename.len() as u32 + 1,
+        0,
+    ];
+    for field in fields.iter() {
+        buf.extend_from_slice(format!("{:08X}", field).as_bytes());
+    }
+    buf.extend_from_slice(filename.as_bytes());
+    buf.push(0);
+    pad4(buf);
+    buf.extend_from_slice(data);
+    pad4(buf);
+}
+
+/// Builds a newc cpio archive in memory, containing the given files in order.
+pub fn make_cpio(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (idx, (filename, data)) in files.iter().enumerate() {
+        write_cpio_entry(&mut buf, idx as u32 + 1, filename, data);
+    }
+    write_cpio_entry(&mut buf, 0, "TRAILER!!!", &[]);
+    buf
+}
+
+/// Generates a checksums file for the given files.
+pub fn make_checksums(files: &[(&str, &[u8])]) -> String {
+    let mut checksums = String::new();
+    for (filename, data) in files.iter() {
+        let mut cksum = Checksum::new_hashable();
+        cksum.update(data);
+        cksum.finalise();
+        checksums.push_str(&format!("{}\t{}\n", filename, cksum));
+    }
+    checksums
+}
+
+/// Builds a skipper archive from a manifest and payload files, generating the checksums file.
+pub fn make_archive(manifest: &str, files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut all_files = vec![("manifest.jsonc", manifest.as_bytes())];
+    all_files.extend_from_slice(files);
+    let checksums = make_checksums(&all_files);
+
+    let mut entries = vec![("checksums", checksums.as_bytes())];
+    entries.extend_from_slice(&all_files);
+    make_cpio(&entries)
+}
@@ -0,0 +1,293 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::cancel::CancelToken;
+use crate::config::BackgroundConfig;
+use crate::linux;
+
+// how often the system load is sampled, reading /proc for every block would be wasteful
+const LOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// how long to wait before re-checking the load while paused
+const PAUSE_INTERVAL: Duration = Duration::from_millis(500);
+
+// pressure files which are checked against max_pressure
+const PRESSURE_FILES: [&str; 3] = ["pressure/cpu", "pressure/io", "pressure/memory"];
+
+/// A token bucket, which limits a byte rate with up to a second of burst.
+pub struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> RateLimiter {
+        RateLimiter {
+            rate: bytes_per_sec as f64,
+            tokens: bytes_per_sec as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Returns how long to wait before count bytes may be used.
+    fn take(&mut self, count: u64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = f64::min(self.rate, self.tokens + elapsed * self.rate);
+        self.last = now;
+
+        self.tokens -= count as f64;
+        if self.tokens >= 0f64 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+
+    /// Blocks until count bytes may be used.
+    pub fn consume(&mut self, count: u64) {
+        let delay = self.take(count);
+        if !delay.is_zero() {
+            trace!("rate limited, sleeping for {:?}", delay);
+            thread::sleep(delay);
+        }
+    }
+}
+
+fn read_loadavg(path: &Path) -> io::Result<f64> {
+    let buf = fs::read_to_string(path)?;
+    buf.split_whitespace()
+        .next()
+        .and_then(|load| load.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid loadavg format"))
+}
+
+// returns the "some avg10" value, the percentage of the last 10 seconds in which at least one
+// task was stalled
+fn read_pressure(path: &Path) -> io::Result<f64> {
+    let buf = fs::read_to_string(path)?;
+    buf.lines()
+        .filter(|line| line.starts_with("some "))
+        .flat_map(|line| line.split_whitespace())
+        .find_map(|field| field.strip_prefix("avg10="))
+        .and_then(|avg| avg.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid pressure format"))
+}
+
+/// Limits the rate at which payloads are written, and pauses while the system is busy.
+pub struct Throttle {
+    write_limit: Option<RateLimiter>,
+    max_load: Option<f64>,
+    max_pressure: Option<f64>,
+    proc_root: PathBuf,
+    last_check: Option<Instant>,
+}
+
+impl Throttle {
+    pub fn new(config: &BackgroundConfig) -> Throttle {
+        Throttle::with_proc_root(config, Path::new("/proc"))
+    }
+
+    fn with_proc_root(config: &BackgroundConfig, proc_root: &Path) -> Throttle {
+        Throttle {
+            write_limit: config.write_rate.map(RateLimiter::new),
+            max_load: config.max_load,
+            max_pressure: config.max_pressure,
+            proc_root: proc_root.to_path_buf(),
+            last_check: None,
+        }
+    }
+
+    // returns a description of the exceeded limit, if any
+    fn overloaded(&self) -> Option<String> {
+        if let Some(max_load) = self.max_load {
+            match read_loadavg(&self.proc_root.join("loadavg")) {
+                Ok(load) if load > max_load => return Some(format!("load average {}", load)),
+                Ok(_) => (),
+                Err(err) => debug!("unable to read load average: {}", err),
+            }
+        }
+        if let Some(max_pressure) = self.max_pressure {
+            for file in PRESSURE_FILES.iter() {
+                // kernels without psi support have no pressure files
+                if let Ok(pressure) = read_pressure(&self.proc_root.join(file)) {
+                    if pressure > max_pressure {
+                        return Some(format!("{} {}%", file, pressure));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Called with each block written, blocking as needed. Returns a cancelled error if the
+    /// token is cancelled while waiting.
+    pub fn wait(&mut self, count: u64, cancel: &CancelToken) -> io::Result<()> {
+        if let Some(limit) = self.write_limit.as_mut() {
+            limit.consume(count);
+        }
+
+        let check_due = self
+            .last_check
+            .map(|last| last.elapsed() >= LOAD_CHECK_INTERVAL)
+            .unwrap_or(true);
+        if !check_due {
+            return Ok(());
+        }
+        if let Some(reason) = self.overloaded() {
+            info!("pausing deploy, system is busy: {}", reason);
+            while self.overloaded().is_some() {
+                cancel.check_io()?;
+                thread::sleep(PAUSE_INTERVAL);
+            }
+            info!("resuming deploy");
+        }
+        self.last_check = Some(Instant::now());
+        Ok(())
+    }
+}
+
+/// Lowers the io and cpu priority of the calling thread, and any threads it creates. Threads
+/// which are already running keep their priority, so this should be called from the thread
+/// which runs deploys.
+pub fn enter_background(config: &BackgroundConfig) -> io::Result<()> {
+    if config.io_idle {
+        linux::set_io_priority_idle()?;
+        debug!("io priority set to idle");
+    }
+    if let Some(nice) = config.nice {
+        linux::set_nice(nice)?;
+        debug!("nice level set to {}", nice);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn background_config() -> BackgroundConfig {
+        BackgroundConfig {
+            io_idle: false,
+            nice: None,
+            write_rate: None,
+            download_rate: None,
+            max_load: None,
+            max_pressure: None,
+        }
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut limiter = RateLimiter::new(1000);
+        // the first second is allowed as a burst
+        assert!(limiter.take(1000).is_zero());
+        let delay = limiter.take(500);
+        assert!(delay > Duration::from_millis(450) && delay <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn parse_proc_files() {
+        init_logging();
+        let root = make_tempfile_path();
+        fs::create_dir_all(root.join("pressure")).unwrap();
+        fs::write(root.join("loadavg"), "2.50 1.20 0.80 2/345 6789\n").unwrap();
+        fs::write(
+            root.join("pressure/io"),
+            "some avg10=12.50 avg60=3.00 avg300=1.00 total=1234\n\
+             full avg10=40.00 avg60=2.00 avg300=0.50 total=567\n",
+        )
+        .unwrap();
+
+        assert_eq!(read_loadavg(&root.join("loadavg")).unwrap(), 2.5);
+        assert_eq!(read_pressure(&root.join("pressure/io")).unwrap(), 12.5);
+
+        let mut config = background_config();
+        config.max_load = Some(4.0);
+        config.max_pressure = Some(20.0);
+        assert!(Throttle::with_proc_root(&config, &root)
+            .overloaded()
+            .is_none());
+        config.max_pressure = Some(10.0);
+        assert!(Throttle::with_proc_root(&config, &root)
+            .overloaded()
+            .is_some());
+        config.max_pressure = None;
+        config.max_load = Some(2.0);
+        assert!(Throttle::with_proc_root(&config, &root)
+            .overloaded()
+            .is_some());
+    }
+
+    #[test]
+    fn paused_until_load_drops() {
+        init_logging();
+        let root = make_tempfile_path();
+        fs::create_dir_all(&root).unwrap();
+        let loadavg = root.join("loadavg");
+        fs::write(&loadavg, "8.00 8.00 8.00 1/100 1\n").unwrap();
+
+        let mut config = background_config();
+        config.max_load = Some(4.0);
+        let mut throttle = Throttle::with_proc_root(&config, &root);
+
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(600));
+            fs::write(&loadavg, "1.00 8.00 8.00 1/100 1\n").unwrap();
+        });
+        let start = Instant::now();
+        throttle.wait(100, &CancelToken::new()).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        writer.join().unwrap();
+    }
+
+    // the nice level of the calling thread, see "man 5 proc"
+    fn thread_nice() -> i32 {
+        let stat = fs::read_to_string("/proc/thread-self/stat").unwrap();
+        let fields: Vec<&str> = stat
+            .rsplit(')')
+            .next()
+            .unwrap()
+            .split_whitespace()
+            .collect();
+        fields[16].parse().unwrap()
+    }
+
+    #[test]
+    fn background_priority() {
+        // run on a separate thread, as the priority applies to the calling thread
+        let nice = thread_nice();
+        thread::spawn(move || {
+            let mut config = background_config();
+            config.io_idle = true;
+            config.nice = Some(nice + 5);
+            enter_background(&config).unwrap();
+            assert_eq!(thread_nice(), nice + 5);
+        })
+        .join()
+        .unwrap();
+        assert_eq!(thread_nice(), nice);
+    }
+
+    #[test]
+    fn cancelled_while_paused() {
+        init_logging();
+        let root = make_tempfile_path();
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("loadavg"), "8.00 8.00 8.00 1/100 1\n").unwrap();
+
+        let mut config = background_config();
+        config.max_load = Some(4.0);
+        let mut throttle = Throttle::with_proc_root(&config, &root);
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let err = throttle.wait(100, &cancel).unwrap_err();
+        assert!(crate::cancel::is_cancelled_error(&err));
+    }
+}
@@ -0,0 +1,353 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use log::*;
+use openssl::sha::sha256;
+use reqwest::blocking::ClientBuilder;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName};
+use rustls_pemfile::Item;
+
+use crate::config::TlsConfig;
+use crate::http_reader::HttpError;
+
+fn tls_error<E: std::fmt::Display>(context: &str) -> impl Fn(E) -> HttpError + '_ {
+    move |err| HttpError::TlsError {
+        reason: format!("{}, {}", context, err),
+    }
+}
+
+fn read_pem(path: &str) -> Result<Vec<Item>, HttpError> {
+    let file = File::open(path).map_err(tls_error(&format!("reading {}", path)))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(tls_error(&format!("parsing {}", path)))
+}
+
+fn read_certs(path: &str) -> Result<Vec<Certificate>, HttpError> {
+    let certs: Vec<Certificate> = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(HttpError::TlsError {
+            reason: format!("no certificate found in {}", path),
+        });
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &str) -> Result<PrivateKey, HttpError> {
+    read_pem(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| HttpError::TlsError {
+            reason: format!("no private key found in {}", path),
+        })
+}
+
+// the system certs, and any from the CA bundle
+fn read_roots(tls: &TlsConfig) -> Result<RootCertStore, HttpError> {
+    let mut roots = RootCertStore::empty();
+    let system =
+        rustls_native_certs::load_native_certs().map_err(tls_error("loading system certs"))?;
+    let (added, ignored) = roots.add_parsable_certificates(&system);
+    debug!("added {} system certs, ignored {}", added, ignored);
+    if let Some(ca_bundle) = &tls.ca_bundle {
+        let certs = read_certs(ca_bundle)?;
+        debug!("adding {} certs from ca bundle {}", certs.len(), ca_bundle);
+        for cert in certs {
+            roots
+                .add(&cert)
+                .map_err(tls_error(&format!("parsing ca bundle {}", ca_bundle)))?;
+        }
+    }
+    Ok(roots)
+}
+
+/// Adds the CA bundle and client identity to a client. When certs are pinned, the pins are
+/// checked in the handshake of every connection the client makes, see PinnedCertVerifier,
+/// and the client only makes https requests.
+pub fn configure(builder: ClientBuilder, tls: &TlsConfig) -> Result<ClientBuilder, HttpError> {
+    let identity = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => Some((read_certs(cert)?, read_key(key)?)),
+        (None, None) => None,
+        _ => {
+            return Err(HttpError::TlsError {
+                reason: String::from("client_cert and client_key must be set together"),
+            })
+        }
+    };
+    let verifier = WebPkiVerifier::new(read_roots(tls)?, None);
+    let config = ClientConfig::builder().with_safe_defaults();
+    let config = if tls.pinned_certs.is_empty() {
+        config.with_custom_certificate_verifier(Arc::new(verifier))
+    } else {
+        config.with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+            verifier,
+            pins: tls
+                .pinned_certs
+                .iter()
+                .map(|pin| normalise_fingerprint(pin))
+                .collect(),
+        }))
+    };
+    let config = match identity {
+        Some((chain, key)) => config
+            .with_client_auth_cert(chain, key)
+            .map_err(tls_error("loading client identity"))?,
+        None => config.with_no_client_auth(),
+    };
+    Ok(builder
+        .use_preconfigured_tls(config)
+        .https_only(!tls.pinned_certs.is_empty()))
+}
+
+/// Pinned certs can only be checked over https.
+pub fn check_url(url: &str, tls: &TlsConfig) -> Result<(), HttpError> {
+    if !tls.pinned_certs.is_empty() && !url.starts_with("https://") {
+        return Err(HttpError::TlsError {
+            reason: format!("certificates are pinned, but {} is not https", url),
+        });
+    }
+    Ok(())
+}
+
+fn normalise_fingerprint(fingerprint: &str) -> String {
+    fingerprint.replace(':', "").to_lowercase()
+}
+
+/// Verifies server certs as usual, then checks the sha256 fingerprint of the cert against the
+/// pins. As it's part of the handshake, every connection is checked, including retries,
+/// mirrors, urls from credential providers and connections through a proxy.
+struct PinnedCertVerifier {
+    verifier: WebPkiVerifier,
+    pins: Vec<String>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let fingerprint: String = sha256(&end_entity.0)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        if !self.pins.contains(&fingerprint) {
+            return Err(rustls::Error::General(format!(
+                "certificate with fingerprint {} is not pinned",
+                fingerprint
+            )));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::HttpConfig;
+    use crate::http_reader::{Credentials, HttpReader};
+    use crate::test_server::*;
+    use crate::test_utils::*;
+    use openssl::hash::MessageDigest;
+    use openssl::x509::X509;
+    use reqwest::blocking::Client;
+    use std::fs;
+    use std::io::Read;
+    use std::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn tls_path(filename: &str) -> Option<String> {
+        Some(test_path(format!("tls/{}", filename)).display().to_string())
+    }
+
+    fn server_fingerprint() -> String {
+        let cert = X509::from_pem(&fs::read(test_path("tls/server.pem")).unwrap()).unwrap();
+        let digest = cert.digest(MessageDigest::sha256()).unwrap();
+        digest
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+
+    fn start_server(require_client_cert: bool) -> (TestServer, String) {
+        let mut args = TestServerArgs::new("http-roots/test1");
+        args.tls(require_client_cert);
+        let server = create_test_server(args);
+        let url = format!("https://127.0.0.1:{}/test-file", server.port);
+        (server, url)
+    }
+
+    fn read_all(mut reader: HttpReader) -> usize {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap()
+    }
+
+    #[test]
+    fn custom_ca_bundle() {
+        init_logging();
+        let (_server, url) = start_server(false);
+        assert!(HttpReader::new(&url, TIMEOUT).is_err());
+
+        let tls = TlsConfig {
+            ca_bundle: tls_path("ca.pem"),
+            ..Default::default()
+        };
+        let reader = HttpReader::with_tls(&url, TIMEOUT, &tls).unwrap();
+        assert_eq!(read_all(reader), 1024);
+
+        let tls = TlsConfig {
+            ca_bundle: tls_path("other-ca.pem"),
+            ..Default::default()
+        };
+        let err = HttpReader::with_tls(&url, TIMEOUT, &tls).err().unwrap();
+        assert!(matches!(err, HttpError::TlsError { .. }));
+    }
+
+    #[test]
+    fn pinned_certs() {
+        init_logging();
+        let (_server, url) = start_server(false);
+        let mut tls = TlsConfig {
+            ca_bundle: tls_path("ca.pem"),
+            pinned_certs: vec![server_fingerprint()],
+            ..Default::default()
+        };
+        let reader = HttpReader::with_tls(&url, TIMEOUT, &tls).unwrap();
+        assert_eq!(read_all(reader), 1024);
+
+        tls.pinned_certs = vec![server_fingerprint().replace(':', "").to_lowercase()];
+        let reader = HttpReader::with_tls(&url, TIMEOUT, &tls).unwrap();
+        assert_eq!(read_all(reader), 1024);
+
+        // the pin doesn't replace the usual checks
+        let localhost = url.replace("127.0.0.1", "localhost");
+        assert!(HttpReader::with_tls(&localhost, TIMEOUT, &tls).is_ok());
+        let unverified = TlsConfig {
+            ca_bundle: None,
+            ..tls.clone()
+        };
+        let err = HttpReader::with_tls(&url, TIMEOUT, &unverified)
+            .err()
+            .unwrap();
+        assert!(matches!(err, HttpError::TlsError { .. }));
+
+        tls.pinned_certs = vec![String::from("00").repeat(32)];
+        let err = HttpReader::with_tls(&url, TIMEOUT, &tls).err().unwrap();
+        assert!(matches!(err, HttpError::TlsError { .. }));
+
+        // pins can't be checked over plain http
+        let err = HttpReader::with_tls("http://127.0.0.1/test-file", TIMEOUT, &tls)
+            .err()
+            .unwrap();
+        assert!(matches!(err, HttpError::TlsError { .. }));
+
+        // nor can they for a url from the credential provider
+        tls.pinned_certs = vec![server_fingerprint()];
+        let err = HttpReader::builder(&url, TIMEOUT)
+            .tls(&tls)
+            .credentials(|| {
+                Ok(Credentials {
+                    url: Some(String::from("http://127.0.0.1/test-file")),
+                    headers: Vec::new(),
+                })
+            })
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(err, HttpError::TlsError { .. }));
+    }
+
+    #[test]
+    fn pins_checked_on_every_connection() {
+        init_logging();
+        let (server, url) = start_server(false);
+        // trusts both server certs, so only the pin tells them apart
+        let bundle = make_tempfile_path();
+        let mut pem = fs::read(test_path("tls/ca.pem")).unwrap();
+        pem.extend(fs::read(test_path("tls/other-server.pem")).unwrap());
+        fs::write(&bundle, pem).unwrap();
+        let tls = TlsConfig {
+            ca_bundle: Some(bundle.display().to_string()),
+            pinned_certs: vec![server_fingerprint()],
+            ..Default::default()
+        };
+        // every request makes a new connection
+        let http = HttpConfig {
+            pool_max_idle: Some(0),
+            ..Default::default()
+        };
+        let mut reader = HttpReader::builder(&url, TIMEOUT)
+            .tls(&tls)
+            .http_config(&http)
+            .retry(1, Duration::from_millis(0))
+            .build()
+            .unwrap();
+
+        server.set_tls_cert("other-server.pem", "other-server.key");
+        let mut buf = [0; 1024];
+        let err = reader.read(&mut buf).unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<HttpError>().unwrap();
+        assert!(matches!(err, HttpError::TlsError { .. }), "{}", err);
+
+        let err = HttpReader::builder(&url, TIMEOUT)
+            .tls(&tls)
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(err, HttpError::TlsError { .. }));
+
+        let unpinned = TlsConfig {
+            pinned_certs: Vec::new(),
+            ..tls
+        };
+        let reader = HttpReader::with_tls(&url, TIMEOUT, &unpinned).unwrap();
+        assert_eq!(read_all(reader), 1024);
+    }
+
+    #[test]
+    fn client_certificate() {
+        init_logging();
+        let (_server, url) = start_server(true);
+        let mut tls = TlsConfig {
+            ca_bundle: tls_path("ca.pem"),
+            ..Default::default()
+        };
+        assert!(HttpReader::with_tls(&url, TIMEOUT, &tls).is_err());
+
+        tls.client_cert = tls_path("client.pem");
+        assert!(matches!(
+            configure(Client::builder(), &tls).err().unwrap(),
+            HttpError::TlsError { .. }
+        ));
+
+        tls.client_key = tls_path("client.key");
+        tls.pinned_certs = vec![server_fingerprint()];
+        let reader = HttpReader::with_tls(&url, TIMEOUT, &tls).unwrap();
+        assert_eq!(read_all(reader), 1024);
+    }
+}
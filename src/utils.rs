@@ -1,4 +1,5 @@
 use rand::{self, Rng};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn gen_rand_str(len: usize) -> String {
     let mut ret = String::new();
@@ -8,4 +9,11 @@ pub fn gen_rand_str(len: usize) -> String {
         ret.push(next_char);
     }
     ret
-}
\ No newline at end of file
+}
+/// The current unix time in seconds.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}